@@ -0,0 +1,375 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+
+/// Capability names indexed by number, as in `linux/capability.h`.
+const NAMES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Capabilities a container keeps unless told otherwise, matching Docker.
+pub const DEFAULT_CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FSETID",
+    "CAP_FOWNER",
+    "CAP_MKNOD",
+    "CAP_NET_RAW",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETFCAP",
+    "CAP_SETPCAP",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_SYS_CHROOT",
+    "CAP_KILL",
+    "CAP_AUDIT_WRITE",
+];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// A Linux capability, stored as its number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Capability(u32);
+
+impl Capability {
+    /// Every capability this runtime knows about.
+    pub fn all() -> Vec<Capability> {
+        (0..NAMES.len() as u32).map(Capability).collect()
+    }
+
+    pub fn defaults() -> Vec<Capability> {
+        DEFAULT_CAPABILITIES
+            .iter()
+            .map(|name| name.parse().expect("default capability names are valid"))
+            .collect()
+    }
+
+    fn bit(self) -> u64 {
+        1 << self.0
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    /// Accepts `CAP_NET_ADMIN` as well as Docker's short, case-insensitive
+    /// `net_admin` form.
+    fn from_str(s: &str) -> Result<Self> {
+        let upper = s.to_ascii_uppercase();
+        let name = if upper.starts_with("CAP_") {
+            upper
+        } else {
+            format!("CAP_{}", upper)
+        };
+        NAMES
+            .iter()
+            .position(|n| *n == name)
+            .map(|i| Capability(i as u32))
+            .with_context(|| format!("Unknown capability {:?}", s))
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(NAMES[self.0 as usize])
+    }
+}
+
+/// A `--cap-add` or `--cap-drop` value: one capability, or `ALL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapArg {
+    All,
+    Cap(Capability),
+}
+
+impl FromStr for CapArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("all") {
+            Ok(CapArg::All)
+        } else {
+            s.parse().map(CapArg::Cap)
+        }
+    }
+}
+
+/// Works out the capabilities a container keeps, the way Docker does:
+/// `--cap-add ALL` starts from every capability, `--cap-drop ALL` starts
+/// from none, and otherwise the defaults are adjusted.
+pub fn resolve_capabilities(add: &[CapArg], drop: &[CapArg]) -> Vec<Capability> {
+    let named = |args: &[CapArg]| -> Vec<Capability> {
+        args.iter()
+            .filter_map(|a| match a {
+                CapArg::Cap(c) => Some(*c),
+                CapArg::All => None,
+            })
+            .collect()
+    };
+    let (add_caps, drop_caps) = (named(add), named(drop));
+
+    let mut caps = if add.contains(&CapArg::All) {
+        Capability::all()
+    } else if drop.contains(&CapArg::All) {
+        Vec::new()
+    } else {
+        Capability::defaults()
+    };
+    if !add.contains(&CapArg::All) {
+        caps.extend(add_caps);
+    }
+    if !drop.contains(&CapArg::All) {
+        caps.retain(|c| !drop_caps.contains(c));
+    }
+    caps.sort();
+    caps.dedup();
+    caps
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Highest capability number the running kernel supports. Read before
+/// Landlock is applied, since the ruleset may not allow `/proc`.
+pub fn last_cap() -> Result<u32> {
+    let last = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .context("Failed to read /proc/sys/kernel/cap_last_cap")?;
+    last.trim()
+        .parse()
+        .with_context(|| format!("Invalid cap_last_cap {:?}", last.trim()))
+}
+
+/// Drops everything but `keep` from the bounding set. This needs
+/// CAP_SETPCAP, so it happens before switching to the container's user;
+/// capabilities already gone from the set are skipped. `last` is the
+/// kernel's [`last_cap`].
+pub fn drop_bounding_set(keep: &[Capability], last: u32) -> Result<()> {
+    let mask = cap_mask(keep, last);
+
+    for cap in 0..=last {
+        let bounded = unsafe { libc::prctl(libc::PR_CAPBSET_READ, cap, 0, 0, 0) } == 1;
+        if bounded && mask & Capability(cap).bit() == 0 {
+            let ret = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error()).with_context(|| {
+                    format!("Failed to drop {} from the bounding set", Capability(cap))
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Limits the calling process to `keep`, which must happen after everything
+/// else that needs privileges except switching user, which keeps the
+/// permitted set. The bounding, permitted and effective sets become `keep`;
+/// the inheritable and ambient sets are cleared so nothing is passed on to
+/// non-root programs, as Docker does since 20.10.
+pub fn apply_capabilities(keep: &[Capability], last: u32) -> Result<()> {
+    drop_bounding_set(keep, last)?;
+    let mask = cap_mask(keep, last);
+
+    let ret = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to clear ambient capabilities");
+    }
+
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    for (i, half) in data.iter_mut().enumerate() {
+        let bits = (mask >> (32 * i)) as u32;
+        half.effective = bits;
+        half.permitted = bits;
+    }
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapHeader,
+            data.as_ptr(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to set capabilities");
+    }
+    Ok(())
+}
+
+fn cap_mask(caps: &[Capability], last: u32) -> u64 {
+    caps.iter()
+        .filter(|c| c.0 <= last)
+        .fold(0u64, |mask, c| mask | c.bit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork};
+
+    fn caps(names: &[&str]) -> Vec<Capability> {
+        let mut caps: Vec<Capability> = names.iter().map(|n| n.parse().unwrap()).collect();
+        caps.sort();
+        caps
+    }
+
+    #[test]
+    fn test_parse_capability_names() {
+        let cap: Capability = "CAP_NET_ADMIN".parse().unwrap();
+        assert_eq!(cap, Capability(12));
+        assert_eq!("net_admin".parse::<Capability>().unwrap(), cap);
+        assert_eq!("Net_Admin".parse::<Capability>().unwrap(), cap);
+        assert_eq!(cap.to_string(), "CAP_NET_ADMIN");
+        assert_eq!(
+            "checkpoint_restore".parse::<Capability>().unwrap(),
+            Capability(40)
+        );
+        assert!("CAP_FLY".parse::<Capability>().is_err());
+        assert_eq!("all".parse::<CapArg>().unwrap(), CapArg::All);
+        assert_eq!(
+            "KILL".parse::<CapArg>().unwrap(),
+            CapArg::Cap(Capability(5))
+        );
+    }
+
+    #[test]
+    fn test_resolve_defaults_with_add_and_drop() {
+        assert_eq!(resolve_capabilities(&[], &[]), caps(DEFAULT_CAPABILITIES));
+
+        let add = ["NET_ADMIN".parse().unwrap()];
+        let drop = ["CAP_MKNOD".parse().unwrap(), "NET_RAW".parse().unwrap()];
+        let resolved = resolve_capabilities(&add, &drop);
+        assert!(resolved.contains(&"CAP_NET_ADMIN".parse().unwrap()));
+        assert!(!resolved.contains(&"CAP_MKNOD".parse().unwrap()));
+        assert!(!resolved.contains(&"CAP_NET_RAW".parse().unwrap()));
+        assert_eq!(resolved.len(), DEFAULT_CAPABILITIES.len() - 1);
+    }
+
+    #[test]
+    fn test_resolve_all() {
+        let drop_all = [CapArg::All];
+        let add = ["CHOWN".parse().unwrap()];
+        assert_eq!(resolve_capabilities(&add, &drop_all), caps(&["CAP_CHOWN"]));
+
+        let add_all = [CapArg::All];
+        let drop = ["SYS_ADMIN".parse().unwrap()];
+        let resolved = resolve_capabilities(&add_all, &drop);
+        assert_eq!(resolved.len(), NAMES.len() - 1);
+        assert!(!resolved.contains(&"CAP_SYS_ADMIN".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_apply_capabilities_in_child() {
+        // Dropping from the bounding set needs CAP_SETPCAP.
+        if !nix::unistd::Uid::effective().is_root() {
+            eprintln!("skipping: applying capabilities needs root");
+            return;
+        }
+        let keep = caps(&["CAP_CHOWN", "CAP_KILL", "CAP_SETPCAP"]);
+        let expected = keep.iter().fold(0u64, |m, c| m | c.bit());
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = if apply_capabilities(&keep, last_cap().unwrap()).is_err() {
+                    2
+                } else {
+                    let mut header = CapHeader {
+                        version: LINUX_CAPABILITY_VERSION_3,
+                        pid: 0,
+                    };
+                    let mut data = [CapData::default(); 2];
+                    unsafe {
+                        libc::syscall(
+                            libc::SYS_capget,
+                            &mut header as *mut CapHeader,
+                            data.as_mut_ptr(),
+                        )
+                    };
+                    let get =
+                        |f: fn(&CapData) -> u32| f(&data[0]) as u64 | (f(&data[1]) as u64) << 32;
+                    let bounding = (0..NAMES.len() as u32)
+                        .filter(
+                            |&cap| unsafe { libc::prctl(libc::PR_CAPBSET_READ, cap, 0, 0, 0) } == 1,
+                        )
+                        .fold(0u64, |m, cap| m | 1 << cap);
+                    if get(|d| d.effective) == expected
+                        && get(|d| d.permitted) == expected
+                        && get(|d| d.inheritable) == 0
+                        && bounding == expected
+                    {
+                        0
+                    } else {
+                        1
+                    }
+                };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
@@ -1,8 +1,178 @@
-use std::fs;
-use std::io::Write;
-use std::path::Path;
+use crate::resources::{CpuSet, DEFAULT_MEMORY_LIMIT, Resources};
+use crate::state::{StateStore, Status};
+use nix::fcntl::{FlockArg, flock};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Leaf that takes over the processes of the delegated cgroup so that
+/// controllers can be enabled below it.
+const SUPERVISOR_CGROUP: &str = "supervisor";
+
+/// Runtime-owned parent holding one leaf per container.
+const CONTAINERS_CGROUP: &str = "docker-clone";
+
+/// How long to wait for a killed cgroup to empty before giving up.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the kernel to finish freezing or thawing a cgroup.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the runtime puts container cgroups.
+///
+/// cgroup v2 only lets a group enable controllers for its children while it
+/// holds no processes itself (the root cgroup excepted), and processes then
+/// have to live in leaves. The layout below the cgroup the runtime was
+/// started in is therefore:
+///
+/// ```text
+/// <delegated>/
+/// ├── supervisor/          processes that were in <delegated>
+/// └── docker-clone/        controllers delegated here
+///     └── <container id>/  the container's processes and limits
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgroupLayout {
+    delegated: PathBuf,
+    is_root: bool,
+}
+
+impl CgroupLayout {
+    /// Layout below `delegated`. `is_root` marks the cgroupfs root, which may
+    /// keep its processes while delegating controllers.
+    pub fn new(delegated: impl AsRef<Path>, is_root: bool) -> Self {
+        Self {
+            delegated: delegated.as_ref().to_path_buf(),
+            is_root,
+        }
+    }
+
+    /// Layout for the calling process. A runtime already moved into a
+    /// `supervisor` leaf by an earlier run uses that leaf's parent.
+    pub fn current() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut delegated = current_cgroup()?;
+        if delegated
+            .file_name()
+            .is_some_and(|name| name == SUPERVISOR_CGROUP)
+            && let Some(parent) = delegated.parent()
+        {
+            delegated = parent.to_path_buf();
+        }
+
+        let is_root = delegated == Path::new(CGROUP_ROOT);
+        Ok(Self::new(delegated, is_root))
+    }
+
+    pub fn supervisor(&self) -> PathBuf {
+        self.delegated.join(SUPERVISOR_CGROUP)
+    }
+
+    pub fn parent(&self) -> PathBuf {
+        self.delegated.join(CONTAINERS_CGROUP)
+    }
+
+    pub fn leaf(&self, id: &str) -> PathBuf {
+        self.parent().join(id)
+    }
+
+    /// Moves any processes out of the delegated cgroup, then enables its
+    /// controllers there and in the runtime-owned parent. Safe to repeat.
+    pub fn prepare(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let controllers_path = self.delegated.join("cgroup.controllers");
+        let controllers = fs::read_to_string(&controllers_path)
+            .map_err(|e| format!("Failed to read {:?}: {}", controllers_path, e))?;
 
-pub fn setup_cgroup(pid: i32) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.is_root {
+            self.move_to_supervisor()?;
+        }
+
+        enable_controllers(&self.delegated, &controllers)?;
+
+        let parent = self.parent();
+        fs::create_dir_all(&parent)
+            .map_err(|e| format!("Failed to create cgroup {:?}: {}", parent, e))?;
+        enable_controllers(&parent, &controllers)?;
+
+        Ok(())
+    }
+
+    /// Creates the container's leaf, applies its limits and moves `pid` in.
+    /// [`prepare`](Self::prepare) must have run first.
+    pub fn create_leaf(
+        &self,
+        id: &str,
+        pid: i32,
+        resources: &Resources,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        // Resolve and check the limits first so a bad request leaves nothing
+        // behind.
+        let settings = resources
+            .cgroup_settings()
+            .map_err(|e| format!("{:#}", e))?;
+        check_delegated(&self.parent(), &settings)?;
+        check_cpuset(&self.parent(), "cpus", resources.cpuset_cpus.as_ref())?;
+        check_cpuset(&self.parent(), "mems", resources.cpuset_mems.as_ref())?;
+
+        // Until the pid is moved in, the leaf is empty and unreferenced, so
+        // keep prune from taking it for a stale one.
+        let _lock = lock_parent(&self.parent(), FlockArg::LockShared)?;
+        let leaf = self.leaf(id);
+        println!("Creating cgroup at {}", leaf.display());
+        fs::create_dir(&leaf).map_err(|e| format!("Failed to create cgroup {:?}: {}", leaf, e))?;
+
+        if let Err(e) = configure_leaf(&leaf, pid, &settings) {
+            // Don't leave a half-configured group behind.
+            let _ = fs::remove_dir(&leaf);
+            return Err(e);
+        }
+
+        Ok(leaf)
+    }
+
+    fn move_to_supervisor(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let procs = cgroup_procs(&self.delegated)?;
+        if procs.is_empty() {
+            return Ok(());
+        }
+
+        let supervisor = self.supervisor();
+        fs::create_dir_all(&supervisor)
+            .map_err(|e| format!("Failed to create cgroup {:?}: {}", supervisor, e))?;
+
+        for pid in procs {
+            // A process that exited since the list was read has nothing to move.
+            if let Err(e) = move_pid(&supervisor, pid)
+                && Path::new(&format!("/proc/{}", pid)).exists()
+            {
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates the container's cgroup under the runtime-owned parent, applies
+/// `resources` and moves `pid` into it. Returns the cgroup directory so the
+/// caller can remove it on exit.
+pub fn setup_cgroup(
+    id: &str,
+    pid: i32,
+    resources: &Resources,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let layout = CgroupLayout::current()?;
+    layout.prepare()?;
+    layout.create_leaf(id, pid, resources)
+}
+
+/// The cgroup v2 directory of the calling process.
+pub fn current_cgroup() -> Result<PathBuf, Box<dyn std::error::Error>> {
     let cgroup_line = std::fs::read_to_string("/proc/self/cgroup")
         .map_err(|e| format!("Failed to read /proc/self/cgroup: {}", e))?;
 
@@ -12,52 +182,303 @@ pub fn setup_cgroup(pid: i32) -> Result<(), Box<dyn std::error::Error>> {
         .ok_or("Could not find cgroup path in expected format")?
         .trim_start_matches("0::");
 
-    let base = "/sys/fs/cgroup";
-    let parent_cgroup = format!("{}{}", base, cgroup_rel);
-    let child_cgroup = format!("{}/docker-clone-{}", parent_cgroup, pid);
+    Ok(PathBuf::from(format!("{}{}", CGROUP_ROOT, cgroup_rel)))
+}
 
-    let controllers_path = format!("{}/cgroup.controllers", parent_cgroup);
-    let controllers = std::fs::read_to_string(&controllers_path)
-        .map_err(|e| format!("Failed to read cgroup.controllers: {}", e))?;
-    println!("Available controllers: {}", controllers);
+fn configure_leaf(
+    leaf: &Path,
+    pid: i32,
+    settings: &[(&str, String)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(leaf.join("memory.max"), DEFAULT_MEMORY_LIMIT.to_string())
+        .map_err(|e| format!("Failed to set memory limit: {}", e))?;
 
-    std::fs::create_dir_all(&child_cgroup)?;
-    enable_controllers(&child_cgroup)?;
+    fs::write(leaf.join("cpu.max"), "50000 100000")
+        .map_err(|e| format!("Failed to set CPU limit: {}", e))?;
 
-    println!("Creating cgroup at {}", child_cgroup);
-    fs::create_dir_all(&child_cgroup)
-        .map_err(|e| format!("Failed to create cgroup directory: {}", e))?;
+    // Requested settings come last so they override the defaults above.
+    for (file, value) in settings {
+        fs::write(leaf.join(file), value)
+            .map_err(|e| format!("Failed to write {:?} to {}: {}", value, file, e))?;
+    }
 
-    let memory_limit_path = format!("{}/memory.max", child_cgroup);
-    fs::write(&memory_limit_path, "104857600")
-        .map_err(|e| format!("Failed to set memory limit: {}", e))?;
+    move_pid(leaf, pid)
+}
 
-    let cpu_limit_path = format!("{}/cpu.max", child_cgroup);
-    fs::write(&cpu_limit_path, "50000 100000")
-        .map_err(|e| format!("Failed to set CPU limit: {}", e))?;
+/// Fails unless `parent` delegates the controller behind every setting.
+fn check_delegated(
+    parent: &Path,
+    settings: &[(&str, String)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if settings.is_empty() {
+        return Ok(());
+    }
 
-    let procs_path = format!("{}/cgroup.procs", child_cgroup);
-    let mut procs = fs::OpenOptions::new()
-        .write(true)
-        .open(&procs_path)
-        .map_err(|e| format!("Failed to open cgroup.procs: {}", e))?;
+    let delegated = subtree_controllers(parent)?;
+    for (file, _) in settings {
+        let controller = file.split('.').next().unwrap_or(file);
+        if !delegated.iter().any(|c| c == controller) {
+            return Err(format!(
+                "Cannot set {}: the {} controller is not delegated to {:?}",
+                file, controller, parent
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
 
-    writeln!(procs, "{}", pid)
-        .map_err(|e| format!("Failed to write PID to cgroup.procs: {}", e))?;
+/// Fails unless every entry of `wanted` is in the parent's effective
+/// `cpuset.<kind>`, since the kernel would otherwise reject the write.
+fn check_cpuset(
+    parent: &Path,
+    kind: &str,
+    wanted: Option<&CpuSet>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(wanted) = wanted else {
+        return Ok(());
+    };
+
+    let file = format!("cpuset.{}.effective", kind);
+    let contents = fs::read_to_string(parent.join(&file))
+        .map_err(|e| format!("Failed to read {}: {}", file, e))?;
+    // An empty effective list means nothing is available.
+    let available = if contents.trim().is_empty() {
+        CpuSet::default()
+    } else {
+        contents.parse().map_err(|e| format!("{:#}", e))?
+    };
+
+    if !wanted.is_subset(&available) {
+        return Err(format!(
+            "cpuset {} {} not available; the container may only use {}",
+            kind,
+            wanted.difference(&available),
+            available
+        )
+        .into());
+    }
 
     Ok(())
 }
 
-fn enable_controllers(parent: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let controllers_file = format!("{}/cgroup.controllers", parent);
-    let subtree_file = format!("{}/cgroup.subtree_control", parent);
+/// Controllers enabled for the children of `cgroup`.
+fn subtree_controllers(cgroup: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let enabled = fs::read_to_string(cgroup.join("cgroup.subtree_control"))
+        .map_err(|e| format!("Failed to read cgroup.subtree_control: {}", e))?;
 
-    // If this cgroup doesn't allow delegation (no controllers file), skip.
-    if !Path::new(&controllers_file).exists() {
+    Ok(enabled
+        .split_whitespace()
+        .map(|c| c.trim_start_matches('+').to_string())
+        .collect())
+}
+
+fn move_pid(cgroup: &Path, pid: i32) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(cgroup.join("cgroup.procs"), pid.to_string())
+        .map_err(|e| format!("Failed to move PID {} to {:?}: {}", pid, cgroup, e))?;
+    Ok(())
+}
+
+/// Kills whatever is still running in `cgroup` and removes it. A cgroup
+/// that is already gone counts as cleaned up.
+pub fn cleanup_cgroup(cgroup: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !cgroup.exists() {
+        return Ok(());
+    }
+
+    kill_cgroup(cgroup)?;
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while !cgroup_procs(cgroup)?.is_empty() {
+        if Instant::now() > deadline {
+            return Err(format!("Processes in {:?} did not exit", cgroup).into());
+        }
+        sleep(Duration::from_millis(10));
+    }
+
+    fs::remove_dir(cgroup).map_err(|e| format!("Failed to remove cgroup {:?}: {}", cgroup, e))?;
+    Ok(())
+}
+
+/// Freezes (or thaws) every process in `cgroup` and waits until
+/// `cgroup.events` reports the change as complete.
+pub fn freeze_cgroup(cgroup: &Path, frozen: bool) -> Result<(), Box<dyn std::error::Error>> {
+    set_frozen(cgroup, frozen, FREEZE_TIMEOUT)
+}
+
+fn set_frozen(
+    cgroup: &Path,
+    frozen: bool,
+    timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let freeze_file = cgroup.join("cgroup.freeze");
+    if !freeze_file.exists() {
+        return Err("The cgroup freezer needs Linux 5.2+".into());
+    }
+    let value = u64::from(frozen);
+    fs::write(&freeze_file, value.to_string())
+        .map_err(|e| format!("Failed to write cgroup.freeze: {}", e))?;
+
+    // Writing cgroup.freeze only requests the change; processes stop (or
+    // resume) asynchronously.
+    let deadline = Instant::now() + timeout;
+    while read_keyed(cgroup, "cgroup.events", "frozen")? != Some(value) {
+        if Instant::now() > deadline {
+            let action = if frozen { "freeze" } else { "thaw" };
+            return Err(format!("Timed out waiting for {:?} to {}", cgroup, action).into());
+        }
+        sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+/// How many processes in `cgroup` the kernel has OOM-killed, from the
+/// `oom_kill` counter in `memory.events`.
+pub fn oom_kill_count(cgroup: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(read_keyed(cgroup, "memory.events", "oom_kill")?.unwrap_or(0))
+}
+
+/// Reads `key` from a flat-keyed cgroup file such as `cgroup.events`.
+fn read_keyed(
+    cgroup: &Path,
+    file: &str,
+    key: &str,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(cgroup.join(file))
+        .map_err(|e| format!("Failed to read {}: {}", file, e))?;
+
+    Ok(content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, value)| value.trim().parse().ok()))
+}
+
+/// Uses `cgroup.kill` (Linux 5.14+) when available, otherwise SIGKILLs each
+/// listed process.
+fn kill_cgroup(cgroup: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let kill_file = cgroup.join("cgroup.kill");
+    if kill_file.exists() {
+        fs::write(&kill_file, "1").map_err(|e| format!("Failed to write cgroup.kill: {}", e))?;
         return Ok(());
     }
 
-    let controllers = fs::read_to_string(&controllers_file)?;
+    for pid in cgroup_procs(cgroup)? {
+        // The process may already have exited; that's fine.
+        let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+    }
+    Ok(())
+}
+
+fn cgroup_procs(cgroup: &Path) -> Result<Vec<i32>, Box<dyn std::error::Error>> {
+    let procs = fs::read_to_string(cgroup.join("cgroup.procs"))
+        .map_err(|e| format!("Failed to read cgroup.procs: {}", e))?;
+
+    Ok(procs
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .collect())
+}
+
+/// Locks the runtime-owned `parent` until the returned file is dropped.
+/// Runtimes hold it shared while creating a leaf and prune holds it
+/// exclusively while removing empty ones.
+fn lock_parent(parent: &Path, arg: FlockArg) -> Result<File, Box<dyn std::error::Error>> {
+    let file =
+        File::open(parent).map_err(|e| format!("Failed to open cgroup {:?}: {}", parent, e))?;
+    flock(file.as_raw_fd(), arg)
+        .map_err(|e| format!("Failed to lock cgroup {:?}: {}", parent, e))?;
+    Ok(file)
+}
+
+/// Removes the stale leaves under `parent`, waiting for runtimes that are
+/// still creating theirs.
+fn remove_stale_cgroups(
+    parent: &Path,
+    in_use: &[PathBuf],
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    if !parent.exists() {
+        return Ok(Vec::new());
+    }
+
+    let _lock = lock_parent(parent, FlockArg::LockExclusive)?;
+    let stale = find_stale_cgroups(parent, in_use);
+    for cgroup in &stale {
+        fs::remove_dir(cgroup)
+            .map_err(|e| format!("Failed to remove cgroup {:?}: {}", cgroup, e))?;
+    }
+    Ok(stale)
+}
+
+/// Container leaves directly under `parent` that hold no processes and are
+/// not in `in_use`.
+pub fn find_stale_cgroups(parent: &Path, in_use: &[PathBuf]) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(parent) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .filter(|path| !in_use.contains(path))
+        .filter(|path| cgroup_procs(path).is_ok_and(|procs| procs.is_empty()))
+        .collect()
+}
+
+/// Reaps cgroups left behind by crashed runs: those recorded for containers
+/// whose init process is gone, and empty unreferenced leaves under the
+/// runtime-owned parents. Returns the cgroups removed.
+pub fn prune_cgroups(store: &StateStore) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut pruned = Vec::new();
+    let mut in_use = Vec::new();
+    let mut parents = Vec::new();
+    if let Ok(layout) = CgroupLayout::current() {
+        parents.push(layout.parent());
+    }
+
+    for mut state in store.list()? {
+        let Some(cgroup) = state.cgroup.clone() else {
+            continue;
+        };
+        // Groups recorded before the leaf layout sit directly in the
+        // delegated cgroup, whose other children aren't ours to remove.
+        if let Some(parent) = cgroup.parent()
+            && parent
+                .file_name()
+                .is_some_and(|name| name == CONTAINERS_CGROUP)
+            && !parents.iter().any(|p| p == parent)
+        {
+            parents.push(parent.to_path_buf());
+        }
+
+        if state.is_alive() {
+            in_use.push(cgroup);
+            continue;
+        }
+
+        if cgroup.exists() {
+            cleanup_cgroup(&cgroup)?;
+            pruned.push(cgroup);
+        }
+        if matches!(state.status, Status::Running | Status::Paused) {
+            state.status = Status::Stopped;
+            store.save(&state)?;
+        }
+    }
+
+    for parent in parents {
+        pruned.extend(remove_stale_cgroups(&parent, &in_use)?);
+    }
+
+    Ok(pruned)
+}
+
+/// Enables `controllers` (as listed in a `cgroup.controllers` file) for the
+/// children of `cgroup`.
+fn enable_controllers(cgroup: &Path, controllers: &str) -> Result<(), Box<dyn std::error::Error>> {
     if controllers.trim().is_empty() {
         return Ok(());
     }
@@ -69,16 +490,367 @@ fn enable_controllers(parent: &str) -> Result<(), Box<dyn std::error::Error>> {
         .collect::<Vec<_>>()
         .join(" ");
 
-    // Only write if subtree_control exists
-    if Path::new(&subtree_file).exists() {
-        fs::write(&subtree_file, enable_string)?;
-    }
+    fs::write(cgroup.join("cgroup.subtree_control"), enable_string)
+        .map_err(|e| format!("Failed to enable controllers in {:?}: {}", cgroup, e))?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn fake_cgroup(parent: &Path, name: &str, procs: &str) -> PathBuf {
+        let cgroup = parent.join(name);
+        fs::create_dir_all(&cgroup).unwrap();
+        fs::write(cgroup.join("cgroup.procs"), procs).unwrap();
+        cgroup
+    }
+
+    #[test]
+    fn test_cgroup_procs_parsing() {
+        let temp_dir = TempDir::new().unwrap();
+        let cgroup = fake_cgroup(temp_dir.path(), "docker-clone-1", "12\n34\n\n");
+
+        assert_eq!(cgroup_procs(&cgroup).unwrap(), vec![12, 34]);
+    }
+
+    #[test]
+    fn test_find_stale_cgroups() {
+        let temp_dir = TempDir::new().unwrap();
+        let parent = temp_dir.path();
+        fs::write(parent.join("cgroup.procs"), "").unwrap();
+        let empty = fake_cgroup(parent, "aaaaaaaaaaaa", "");
+        let busy = fake_cgroup(parent, "bbbbbbbbbbbb", "200\n");
+        let in_use = fake_cgroup(parent, "cccccccccccc", "");
+
+        let stale = find_stale_cgroups(parent, std::slice::from_ref(&in_use));
+
+        assert_eq!(stale, vec![empty]);
+        assert!(!stale.contains(&busy));
+    }
+
+    #[test]
+    fn test_find_stale_cgroups_missing_parent() {
+        assert!(find_stale_cgroups(Path::new("/nonexistent/cgroup"), &[]).is_empty());
+        assert!(
+            remove_stale_cgroups(Path::new("/nonexistent/cgroup"), &[])
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_remove_stale_cgroups_waits_for_leaf_creation() {
+        let temp_dir = TempDir::new().unwrap();
+        let parent = temp_dir.path().to_path_buf();
+        let creating = lock_parent(&parent, FlockArg::LockShared).unwrap();
+        let leaf = parent.join("aaaaaaaaaaaa");
+        fs::create_dir(&leaf).unwrap();
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let pruning = parent.clone();
+        let handle = std::thread::spawn(move || {
+            let removed = remove_stale_cgroups(&pruning, &[]).map_err(|e| e.to_string());
+            done.send(()).unwrap();
+            removed
+        });
+
+        assert!(finished.recv_timeout(Duration::from_millis(200)).is_err());
+        assert!(leaf.exists());
+        // Once the pid is in, the leaf is no longer stale.
+        fs::write(leaf.join("cgroup.procs"), "4242\n").unwrap();
+        drop(creating);
+
+        assert!(handle.join().unwrap().unwrap().is_empty());
+        assert!(leaf.exists());
+    }
+
+    /// A delegated cgroup as the kernel would present it, holding `procs`.
+    fn fake_cgroupfs(procs: &str) -> TempDir {
+        let temp_dir = TempDir::new().unwrap();
+        let delegated = temp_dir.path();
+        fs::write(delegated.join("cgroup.controllers"), "cpu memory pids\n").unwrap();
+        fs::write(delegated.join("cgroup.subtree_control"), "").unwrap();
+        fs::write(delegated.join("cgroup.procs"), procs).unwrap();
+        temp_dir
+    }
+
+    #[test]
+    fn test_layout_paths() {
+        let layout = CgroupLayout::new("/sys/fs/cgroup/user.slice/run.scope", false);
+
+        assert_eq!(
+            layout.supervisor(),
+            PathBuf::from("/sys/fs/cgroup/user.slice/run.scope/supervisor")
+        );
+        assert_eq!(
+            layout.leaf("abc123"),
+            PathBuf::from("/sys/fs/cgroup/user.slice/run.scope/docker-clone/abc123")
+        );
+    }
+
+    #[test]
+    fn test_prepare_moves_processes_to_supervisor() {
+        let cgroupfs = fake_cgroupfs("4242\n");
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+
+        layout.prepare().unwrap();
+
+        let moved = fs::read_to_string(layout.supervisor().join("cgroup.procs")).unwrap();
+        assert_eq!(moved, "4242");
+    }
+
+    #[test]
+    fn test_prepare_delegates_controllers_to_parent() {
+        let cgroupfs = fake_cgroupfs("");
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+
+        layout.prepare().unwrap();
+
+        for cgroup in [cgroupfs.path().to_path_buf(), layout.parent()] {
+            let enabled = fs::read_to_string(cgroup.join("cgroup.subtree_control")).unwrap();
+            assert_eq!(enabled, "+cpu +memory +pids");
+        }
+        // Nothing needed moving, so no supervisor leaf.
+        assert!(!layout.supervisor().exists());
+    }
+
+    #[test]
+    fn test_prepare_keeps_processes_in_root_cgroup() {
+        let cgroupfs = fake_cgroupfs("1\n");
+        let layout = CgroupLayout::new(cgroupfs.path(), true);
+
+        layout.prepare().unwrap();
+
+        assert!(!layout.supervisor().exists());
+        assert!(layout.parent().is_dir());
+    }
+
+    #[test]
+    fn test_prepare_is_repeatable() {
+        let cgroupfs = fake_cgroupfs("");
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+
+        layout.prepare().unwrap();
+        layout.prepare().unwrap();
+    }
+
+    #[test]
+    fn test_prepare_without_cgroup_v2() {
+        let temp_dir = TempDir::new().unwrap();
+        let layout = CgroupLayout::new(temp_dir.path(), false);
+
+        assert!(layout.prepare().is_err());
+        assert!(!layout.parent().exists());
+    }
+
+    #[test]
+    fn test_create_leaf() {
+        let cgroupfs = fake_cgroupfs("");
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+        layout.prepare().unwrap();
+
+        let leaf = layout
+            .create_leaf("abc123", 4242, &Resources::default())
+            .unwrap();
+
+        assert_eq!(leaf, layout.leaf("abc123"));
+        assert_eq!(
+            fs::read_to_string(leaf.join("cgroup.procs")).unwrap(),
+            "4242"
+        );
+        assert_eq!(
+            fs::read_to_string(leaf.join("memory.max")).unwrap(),
+            "104857600"
+        );
+        assert_eq!(
+            fs::read_to_string(leaf.join("cpu.max")).unwrap(),
+            "50000 100000"
+        );
+        // Leaves never get controllers of their own.
+        assert!(!leaf.join("cgroup.subtree_control").exists());
+    }
+
+    #[test]
+    fn test_create_leaf_applies_io_limits() {
+        let cgroupfs = fake_cgroupfs("");
+        fs::write(
+            cgroupfs.path().join("cgroup.controllers"),
+            "cpu io memory pids\n",
+        )
+        .unwrap();
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+        layout.prepare().unwrap();
+        let resources = Resources {
+            blkio_weight: Some(1000),
+            ..Default::default()
+        };
+
+        let leaf = layout.create_leaf("abc123", 4242, &resources).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(leaf.join("io.weight")).unwrap(),
+            "default 10000"
+        );
+    }
+
+    #[test]
+    fn test_create_leaf_fails_without_io_controller() {
+        let cgroupfs = fake_cgroupfs("");
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+        layout.prepare().unwrap();
+        let resources = Resources {
+            blkio_weight: Some(500),
+            ..Default::default()
+        };
+
+        let err = layout.create_leaf("abc123", 4242, &resources).unwrap_err();
+
+        assert!(err.to_string().contains("io controller is not delegated"));
+        assert!(!layout.leaf("abc123").exists());
+    }
+
+    #[test]
+    fn test_create_leaf_applies_cpuset() {
+        let cgroupfs = fake_cgroupfs("");
+        fs::write(
+            cgroupfs.path().join("cgroup.controllers"),
+            "cpuset cpu memory pids\n",
+        )
+        .unwrap();
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+        layout.prepare().unwrap();
+        fs::write(layout.parent().join("cpuset.cpus.effective"), "0-3\n").unwrap();
+        fs::write(layout.parent().join("cpuset.mems.effective"), "0\n").unwrap();
+        let resources = Resources {
+            cpuset_cpus: Some("1,2".parse().unwrap()),
+            cpuset_mems: Some("0".parse().unwrap()),
+            cpu_weight: Some(200),
+            ..Default::default()
+        };
+
+        let leaf = layout.create_leaf("abc123", 4242, &resources).unwrap();
+
+        assert_eq!(fs::read_to_string(leaf.join("cpuset.cpus")).unwrap(), "1-2");
+        assert_eq!(fs::read_to_string(leaf.join("cpuset.mems")).unwrap(), "0");
+        assert_eq!(fs::read_to_string(leaf.join("cpu.weight")).unwrap(), "200");
+    }
+
+    #[test]
+    fn test_create_leaf_rejects_unavailable_cpus() {
+        let cgroupfs = fake_cgroupfs("");
+        fs::write(
+            cgroupfs.path().join("cgroup.controllers"),
+            "cpuset cpu memory pids\n",
+        )
+        .unwrap();
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+        layout.prepare().unwrap();
+        fs::write(layout.parent().join("cpuset.cpus.effective"), "0-3\n").unwrap();
+        let resources = Resources {
+            cpuset_cpus: Some("2-5".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let err = layout.create_leaf("abc123", 4242, &resources).unwrap_err();
+
+        assert!(err.to_string().contains("cpuset cpus 4-5 not available"));
+        assert!(!layout.leaf("abc123").exists());
+    }
+
+    #[test]
+    fn test_create_leaf_requires_prepare() {
+        let cgroupfs = fake_cgroupfs("");
+        let layout = CgroupLayout::new(cgroupfs.path(), false);
+
+        assert!(
+            layout
+                .create_leaf("abc123", 4242, &Resources::default())
+                .is_err()
+        );
+        assert!(!layout.leaf("abc123").exists());
+    }
+
+    #[test]
+    fn test_freeze_cgroup() {
+        let temp_dir = TempDir::new().unwrap();
+        let cgroup = fake_cgroup(temp_dir.path(), "abc123", "42\n");
+        fs::write(cgroup.join("cgroup.freeze"), "0").unwrap();
+        fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 1\n").unwrap();
+
+        freeze_cgroup(&cgroup, true).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(cgroup.join("cgroup.freeze")).unwrap(),
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_freeze_cgroup_times_out() {
+        let temp_dir = TempDir::new().unwrap();
+        let cgroup = fake_cgroup(temp_dir.path(), "abc123", "42\n");
+        fs::write(cgroup.join("cgroup.freeze"), "1").unwrap();
+        fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 1\n").unwrap();
+
+        assert!(set_frozen(&cgroup, false, Duration::from_millis(20)).is_err());
+    }
+
+    #[test]
+    fn test_freeze_cgroup_without_freezer() {
+        let temp_dir = TempDir::new().unwrap();
+        let cgroup = fake_cgroup(temp_dir.path(), "abc123", "");
+
+        assert!(freeze_cgroup(&cgroup, true).is_err());
+    }
+
+    #[test]
+    fn test_read_keyed() {
+        let temp_dir = TempDir::new().unwrap();
+        let cgroup = fake_cgroup(temp_dir.path(), "abc123", "");
+        fs::write(cgroup.join("cgroup.events"), "populated 0\nfrozen 1\n").unwrap();
+
+        assert_eq!(
+            read_keyed(&cgroup, "cgroup.events", "frozen").unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            read_keyed(&cgroup, "cgroup.events", "populated").unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            read_keyed(&cgroup, "cgroup.events", "missing").unwrap(),
+            None
+        );
+        assert!(read_keyed(&cgroup, "memory.events", "oom_kill").is_err());
+    }
+
+    #[test]
+    fn test_oom_kill_count() {
+        let temp_dir = TempDir::new().unwrap();
+        let cgroup = fake_cgroup(temp_dir.path(), "abc123", "");
+        fs::write(
+            cgroup.join("memory.events"),
+            "low 0\nhigh 0\nmax 12\noom 2\noom_kill 1\noom_group_kill 0\n",
+        )
+        .unwrap();
+
+        assert_eq!(oom_kill_count(&cgroup).unwrap(), 1);
+
+        fs::write(
+            cgroup.join("memory.events"),
+            "low 0\nhigh 0\nmax 0\noom 0\n",
+        )
+        .unwrap();
+        assert_eq!(oom_kill_count(&cgroup).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cleanup_missing_cgroup_is_ok() {
+        assert!(cleanup_cgroup(Path::new("/nonexistent/docker-clone-1")).is_ok());
+    }
 
     #[test]
     fn test_cgroup_path_generation() {
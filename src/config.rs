@@ -0,0 +1,210 @@
+use crate::capabilities::Capability;
+use crate::devices::{Device, DeviceRule};
+use crate::image::oci::{Digest, ExecConfig};
+use crate::landlock::LandlockRules;
+use crate::lsm::SelinuxLabel;
+use crate::masking::{default_masked_paths, default_readonly_paths};
+use crate::mounts::container_path;
+use crate::mounts::{Tmpfs, Volume};
+use crate::pivot_root::Propagation;
+use crate::process::{User, merge_env, parse_signal};
+use crate::psi::PressureTrigger;
+use crate::resources::Resources;
+use crate::seccomp::Profile;
+use crate::state::default_root;
+use crate::ulimit::Ulimit;
+use anyhow::Result;
+use nix::sys::signal::Signal;
+use std::path::PathBuf;
+
+/// Everything needed to start a container, assembled from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct ContainerConfig {
+    /// Data directory holding container state.
+    pub root: PathBuf,
+    pub rootfs: String,
+    pub command: String,
+    pub args: Vec<String>,
+    /// `KEY=VALUE` environment of the command, besides the default `PATH`.
+    pub env: Vec<String>,
+    /// Directory the command starts in, created if missing.
+    pub working_dir: Option<PathBuf>,
+    /// User the command runs as; root when unset.
+    pub user: Option<User>,
+    /// Sent to the container when the runtime is interrupted or terminated;
+    /// `SIGTERM` when unset.
+    pub stop_signal: Option<Signal>,
+    /// Run on a copy-on-write overlay of `rootfs` instead of mutating it.
+    pub overlay: bool,
+    /// Extra read-only layers stacked above `rootfs`, bottom to top.
+    pub layers: Vec<PathBuf>,
+    /// Manifest digest of the image the layers came from, recorded so the
+    /// image is not deleted while the container exists.
+    pub image: Option<Digest>,
+    /// Ports the image expects to listen on. Containers share the host
+    /// network, so these are only recorded.
+    pub exposed_ports: Vec<String>,
+    /// Container paths that get an empty volume of their own, kept with the
+    /// container until it is deleted.
+    pub anonymous_volumes: Vec<PathBuf>,
+    /// Remount the container root read-only once it has been pivoted into.
+    pub read_only: bool,
+    /// Propagation applied to `/` before pivoting into the rootfs.
+    pub rootfs_propagation: Propagation,
+    pub volumes: Vec<Volume>,
+    pub tmpfs: Vec<Tmpfs>,
+    /// Paths hidden from the container once `/proc` and `/sys` are mounted.
+    pub masked_paths: Vec<String>,
+    /// Paths remounted read-only once `/proc` and `/sys` are mounted.
+    pub readonly_paths: Vec<String>,
+    /// Limits written to the container's cgroup.
+    pub resources: Resources,
+    /// Written to the container process's `oom_score_adj` before it starts.
+    pub oom_score_adj: Option<i32>,
+    /// PSI thresholds that log an event while the container runs.
+    pub pressure_triggers: Vec<PressureTrigger>,
+    /// Syscall filter installed just before exec; `None` runs unconfined.
+    pub seccomp: Option<Profile>,
+    /// Capabilities the container process keeps; everything else is dropped.
+    pub capabilities: Vec<Capability>,
+    /// Set `no_new_privs` so setuid binaries cannot raise privileges.
+    pub no_new_privileges: bool,
+    /// Resource limits set with `setrlimit` before exec.
+    pub ulimits: Vec<Ulimit>,
+    /// Landlock paths enforced after pivoting; empty means no ruleset.
+    pub landlock: LandlockRules,
+    /// Host device nodes made visible inside the container.
+    pub devices: Vec<Device>,
+    /// Device access allowed through the cgroup, besides `devices`.
+    pub device_rules: Vec<DeviceRule>,
+    /// AppArmor profile the command is executed under.
+    pub apparmor_profile: Option<String>,
+    /// SELinux label the command is executed under.
+    pub selinux_label: Option<SelinuxLabel>,
+}
+
+impl ContainerConfig {
+    pub fn new(rootfs: &str, command: &str, args: Vec<String>) -> Self {
+        Self {
+            root: default_root(),
+            rootfs: rootfs.to_string(),
+            command: command.to_string(),
+            args,
+            masked_paths: default_masked_paths(),
+            readonly_paths: default_readonly_paths(),
+            seccomp: Some(Profile::default_profile()),
+            capabilities: Capability::defaults(),
+            no_new_privileges: true,
+            device_rules: DeviceRule::defaults(),
+            ..Default::default()
+        }
+    }
+
+    /// Fills in what the command line left unset from an image's config.
+    /// The image's environment comes first so `env` entries override it.
+    /// Image volumes already covered by a volume or tmpfs are skipped.
+    pub fn apply_image_defaults(&mut self, defaults: &ExecConfig) -> Result<()> {
+        let mut env = defaults.env.clone().unwrap_or_default();
+        merge_env(&mut env, &self.env);
+        self.env = env;
+
+        if self.working_dir.is_none()
+            && let Some(dir) = defaults.working_dir.as_deref().filter(|d| !d.is_empty())
+        {
+            self.working_dir = Some(container_path(dir)?);
+        }
+        if self.user.is_none()
+            && let Some(user) = defaults.user.as_deref().filter(|u| !u.is_empty())
+        {
+            self.user = Some(user.parse()?);
+        }
+        if self.stop_signal.is_none()
+            && let Some(signal) = &defaults.stop_signal
+        {
+            self.stop_signal = Some(parse_signal(signal)?);
+        }
+        if let Some(ports) = &defaults.exposed_ports {
+            self.exposed_ports = ports.keys().cloned().collect();
+        }
+
+        for path in defaults.volumes.iter().flat_map(|v| v.keys()) {
+            let path = container_path(path)?;
+            let taken = self.volumes.iter().any(|v| v.destination == path)
+                || self.tmpfs.iter().any(|t| t.destination == path)
+                || self.anonymous_volumes.contains(&path);
+            if !taken {
+                self.anonymous_volumes.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_new_config_defaults() {
+        let config = ContainerConfig::new("/tmp/rootfs", "/bin/sh", vec!["-c".to_string()]);
+
+        assert_eq!(config.rootfs, "/tmp/rootfs");
+        assert_eq!(config.command, "/bin/sh");
+        assert_eq!(config.args, vec!["-c"]);
+        assert!(!config.read_only);
+        assert!(!config.overlay);
+        assert!(config.layers.is_empty());
+        assert!(config.volumes.is_empty());
+        assert!(config.tmpfs.is_empty());
+        assert!(config.masked_paths.contains(&"/proc/kcore".to_string()));
+        assert!(config.readonly_paths.contains(&"/proc/sys".to_string()));
+        assert_eq!(config.seccomp, Some(Profile::default_profile()));
+        assert_eq!(config.capabilities, Capability::defaults());
+        assert!(config.no_new_privileges);
+        assert!(config.ulimits.is_empty());
+        assert!(config.devices.is_empty());
+        assert_eq!(config.device_rules, DeviceRule::defaults());
+        assert!(config.env.is_empty());
+        assert_eq!(config.stop_signal, None);
+    }
+
+    #[test]
+    fn test_apply_image_defaults() {
+        let mut config = ContainerConfig::new("/tmp/rootfs", "/bin/sh", vec![]);
+        config.env = vec!["LANG=C.UTF-8".to_string()];
+        config.user = Some("root".parse().unwrap());
+        config.tmpfs = vec!["/cache".parse().unwrap()];
+        let empty = || serde_json::Value::Object(Default::default());
+        let defaults = ExecConfig {
+            env: Some(vec!["PATH=/usr/bin".to_string(), "LANG=C".to_string()]),
+            working_dir: Some("/srv".to_string()),
+            user: Some("nobody".to_string()),
+            exposed_ports: Some(BTreeMap::from([("80/tcp".to_string(), empty())])),
+            volumes: Some(BTreeMap::from([
+                ("/data".to_string(), empty()),
+                ("/cache".to_string(), empty()),
+            ])),
+            stop_signal: Some("SIGQUIT".to_string()),
+            ..Default::default()
+        };
+
+        config.apply_image_defaults(&defaults).unwrap();
+        assert_eq!(config.env, ["PATH=/usr/bin", "LANG=C.UTF-8"]);
+        assert_eq!(config.working_dir, Some(PathBuf::from("/srv")));
+        assert_eq!(config.user.unwrap().user, "root");
+        assert_eq!(config.stop_signal, Some(Signal::SIGQUIT));
+        assert_eq!(config.exposed_ports, ["80/tcp"]);
+        assert_eq!(config.anonymous_volumes, [PathBuf::from("/data")]);
+
+        let relative = ExecConfig {
+            working_dir: Some("srv".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            ContainerConfig::default()
+                .apply_image_defaults(&relative)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sched::{CloneFlags, clone};
+use nix::sys::signal::Signal;
+use nix::sys::wait::waitpid;
+use nix::unistd::{Gid, Uid, close, pipe, read};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Stack for the short-lived process that holds a helper user namespace.
+const HELPER_STACK_SIZE: usize = 64 * 1024;
+
+// The new mount API (open_tree, mount_setattr, move_mount) has no nix
+// wrappers yet, so these go through raw syscalls.
+
+/// Clones the mount tree at `source` into a detached mount whose file
+/// ownership is mapped through the user namespace `userns`.
+///
+/// Only the host side can do this: the kernel requires `CAP_SYS_ADMIN` over
+/// the filesystem's own user namespace, which is the initial one for disk
+/// filesystems.
+pub fn open_idmapped_tree(source: &Path, userns: &File, read_only: bool) -> Result<OwnedFd> {
+    let tree = open_tree(source)?;
+
+    let mut attr_set = libc::MOUNT_ATTR_IDMAP;
+    if read_only {
+        attr_set |= libc::MOUNT_ATTR_RDONLY;
+    }
+    let attr = libc::mount_attr {
+        attr_set,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns.as_raw_fd() as u64,
+    };
+
+    let empty = CString::default();
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_RECURSIVE,
+            &attr as *const libc::mount_attr,
+            std::mem::size_of::<libc::mount_attr>(),
+        )
+    };
+
+    Errno::result(res).map_err(|errno| match errno {
+        Errno::ENOSYS => anyhow::anyhow!("Idmapped mounts need Linux 5.12+ (no mount_setattr)"),
+        Errno::EINVAL => anyhow::anyhow!(
+            "The filesystem of {:?} does not support idmapped mounts",
+            source
+        ),
+        Errno::EPERM => anyhow::anyhow!("Idmapped mounts need CAP_SYS_ADMIN on the host"),
+        errno => anyhow::anyhow!("Failed to idmap {:?}: {}", source, errno),
+    })?;
+
+    Ok(tree)
+}
+
+/// Creates a user namespace that maps the owner of `source` to `uid` and
+/// `gid`, for use as the idmap of a mount of `source`: its files then
+/// belong to that host user, which the container sees as its root.
+///
+/// The namespace lives in a child that only waits for the parent to close
+/// a pipe; it stays alive through the returned file once the child exits.
+pub fn owner_userns(source: &Path, uid: Uid, gid: Gid) -> Result<File> {
+    let metadata = std::fs::metadata(source)
+        .with_context(|| format!("Volume source does not exist: {:?}", source))?;
+    let (wait_end, release_end) = pipe().context("Failed to create pipe")?;
+    let (wait_end, release_end) = unsafe {
+        (
+            OwnedFd::from_raw_fd(wait_end),
+            OwnedFd::from_raw_fd(release_end),
+        )
+    };
+
+    let mut stack = vec![0u8; HELPER_STACK_SIZE];
+    let child = unsafe {
+        clone(
+            Box::new(|| {
+                // Its copy of the write end would keep the read from ending.
+                let _ = close(release_end.as_raw_fd());
+                let mut buf = [0u8];
+                let _ = read(wait_end.as_raw_fd(), &mut buf);
+                0
+            }),
+            &mut stack,
+            CloneFlags::CLONE_NEWUSER,
+            Some(Signal::SIGCHLD as i32),
+        )
+    }
+    .context("Failed to create a user namespace for the idmapped mount")?;
+
+    let result = (|| {
+        let maps = [
+            ("uid_map", metadata.uid(), uid.as_raw()),
+            ("gid_map", metadata.gid(), gid.as_raw()),
+        ];
+        for (file, owner, host) in maps {
+            let path = format!("/proc/{}/{}", child, file);
+            std::fs::write(&path, format!("{} {} 1", owner, host))
+                .with_context(|| format!("Failed to write {}", path))?;
+        }
+        let path = format!("/proc/{}/ns/user", child);
+        File::open(&path).with_context(|| format!("Failed to open {}", path))
+    })();
+
+    drop(release_end);
+    let _ = waitpid(child, None);
+    result
+}
+
+/// Attaches a detached mount from [`open_tree`] or [`open_idmapped_tree`]
+/// at `target`, following symlinks so that `target` may be a
+/// `/proc/self/fd` path.
+pub fn attach_tree(tree: &OwnedFd, target: &Path) -> Result<()> {
+    let empty = CString::default();
+    let target_c = CString::new(target.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid mount target {:?}", target))?;
+
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            target_c.as_ptr(),
+            libc::MOVE_MOUNT_F_EMPTY_PATH | libc::MOVE_MOUNT_T_SYMLINKS,
+        )
+    };
+
+    Errno::result(res).with_context(|| format!("Failed to move idmapped mount to {:?}", target))?;
+    Ok(())
+}
+
+/// Clones the mount tree at `source` into a detached mount, which another
+/// mount namespace can attach with [`attach_tree`].
+pub fn open_tree(source: &Path) -> Result<OwnedFd> {
+    let source_c = CString::new(source.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid mount source {:?}", source))?;
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            source_c.as_ptr(),
+            libc::OPEN_TREE_CLONE | libc::OPEN_TREE_CLOEXEC | libc::AT_RECURSIVE as libc::c_uint,
+        )
+    };
+
+    match Errno::result(fd) {
+        Ok(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) }),
+        Err(Errno::ENOSYS) => anyhow::bail!("Detached mounts need Linux 5.2+ (no open_tree)"),
+        Err(e) => Err(e).with_context(|| format!("Failed to clone mount tree at {:?}", source)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::mount::{MsFlags, mount};
+    use nix::sys::wait::WaitStatus;
+    use nix::unistd::{ForkResult, chown, fork};
+
+    /// Whether `path` belongs to root when seen from a user namespace whose
+    /// root is host user `uid`, as it is from inside a container.
+    fn owned_by_root_in_userns(path: &Path, uid: u32) -> Result<bool> {
+        let (wait_end, release_end) = pipe()?;
+        let (wait_end, release_end) = unsafe {
+            (
+                OwnedFd::from_raw_fd(wait_end),
+                OwnedFd::from_raw_fd(release_end),
+            )
+        };
+        let mut stack = vec![0u8; HELPER_STACK_SIZE];
+        let child = unsafe {
+            clone(
+                Box::new(|| {
+                    let _ = close(release_end.as_raw_fd());
+                    let _ = read(wait_end.as_raw_fd(), &mut [0u8]);
+                    match std::fs::metadata(path) {
+                        Ok(m) if (m.uid(), m.gid()) == (0, 0) => 0,
+                        _ => 1,
+                    }
+                }),
+                &mut stack,
+                CloneFlags::CLONE_NEWUSER,
+                Some(Signal::SIGCHLD as i32),
+            )
+        }?;
+        for file in ["uid_map", "gid_map"] {
+            std::fs::write(format!("/proc/{}/{}", child, file), format!("0 {} 1", uid))?;
+        }
+        drop(release_end);
+        Ok(waitpid(child, None)? == WaitStatus::Exited(child, 0))
+    }
+
+    #[test]
+    fn test_volume_owner_becomes_container_root() {
+        if !Uid::effective().is_root() {
+            eprintln!("skipping: idmapped mounts need root");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let (source, target) = (dir.path().join("source"), dir.path().join("target"));
+        std::fs::create_dir(&source).unwrap();
+        std::fs::create_dir(&target).unwrap();
+        std::fs::write(source.join("file"), "").unwrap();
+        let owner = (Some(Uid::from_raw(1234)), Some(Gid::from_raw(1234)));
+        chown(&source, owner.0, owner.1).unwrap();
+        chown(&source.join("file"), owner.0, owner.1).unwrap();
+
+        // Container root is host user 4321.
+        let root = 4321;
+        let userns = owner_userns(&source, Uid::from_raw(root), Gid::from_raw(root)).unwrap();
+        let tree = match open_idmapped_tree(&source, &userns, false) {
+            Ok(tree) => tree,
+            Err(e) => {
+                eprintln!("skipping: {:#}", e);
+                return;
+            }
+        };
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let file = target.join("file");
+                let code = (|| -> Result<i32> {
+                    nix::sched::unshare(CloneFlags::CLONE_NEWNS)?;
+                    let private = MsFlags::MS_REC | MsFlags::MS_PRIVATE;
+                    mount(None::<&str>, "/", None::<&str>, private, None::<&str>)?;
+                    attach_tree(&tree, &target)?;
+                    let on_host = std::fs::metadata(&file)?;
+                    if (on_host.uid(), on_host.gid()) != (root, root) {
+                        return Ok(1);
+                    }
+                    Ok(if owned_by_root_in_userns(&file, root)? {
+                        0
+                    } else {
+                        2
+                    })
+                })()
+                .unwrap_or(3);
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_idmapped_tree_missing_source() {
+        let userns = File::open("/proc/self/ns/user").unwrap();
+        let result = open_idmapped_tree(Path::new("/nonexistent/docker-clone"), &userns, false);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_open_idmapped_tree_rejects_nul() {
+        let userns = File::open("/proc/self/ns/user").unwrap();
+        let source = Path::new(std::ffi::OsStr::from_bytes(b"/tmp/bad\0path"));
+
+        assert!(open_idmapped_tree(source, &userns, false).is_err());
+    }
+}
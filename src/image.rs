@@ -0,0 +1,1370 @@
+mod layer;
+pub mod oci;
+pub mod reference;
+pub mod registry;
+
+use crate::state::{ContainerState, StateStore, generate_id};
+use anyhow::{Context, Result, bail};
+use layer::{is_gzip, open_tar, unpack_layer};
+use nix::fcntl::{FlockArg, flock};
+use oci::{
+    ANNOTATION_IMAGE_NAME, ANNOTATION_REF_NAME, Descriptor, Digest, DigestReader, ImageConfig,
+    Index, MEDIA_TYPE_CONFIG, MEDIA_TYPE_LAYER, MEDIA_TYPE_LAYER_GZIP, MEDIA_TYPE_MANIFEST,
+    Manifest, Platform, verify,
+};
+use reference::Reference;
+use registry::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Blobs (manifests, configs and layer tarballs) named by their digest.
+const BLOBS_DIR: &str = "blobs/sha256";
+/// Unpacked layers named by their diff ID, shared by every image using them.
+const LAYERS_DIR: &str = "layers";
+/// One record per image, named by its manifest digest.
+const IMAGEDB_DIR: &str = "imagedb";
+/// Maps `name:tag` to a manifest digest.
+const REPOSITORIES_FILE: &str = "repositories.json";
+/// Locked exclusively by every operation that changes the store.
+const LOCK_FILE: &str = "lock";
+
+/// Marks a directory as an OCI image layout.
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const OCI_INDEX_FILE: &str = "index.json";
+/// Lists the images in a `docker save` archive.
+const DOCKER_MANIFEST_FILE: &str = "manifest.json";
+
+/// One layer of an image: the blob as stored, and the digest of its
+/// uncompressed tar, which names the unpacked directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Layer {
+    pub digest: Digest,
+    pub diff_id: Digest,
+}
+
+/// A stored image, recorded as `<root>/images/imagedb/<manifest>.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Image {
+    /// Digest of the image config.
+    pub id: Digest,
+    /// Digest of the manifest, which is what tags point at.
+    pub manifest: Digest,
+    /// Tags naming the image, filled in from `repositories.json`.
+    #[serde(skip)]
+    pub repo_tags: Vec<String>,
+    pub platform: Platform,
+    /// Bottom first.
+    pub layers: Vec<Layer>,
+    /// Seconds since the Unix epoch.
+    pub loaded: u64,
+}
+
+/// What `image rm` and `image prune` did, printed one per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Removed {
+    Untagged(String),
+    /// An image, or a layer no image uses any more.
+    Deleted(Digest),
+}
+
+impl fmt::Display for Removed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Removed::Untagged(tag) => write!(f, "Untagged: {}", tag),
+            Removed::Deleted(digest) => write!(f, "Deleted: {}", digest),
+        }
+    }
+}
+
+/// A layer blob inside an archive, with its descriptor when the archive has
+/// one to check it against.
+struct SourceLayer {
+    path: PathBuf,
+    descriptor: Option<Descriptor>,
+}
+
+/// One image found in an archive. The manifest and config are already
+/// verified; layers are checked as they are copied into the store.
+struct Source {
+    /// `docker save` archives have no manifest, so one is written for them.
+    manifest: Option<Vec<u8>>,
+    config: Vec<u8>,
+    layers: Vec<SourceLayer>,
+    tags: Vec<String>,
+}
+
+/// One entry of a `docker save` manifest.json.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// The `name:tag` key for a reference found in an archive, or `None` if it
+/// is not a valid tagged reference.
+fn tag_key(name: &str) -> Option<String> {
+    name.parse::<Reference>().ok()?.tag_key()
+}
+
+/// The tag an OCI layout records for an image. `ref.name` is only used when
+/// it is a full reference rather than a bare tag.
+fn annotated_name(annotations: &HashMap<String, String>) -> Option<String> {
+    annotations
+        .get(ANNOTATION_IMAGE_NAME)
+        .or_else(|| {
+            annotations
+                .get(ANNOTATION_REF_NAME)
+                .filter(|name| name.contains(':'))
+        })
+        .and_then(|name| tag_key(name))
+}
+
+/// Joins a path taken from an archive's metadata onto `dir`, refusing
+/// anything that could point outside it.
+fn archive_path(dir: &Path, rel: &str) -> Result<PathBuf> {
+    let path = Path::new(rel);
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("Invalid path {:?} in image archive", rel);
+    }
+    Ok(dir.join(path))
+}
+
+fn layout_blob(dir: &Path, digest: &Digest) -> PathBuf {
+    dir.join(BLOBS_DIR).join(digest.hex())
+}
+
+fn check_size(descriptor: &Descriptor, size: u64) -> Result<()> {
+    if size != descriptor.size {
+        bail!(
+            "Size mismatch for blob {}: expected {} bytes, got {}",
+            descriptor.digest,
+            descriptor.size,
+            size
+        );
+    }
+    Ok(())
+}
+
+/// Reads a small blob (index, manifest or config) out of an OCI layout and
+/// checks it against its descriptor.
+fn read_blob(dir: &Path, descriptor: &Descriptor) -> Result<Vec<u8>> {
+    let path = layout_blob(dir, &descriptor.digest);
+    let bytes = fs::read(&path).with_context(|| format!("Missing blob {}", descriptor.digest))?;
+    check_size(descriptor, bytes.len() as u64)?;
+    verify("blob", &descriptor.digest, &Digest::of(&bytes))?;
+    Ok(bytes)
+}
+
+/// Copies `path` to `dest`, checking it against `descriptor` on the way.
+fn copy_blob(path: &Path, dest: &Path, descriptor: Option<&Descriptor>) -> Result<Digest> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut reader = DigestReader::new(file);
+    io::copy(&mut reader, &mut File::create(dest)?)
+        .with_context(|| format!("Failed to copy {:?}", path))?;
+    let (digest, size) = reader.finish()?;
+    if let Some(descriptor) = descriptor {
+        check_size(descriptor, size)?;
+        verify("blob", &descriptor.digest, &digest)?;
+    }
+    Ok(digest)
+}
+
+/// Reads the images for this host out of an OCI image layout. Entries of
+/// `index.json` that are themselves indexes resolve to the host platform.
+fn read_oci_layout(dir: &Path) -> Result<Vec<Source>> {
+    let layout: serde_json::Value = serde_json::from_slice(&fs::read(dir.join(OCI_LAYOUT_FILE))?)
+        .context("Failed to parse oci-layout")?;
+    if !layout["imageLayoutVersion"].is_string() {
+        bail!("oci-layout has no imageLayoutVersion");
+    }
+    let index: Index = serde_json::from_slice(&fs::read(dir.join(OCI_INDEX_FILE))?)
+        .context("Failed to parse index.json")?;
+
+    let platform = Platform::host();
+    let mut sources = Vec::new();
+    for descriptor in &index.manifests {
+        let manifest = if descriptor.is_index() {
+            let nested: Index = serde_json::from_slice(&read_blob(dir, descriptor)?)
+                .with_context(|| format!("Failed to parse index {}", descriptor.digest))?;
+            nested.select(&platform)?.clone()
+        } else if descriptor.is_manifest()
+            && descriptor
+                .platform
+                .as_ref()
+                .is_none_or(|p| p.matches(&platform))
+        {
+            descriptor.clone()
+        } else {
+            continue;
+        };
+        let bytes = read_blob(dir, &manifest)?;
+        let manifest: Manifest = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to parse manifest {}", manifest.digest))?;
+
+        sources.push(Source {
+            config: read_blob(dir, &manifest.config)?,
+            manifest: Some(bytes),
+            layers: manifest
+                .layers
+                .into_iter()
+                .map(|layer| SourceLayer {
+                    path: layout_blob(dir, &layer.digest),
+                    descriptor: Some(layer),
+                })
+                .collect(),
+            tags: annotated_name(&descriptor.annotations)
+                .into_iter()
+                .collect(),
+        });
+    }
+
+    if sources.is_empty() {
+        bail!("The image layout has no image for platform {}", platform);
+    }
+    Ok(sources)
+}
+
+/// Reads the images out of an extracted `docker save` archive. Config files
+/// are named after their digest; layers are checked against the config's
+/// diff IDs while unpacking.
+fn read_docker_save(dir: &Path) -> Result<Vec<Source>> {
+    let manifests: Vec<DockerManifest> =
+        serde_json::from_slice(&fs::read(dir.join(DOCKER_MANIFEST_FILE))?)
+            .context("Failed to parse manifest.json")?;
+
+    let mut sources = Vec::new();
+    for manifest in manifests {
+        let path = archive_path(dir, &manifest.config)?;
+        let config = fs::read(&path).with_context(|| format!("Missing config {:?}", path))?;
+        // Either `<hex>.json` or, in newer archives, `blobs/sha256/<hex>`.
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let expected = Digest::from_hex(name.strip_suffix(".json").unwrap_or(&name))?;
+        verify("image config", &expected, &Digest::of(&config))?;
+
+        let mut layers = Vec::new();
+        for layer in &manifest.layers {
+            layers.push(SourceLayer {
+                path: archive_path(dir, layer)?,
+                descriptor: None,
+            });
+        }
+        sources.push(Source {
+            manifest: None,
+            config,
+            layers,
+            tags: manifest
+                .repo_tags
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|tag| tag_key(tag))
+                .collect(),
+        });
+    }
+    Ok(sources)
+}
+
+fn read_sources(dir: &Path) -> Result<Vec<Source>> {
+    if dir.join(OCI_LAYOUT_FILE).exists() {
+        read_oci_layout(dir)
+    } else if dir.join(DOCKER_MANIFEST_FILE).exists() {
+        read_docker_save(dir)
+    } else {
+        bail!(
+            "{:?} is neither an OCI image layout nor a docker save archive",
+            dir
+        )
+    }
+}
+
+/// Renders images as `image ls` prints them, one row per tag.
+pub fn format_table(images: &[Image]) -> String {
+    let mut table = format!(
+        "{:<32}{:<16}{:<16}{:<16}{}\n",
+        "REPOSITORY", "TAG", "IMAGE ID", "PLATFORM", "LAYERS"
+    );
+
+    for image in images {
+        let mut names: Vec<_> = image
+            .repo_tags
+            .iter()
+            .filter_map(|tag| tag.rsplit_once(':'))
+            .collect();
+        if names.is_empty() {
+            names.push(("<none>", "<none>"));
+        }
+        for (repository, tag) in names {
+            table.push_str(&format!(
+                "{:<32}{:<16}{:<16}{:<16}{}\n",
+                repository,
+                tag,
+                image.id.short(),
+                image.platform.to_string(),
+                image.layers.len()
+            ));
+        }
+    }
+
+    table
+}
+
+/// Images under `<root>/images`. Layers are unpacked once and shared by
+/// every image that lists them; an image is its manifest, config and layer
+/// blobs plus a record in `imagedb/`.
+pub struct ImageStore {
+    /// Data root, also holding the container records that pin images.
+    root: PathBuf,
+    dir: PathBuf,
+}
+
+impl ImageStore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            dir: root.as_ref().join("images"),
+        }
+    }
+
+    pub fn blob_path(&self, digest: &Digest) -> PathBuf {
+        self.dir.join(BLOBS_DIR).join(digest.hex())
+    }
+
+    pub fn layer_dir(&self, diff_id: &Digest) -> PathBuf {
+        self.dir.join(LAYERS_DIR).join(diff_id.hex())
+    }
+
+    fn record_path(&self, manifest: &Digest) -> PathBuf {
+        self.dir
+            .join(IMAGEDB_DIR)
+            .join(format!("{}.json", manifest.hex()))
+    }
+
+    /// Takes the store's exclusive lock, held until the returned file is
+    /// dropped, so that garbage collection cannot delete blobs and layers a
+    /// concurrent load or pull is about to reference.
+    fn lock(&self) -> Result<File> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {:?}", self.dir))?;
+        let path = self.dir.join(LOCK_FILE);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {:?}", path))?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)
+            .with_context(|| format!("Failed to lock {:?}", path))?;
+        Ok(file)
+    }
+
+    /// A scratch path in `subdir`, so renaming it into place does not cross
+    /// filesystems. Names that are not digests are ignored when listing.
+    fn scratch(&self, subdir: &str) -> Result<PathBuf> {
+        let parent = self.dir.join(subdir);
+        fs::create_dir_all(&parent).with_context(|| format!("Failed to create {:?}", parent))?;
+        Ok(parent.join(format!("tmp-{}", generate_id()?)))
+    }
+
+    /// Write then rename, so readers never see a half-written file.
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let tmp = self.scratch(IMAGEDB_DIR)?;
+        fs::write(&tmp, contents).with_context(|| format!("Failed to write {:?}", tmp))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to write {:?}", path))
+    }
+
+    /// Copies a blob into the store, verifying it against `descriptor` when
+    /// given, and returns its digest.
+    fn put_blob(&self, path: &Path, descriptor: Option<&Descriptor>) -> Result<Digest> {
+        if let Some(descriptor) = descriptor
+            && self.blob_path(&descriptor.digest).exists()
+        {
+            return Ok(descriptor.digest.clone());
+        }
+
+        let tmp = self.scratch(BLOBS_DIR)?;
+        let result = copy_blob(path, &tmp, descriptor).and_then(|digest| {
+            fs::rename(&tmp, self.blob_path(&digest))
+                .with_context(|| format!("Failed to store blob {}", digest))?;
+            Ok(digest)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    fn write_blob(&self, bytes: &[u8]) -> Result<Digest> {
+        let digest = Digest::of(bytes);
+        let path = self.blob_path(&digest);
+        if !path.exists() {
+            let tmp = self.scratch(BLOBS_DIR)?;
+            fs::write(&tmp, bytes).with_context(|| format!("Failed to write {:?}", tmp))?;
+            fs::rename(&tmp, &path).with_context(|| format!("Failed to store blob {}", digest))?;
+        }
+        Ok(digest)
+    }
+
+    /// Unpacks a stored layer blob unless an image already brought the same
+    /// layer in.
+    fn unpack(&self, layer: &Layer) -> Result<()> {
+        let dir = self.layer_dir(&layer.diff_id);
+        if dir.exists() {
+            return Ok(());
+        }
+
+        let scratch = self.scratch(LAYERS_DIR)?;
+        fs::create_dir(&scratch).with_context(|| format!("Failed to create {:?}", scratch))?;
+        let result = unpack_layer(&self.blob_path(&layer.digest), &layer.diff_id, &scratch)
+            .and_then(|()| {
+                fs::rename(&scratch, &dir)
+                    .with_context(|| format!("Failed to store layer {}", layer.diff_id))
+            });
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&scratch);
+        }
+        result
+    }
+
+    /// Writes a manifest for an image that came without one.
+    fn manifest_for(
+        &self,
+        config: &Digest,
+        config_size: usize,
+        layers: &[Layer],
+    ) -> Result<Vec<u8>> {
+        let mut descriptors = Vec::new();
+        for layer in layers {
+            let path = self.blob_path(&layer.digest);
+            descriptors.push(Descriptor {
+                media_type: if is_gzip(&path)? {
+                    MEDIA_TYPE_LAYER_GZIP
+                } else {
+                    MEDIA_TYPE_LAYER
+                }
+                .to_string(),
+                digest: layer.digest.clone(),
+                size: fs::metadata(&path)?.len(),
+                platform: None,
+                annotations: HashMap::new(),
+            });
+        }
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MEDIA_TYPE_MANIFEST.to_string()),
+            config: Descriptor {
+                media_type: MEDIA_TYPE_CONFIG.to_string(),
+                digest: config.clone(),
+                size: config_size as u64,
+                platform: None,
+                annotations: HashMap::new(),
+            },
+            layers: descriptors,
+        };
+        Ok(serde_json::to_vec(&manifest)?)
+    }
+
+    fn tags(&self) -> Result<BTreeMap<String, Digest>> {
+        let path = self.dir.join(REPOSITORIES_FILE);
+        match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .with_context(|| format!("Corrupt tag list {:?}", path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        }
+    }
+
+    fn save_tags(&self, tags: &BTreeMap<String, Digest>) -> Result<()> {
+        self.write_file(
+            &self.dir.join(REPOSITORIES_FILE),
+            &serde_json::to_vec_pretty(tags)?,
+        )
+    }
+
+    fn save(&self, image: &Image) -> Result<()> {
+        self.write_file(
+            &self.record_path(&image.manifest),
+            &serde_json::to_vec_pretty(image)?,
+        )
+    }
+
+    fn read_record(&self, manifest: &Digest) -> Result<Image> {
+        let path = self.record_path(manifest);
+        let json = fs::read(&path).with_context(|| format!("No such image: {}", manifest))?;
+        serde_json::from_slice(&json).with_context(|| format!("Corrupt image record {:?}", path))
+    }
+
+    /// All stored images with their tags, oldest first.
+    pub fn list(&self) -> Result<Vec<Image>> {
+        let dir = self.dir.join(IMAGEDB_DIR);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut images = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}", dir))? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(hex) = name.strip_suffix(".json")
+                && let Ok(manifest) = Digest::from_hex(hex)
+            {
+                images.push(self.read_record(&manifest)?);
+            }
+        }
+
+        let tags = self.tags()?;
+        for image in &mut images {
+            image.repo_tags = tags
+                .iter()
+                .filter(|(_, manifest)| **manifest == image.manifest)
+                .map(|(tag, _)| tag.clone())
+                .collect();
+        }
+        images.sort_by_key(|i| i.loaded);
+        Ok(images)
+    }
+
+    /// Finds an image by tag (`alpine`, `alpine:3.19`), manifest digest
+    /// (`alpine@sha256:...`), full ID or manifest digest (`sha256:...`), or a
+    /// unique prefix of its ID.
+    pub fn resolve(&self, reference: &str) -> Result<Image> {
+        let images = self.list()?;
+
+        let found = if let Ok(digest) = reference.parse::<Digest>() {
+            images
+                .iter()
+                .find(|i| i.id == digest || i.manifest == digest)
+        } else if let Ok(parsed) = reference.parse::<Reference>() {
+            match (&parsed.digest, parsed.tag_key()) {
+                (Some(digest), _) => images.iter().find(|i| &i.manifest == digest),
+                (None, Some(key)) => images.iter().find(|i| i.repo_tags.contains(&key)),
+                (None, None) => None,
+            }
+        } else {
+            None
+        };
+        if let Some(image) = found {
+            return Ok(image.clone());
+        }
+
+        if reference.bytes().all(|b| b.is_ascii_hexdigit()) && !reference.is_empty() {
+            let mut matches = images.iter().filter(|i| i.id.hex().starts_with(reference));
+            if let Some(image) = matches.next() {
+                if matches.next().is_some() {
+                    bail!("Image ID prefix {} is ambiguous", reference);
+                }
+                return Ok(image.clone());
+            }
+        }
+        bail!("No such image: {}", reference)
+    }
+
+    /// The image's config, read back from its blob.
+    pub fn config(&self, image: &Image) -> Result<ImageConfig> {
+        let path = self.blob_path(&image.id);
+        ImageConfig::parse(
+            &fs::read(&path).with_context(|| format!("Missing config of image {}", image.id))?,
+        )
+    }
+
+    /// The unpacked layer directories of `image`, bottom first, to be stacked
+    /// as overlay lower dirs.
+    pub fn layer_dirs(&self, image: &Image) -> Result<Vec<PathBuf>> {
+        if image.layers.is_empty() {
+            bail!("Image {} has no layers", image.id.short());
+        }
+        image
+            .layers
+            .iter()
+            .map(|layer| {
+                let dir = self.layer_dir(&layer.diff_id);
+                if !dir.is_dir() {
+                    bail!(
+                        "Layer {} of image {} is missing; load the image again",
+                        layer.diff_id,
+                        image.id.short()
+                    );
+                }
+                Ok(dir)
+            })
+            .collect()
+    }
+
+    /// How many images use each unpacked layer. Layers that drop to zero are
+    /// deleted when an image is removed.
+    pub fn layer_refs(&self) -> Result<HashMap<Digest, usize>> {
+        let mut refs = HashMap::new();
+        for image in self.list()? {
+            for layer in image.layers {
+                *refs.entry(layer.diff_id).or_insert(0) += 1;
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Imports every image in `input`: an OCI image layout directory, a
+    /// directory holding an extracted `docker save` archive, or either of
+    /// them as a (possibly gzip-compressed) tarball.
+    pub fn load(&self, input: &Path) -> Result<Vec<Image>> {
+        let _lock = self.lock()?;
+        if input.is_dir() {
+            return self.load_dir(input);
+        }
+
+        let scratch = self.scratch("")?;
+        let result = tar::Archive::new(open_tar(input)?)
+            .unpack(&scratch)
+            .with_context(|| format!("Failed to extract {:?}", input))
+            .and_then(|()| self.load_dir(&scratch));
+        let _ = fs::remove_dir_all(&scratch);
+        result
+    }
+
+    fn load_dir(&self, dir: &Path) -> Result<Vec<Image>> {
+        read_sources(dir)?
+            .into_iter()
+            .map(|source| self.import(source))
+            .collect()
+    }
+
+    /// Downloads `reference` for `platform` from its registry, skipping blobs
+    /// already in the store, and tags it unless it was pulled by digest
+    /// alone.
+    pub fn pull(
+        &self,
+        client: &mut Client,
+        reference: &Reference,
+        platform: &Platform,
+    ) -> Result<Image> {
+        let _lock = self.lock()?;
+        let target = match (&reference.digest, reference.tag_or_default()) {
+            (Some(digest), _) => digest.to_string(),
+            (None, Some(tag)) => tag.to_string(),
+            (None, None) => unreachable!("references have a digest or a tag"),
+        };
+        let mut manifest = client.manifest(&target)?;
+        if manifest.is_index() {
+            let index: Index = serde_json::from_slice(&manifest.bytes)
+                .with_context(|| format!("Failed to parse index {}", manifest.digest))?;
+            let selected = index.select(platform)?.digest.to_string();
+            manifest = client.manifest(&selected)?;
+        }
+        if !manifest.is_manifest() {
+            bail!("Unsupported manifest type {:?}", manifest.media_type);
+        }
+        let parsed: Manifest = serde_json::from_slice(&manifest.bytes)
+            .with_context(|| format!("Failed to parse manifest {}", manifest.digest))?;
+
+        fs::create_dir_all(self.dir.join(BLOBS_DIR))?;
+        let config_path = self.blob_path(&parsed.config.digest);
+        if !config_path.exists() {
+            client.fetch_blob(&parsed.config, &config_path)?;
+        }
+        let config = fs::read(&config_path)?;
+        let image_platform = ImageConfig::parse(&config)?.platform();
+        if !image_platform.matches(platform) {
+            bail!(
+                "Image {} is for {}, not {}",
+                reference,
+                image_platform,
+                platform
+            );
+        }
+
+        for layer in &parsed.layers {
+            let path = self.blob_path(&layer.digest);
+            if path.exists() {
+                println!("{}: Already exists", layer.digest.short());
+            } else {
+                client.fetch_blob(layer, &path)?;
+                println!("{}: Pull complete", layer.digest.short());
+            }
+        }
+
+        self.import(Source {
+            manifest: Some(manifest.bytes),
+            config,
+            layers: parsed
+                .layers
+                .into_iter()
+                .map(|layer| SourceLayer {
+                    path: self.blob_path(&layer.digest),
+                    descriptor: Some(layer),
+                })
+                .collect(),
+            tags: reference.tag_key().into_iter().collect(),
+        })
+    }
+
+    /// Adds an image's blobs and layers to the store, skipping what is
+    /// already there, then points its tags at it.
+    fn import(&self, source: Source) -> Result<Image> {
+        let config = ImageConfig::parse(&source.config)?;
+        if config.rootfs.diff_ids.len() != source.layers.len() {
+            bail!(
+                "Image has {} layers but its config lists {}",
+                source.layers.len(),
+                config.rootfs.diff_ids.len()
+            );
+        }
+
+        let mut layers = Vec::new();
+        for (layer, diff_id) in source.layers.iter().zip(&config.rootfs.diff_ids) {
+            let layer = Layer {
+                digest: self.put_blob(&layer.path, layer.descriptor.as_ref())?,
+                diff_id: diff_id.clone(),
+            };
+            self.unpack(&layer)?;
+            layers.push(layer);
+        }
+        let id = self.write_blob(&source.config)?;
+        let manifest = match source.manifest {
+            Some(manifest) => manifest,
+            None => self.manifest_for(&id, source.config.len(), &layers)?,
+        };
+        let manifest = self.write_blob(&manifest)?;
+
+        if !self.record_path(&manifest).exists() {
+            self.save(&Image {
+                id,
+                manifest: manifest.clone(),
+                repo_tags: Vec::new(),
+                platform: config.platform(),
+                layers,
+                loaded: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            })?;
+        }
+
+        // A tag names one image at a time, so this moves it if needed.
+        let mut tags = self.tags()?;
+        for tag in source.tags {
+            tags.insert(tag, manifest.clone());
+        }
+        self.save_tags(&tags)?;
+
+        self.resolve(&manifest.to_string())
+    }
+
+    /// Containers created from `image`, which keep it from being deleted.
+    fn users(&self, image: &Image) -> Result<Vec<ContainerState>> {
+        Ok(StateStore::new(&self.root)
+            .list()?
+            .into_iter()
+            .filter(|c| c.image.as_ref() == Some(&image.manifest))
+            .collect())
+    }
+
+    /// Removes the image record and every tag pointing at it.
+    fn delete(&self, image: &Image) -> Result<Vec<Removed>> {
+        let mut removed = Vec::new();
+        let mut tags = self.tags()?;
+        tags.retain(|tag, manifest| {
+            let keep = *manifest != image.manifest;
+            if !keep {
+                removed.push(Removed::Untagged(tag.clone()));
+            }
+            keep
+        });
+        self.save_tags(&tags)?;
+
+        let path = self.record_path(&image.manifest);
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}", path))?;
+        removed.push(Removed::Deleted(image.id.clone()));
+        Ok(removed)
+    }
+
+    /// Removes an image. Given a tag, only that tag goes unless it is the
+    /// image's last; given an ID, the image goes with all its tags, which
+    /// needs `force` if there are several. Images used by a container are
+    /// kept unless `force`, and those of running containers always are.
+    pub fn remove(&self, reference: &str, force: bool) -> Result<Vec<Removed>> {
+        let _lock = self.lock()?;
+        let image = self.resolve(reference)?;
+        let tag = reference
+            .parse::<Reference>()
+            .ok()
+            .and_then(|r| r.tag_key())
+            .filter(|key| image.repo_tags.contains(key));
+
+        if let Some(tag) = &tag
+            && image.repo_tags.len() > 1
+        {
+            let mut tags = self.tags()?;
+            tags.remove(tag);
+            self.save_tags(&tags)?;
+            return Ok(vec![Removed::Untagged(tag.clone())]);
+        }
+        if tag.is_none() && image.repo_tags.len() > 1 && !force {
+            bail!(
+                "Image {} is tagged as {}; remove the tags or use --force",
+                image.id.short(),
+                image.repo_tags.join(", ")
+            );
+        }
+
+        let users = self.users(&image)?;
+        if let Some(running) = users.iter().find(|c| c.is_alive()) {
+            bail!(
+                "Image {} is used by running container {}",
+                reference,
+                running.id
+            );
+        }
+        if let Some(user) = users.first()
+            && !force
+        {
+            bail!(
+                "Image {} is used by container {}; delete it or use --force",
+                reference,
+                user.id
+            );
+        }
+
+        let mut removed = self.delete(&image)?;
+        removed.extend(self.collect_garbage()?);
+        Ok(removed)
+    }
+
+    /// Removes untagged images, or with `all` every image, that no container
+    /// uses, then whatever layers and blobs that leaves unreferenced.
+    pub fn prune(&self, all: bool) -> Result<Vec<Removed>> {
+        let _lock = self.lock()?;
+        let used: HashSet<Digest> = StateStore::new(&self.root)
+            .list()?
+            .into_iter()
+            .filter_map(|c| c.image)
+            .collect();
+
+        let mut removed = Vec::new();
+        for image in self.list()? {
+            if (all || image.repo_tags.is_empty()) && !used.contains(&image.manifest) {
+                removed.extend(self.delete(&image)?);
+            }
+        }
+        removed.extend(self.collect_garbage()?);
+        Ok(removed)
+    }
+
+    /// Deletes unpacked layers with no references left, and blobs no image
+    /// lists. Scratch entries are left to the load that owns them.
+    fn collect_garbage(&self) -> Result<Vec<Removed>> {
+        let images = self.list()?;
+        let refs = self.layer_refs()?;
+        let blobs: HashSet<&Digest> = images
+            .iter()
+            .flat_map(|i| {
+                [&i.id, &i.manifest]
+                    .into_iter()
+                    .chain(i.layers.iter().map(|l| &l.digest))
+            })
+            .collect();
+
+        let mut removed = Vec::new();
+        for (subdir, is_layer) in [(LAYERS_DIR, true), (BLOBS_DIR, false)] {
+            let dir = self.dir.join(subdir);
+            if !dir.exists() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}", dir))? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let Ok(digest) = Digest::from_hex(&name) else {
+                    continue;
+                };
+                if is_layer && !refs.contains_key(&digest) {
+                    fs::remove_dir_all(&path)
+                        .with_context(|| format!("Failed to remove layer {}", digest))?;
+                    removed.push(Removed::Deleted(digest));
+                } else if !is_layer && !blobs.contains(&digest) {
+                    fs::remove_file(&path)
+                        .with_context(|| format!("Failed to remove blob {}", digest))?;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use layer::tests::{Entry, build_tar};
+    use oci::MEDIA_TYPE_INDEX;
+    use registry::tests::FakeRegistry;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    fn config_json(diff_ids: &[Digest]) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "architecture": Platform::host().architecture,
+            "os": "linux",
+            "config": {"Cmd": ["/bin/sh"]},
+            "rootfs": {"type": "layers", "diff_ids": diff_ids},
+        }))
+        .unwrap()
+    }
+
+    fn write_blob(dir: &Path, media_type: &str, bytes: &[u8]) -> serde_json::Value {
+        let digest = Digest::of(bytes);
+        fs::create_dir_all(dir.join(BLOBS_DIR)).unwrap();
+        fs::write(layout_blob(dir, &digest), bytes).unwrap();
+        json!({"mediaType": media_type, "digest": digest, "size": bytes.len()})
+    }
+
+    /// Writes an OCI layout holding one image, behind a nested index when
+    /// `nested` is set, and returns the image's config digest.
+    fn write_oci_layout(dir: &Path, layers: &[&[Entry]], name: &str, nested: bool) -> Digest {
+        let tars: Vec<_> = layers.iter().map(|entries| build_tar(entries)).collect();
+        let diff_ids: Vec<_> = tars.iter().map(|tar| Digest::of(tar)).collect();
+        let config = config_json(&diff_ids);
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": MEDIA_TYPE_MANIFEST,
+            "config": write_blob(dir, MEDIA_TYPE_CONFIG, &config),
+            "layers": tars
+                .iter()
+                .map(|tar| write_blob(dir, MEDIA_TYPE_LAYER, tar))
+                .collect::<Vec<_>>(),
+        });
+        let mut top = write_blob(
+            dir,
+            MEDIA_TYPE_MANIFEST,
+            &serde_json::to_vec(&manifest).unwrap(),
+        );
+        if nested {
+            top["platform"] = json!({"architecture": Platform::host().architecture, "os": "linux"});
+            let index = json!({"schemaVersion": 2, "manifests": [
+                {"mediaType": MEDIA_TYPE_MANIFEST, "digest": format!("sha256:{}", "0".repeat(64)),
+                 "size": 1, "platform": {"architecture": "riscv64", "os": "linux"}},
+                top,
+            ]});
+            top = write_blob(dir, MEDIA_TYPE_INDEX, &serde_json::to_vec(&index).unwrap());
+        }
+        top["annotations"] = json!({ANNOTATION_IMAGE_NAME: name});
+        fs::write(
+            dir.join(OCI_LAYOUT_FILE),
+            r#"{"imageLayoutVersion": "1.0.0"}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join(OCI_INDEX_FILE),
+            serde_json::to_vec(&json!({"schemaVersion": 2, "manifests": [top]})).unwrap(),
+        )
+        .unwrap();
+        Digest::of(&config)
+    }
+
+    fn tar_dir(dir: &Path, output: &Path) {
+        let mut builder = tar::Builder::new(File::create(output).unwrap());
+        builder.append_dir_all(".", dir).unwrap();
+        builder.finish().unwrap();
+    }
+
+    /// Loads an image made of `layers` from a fresh OCI layout.
+    fn load(store: &ImageStore, layers: &[&[Entry]], name: &str) -> Image {
+        let layout = TempDir::new().unwrap();
+        write_oci_layout(layout.path(), layers, name, false);
+        store.load(layout.path()).unwrap().remove(0)
+    }
+
+    fn entries(dir: &Path) -> usize {
+        fs::read_dir(dir).map_or(0, |d| d.count())
+    }
+
+    const BASE: &[Entry] = &[("bin", None), ("bin/sh", Some("shell"))];
+
+    #[test]
+    fn test_tag_names() {
+        assert_eq!(
+            tag_key("docker.io/library/alpine:3.19").as_deref(),
+            Some("alpine:3.19")
+        );
+        assert_eq!(tag_key("alpine").as_deref(), Some("alpine:latest"));
+        assert_eq!(tag_key("Not A Name"), None);
+
+        let mut annotations = HashMap::new();
+        annotations.insert(ANNOTATION_REF_NAME.to_string(), "3.19".to_string());
+        assert_eq!(annotated_name(&annotations), None);
+        annotations.insert(ANNOTATION_REF_NAME.to_string(), "app:1".to_string());
+        assert_eq!(annotated_name(&annotations).as_deref(), Some("app:1"));
+    }
+
+    #[test]
+    fn test_archive_path_rejects_escapes() {
+        let dir = Path::new("/tmp/archive");
+        assert_eq!(
+            archive_path(dir, "abc/layer.tar").unwrap(),
+            dir.join("abc/layer.tar")
+        );
+        for bad in ["", "../layer.tar", "/etc/passwd", "a/../../b"] {
+            assert!(archive_path(dir, bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_load_oci_layout_directory() {
+        let layout = TempDir::new().unwrap();
+        let root = TempDir::new().unwrap();
+        let id = write_oci_layout(
+            layout.path(),
+            &[
+                BASE,
+                &[("etc/.wh.motd", Some("")), ("etc/hostname", Some("box"))],
+            ],
+            "docker.io/library/test:1",
+            true,
+        );
+
+        let store = ImageStore::new(root.path());
+        let images = store.load(layout.path()).unwrap();
+        assert_eq!(images.len(), 1);
+        let image = &images[0];
+        assert_eq!(image.id, id);
+        assert_eq!(image.repo_tags, ["test:1"]);
+        assert_eq!(image.platform, Platform::host());
+
+        let dirs = store.layer_dirs(image).unwrap();
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(fs::read_to_string(dirs[0].join("bin/sh")).unwrap(), "shell");
+        assert_eq!(
+            fs::read_to_string(dirs[1].join("etc/hostname")).unwrap(),
+            "box"
+        );
+        for digest in [&image.id, &image.manifest, &image.layers[0].digest] {
+            assert!(store.blob_path(digest).exists());
+        }
+        assert_eq!(store.list().unwrap(), images);
+        let config = store.config(image).unwrap().config.unwrap();
+        assert_eq!(config.cmd.unwrap(), ["/bin/sh"]);
+        assert_eq!(store.resolve("test:1").unwrap(), *image);
+        assert_eq!(store.resolve(&id.hex()[..6]).unwrap(), *image);
+        assert_eq!(
+            store
+                .resolve(&format!("test@{}", image.manifest))
+                .unwrap()
+                .id,
+            id
+        );
+        assert!(store.resolve("test:2").is_err());
+    }
+
+    #[test]
+    fn test_load_tarball_moves_tags_and_shares_layers() {
+        let root = TempDir::new().unwrap();
+        let store = ImageStore::new(root.path());
+
+        let layout = TempDir::new().unwrap();
+        write_oci_layout(layout.path(), &[BASE, &[("v", Some("1"))]], "app", false);
+        let archive = root.path().join("first.tar");
+        tar_dir(layout.path(), &archive);
+        let first = store.load(&archive).unwrap().remove(0);
+
+        let second = load(&store, &[BASE, &[("v", Some("2"))]], "app:latest");
+
+        assert!(
+            store
+                .resolve(&first.id.to_string())
+                .unwrap()
+                .repo_tags
+                .is_empty()
+        );
+        assert_eq!(second.repo_tags, ["app:latest"]);
+        assert_eq!(first.layers[0], second.layers[0]);
+        // The base layer is unpacked once; scratch directories are gone.
+        assert_eq!(entries(&store.dir.join(LAYERS_DIR)), 3);
+        assert_eq!(store.layer_refs().unwrap()[&first.layers[0].diff_id], 2);
+        assert!(
+            !store
+                .dir
+                .read_dir()
+                .unwrap()
+                .any(|e| { e.unwrap().file_name().to_string_lossy().starts_with("tmp-") })
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_tampered_blob() {
+        let layout = TempDir::new().unwrap();
+        let root = TempDir::new().unwrap();
+        write_oci_layout(layout.path(), &[&[("file", Some("data"))]], "app:1", false);
+
+        for entry in fs::read_dir(layout.path().join(BLOBS_DIR)).unwrap() {
+            let path = entry.unwrap().path();
+            let mut bytes = fs::read(&path).unwrap();
+            if bytes.starts_with(b"file") {
+                bytes[512] = b'X';
+                fs::write(&path, bytes).unwrap();
+            }
+        }
+
+        let store = ImageStore::new(root.path());
+        let err = store.load(layout.path()).unwrap_err();
+        assert!(err.to_string().contains("Digest mismatch"), "{:#}", err);
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(entries(&store.dir.join(LAYERS_DIR)), 0);
+        assert_eq!(entries(&store.dir.join(BLOBS_DIR)), 0);
+    }
+
+    #[test]
+    fn test_load_docker_save_archive() {
+        let archive_dir = TempDir::new().unwrap();
+        let root = TempDir::new().unwrap();
+        let dir = archive_dir.path();
+
+        let tar = build_tar(&[("etc", None), ("etc/os-release", Some("ID=test"))]);
+        let config = config_json(&[Digest::of(&tar)]);
+        let config_name = format!("{}.json", Digest::of(&config).hex());
+        fs::create_dir(dir.join("layer1")).unwrap();
+        fs::write(dir.join("layer1/layer.tar"), &tar).unwrap();
+        fs::write(dir.join(&config_name), &config).unwrap();
+        fs::write(
+            dir.join(DOCKER_MANIFEST_FILE),
+            serde_json::to_vec(&json!([{
+                "Config": config_name,
+                "RepoTags": ["docker.io/library/test:2"],
+                "Layers": ["layer1/layer.tar"],
+            }]))
+            .unwrap(),
+        )
+        .unwrap();
+
+        let archive = root.path().join("save.tar");
+        tar_dir(dir, &archive);
+        let store = ImageStore::new(root.path());
+        let image = store.load(&archive).unwrap().remove(0);
+        assert_eq!(image.repo_tags, ["test:2"]);
+        let layer = &store.layer_dirs(&image).unwrap()[0];
+        assert_eq!(
+            fs::read_to_string(layer.join("etc/os-release")).unwrap(),
+            "ID=test"
+        );
+
+        // The manifest written for the image describes the stored blobs.
+        let manifest: Manifest =
+            serde_json::from_slice(&fs::read(store.blob_path(&image.manifest)).unwrap()).unwrap();
+        assert_eq!(manifest.config.digest, image.id);
+        assert_eq!(manifest.layers[0].digest, Digest::of(&tar));
+        assert_eq!(manifest.layers[0].media_type, MEDIA_TYPE_LAYER);
+        // Loading again yields the same image.
+        assert_eq!(store.load(&archive).unwrap()[0], image);
+
+        // A config whose name does not match its content is refused.
+        fs::write(dir.join(&config_name), b"{}").unwrap();
+        assert!(store.load(dir).is_err());
+    }
+
+    #[test]
+    fn test_pull_from_registry() {
+        let base = build_tar(BASE);
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        io::Write::write_all(&mut gzip, &build_tar(&[("app", Some("1"))])).unwrap();
+        let app = gzip.finish().unwrap();
+        let config = config_json(&[
+            Digest::of(&base),
+            Digest::of(&build_tar(&[("app", Some("1"))])),
+        ]);
+        let blob = |media_type: &str, bytes: &[u8]| json!({"mediaType": media_type, "digest": Digest::of(bytes), "size": bytes.len()});
+        let manifest = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": MEDIA_TYPE_MANIFEST,
+            "config": blob(MEDIA_TYPE_CONFIG, &config),
+            "layers": [blob(MEDIA_TYPE_LAYER, &base), blob(MEDIA_TYPE_LAYER_GZIP, &app)],
+        }))
+        .unwrap();
+        let mut host = blob(MEDIA_TYPE_MANIFEST, &manifest);
+        host["platform"] = json!({"architecture": Platform::host().architecture, "os": "linux"});
+        let index = serde_json::to_vec(
+            &json!({"schemaVersion": 2, "mediaType": MEDIA_TYPE_INDEX, "manifests": [
+                {"mediaType": MEDIA_TYPE_MANIFEST, "digest": Digest::of(b"s390x"), "size": 5,
+                 "platform": {"architecture": "s390x", "os": "linux"}},
+                host,
+            ]}),
+        )
+        .unwrap();
+
+        let registry = Arc::new(Mutex::new(FakeRegistry::default()));
+        {
+            let mut registry = registry.lock().unwrap();
+            let mut add = |path: String, media_type: &str, bytes: &[u8]| {
+                registry
+                    .content
+                    .insert(path, (media_type.to_string(), bytes.to_vec()));
+            };
+            add("test/app/manifests/1".to_string(), MEDIA_TYPE_INDEX, &index);
+            add(
+                format!("test/app/manifests/{}", Digest::of(&manifest)),
+                MEDIA_TYPE_MANIFEST,
+                &manifest,
+            );
+            for bytes in [&config, &base, &app] {
+                add(
+                    format!("test/app/blobs/{}", Digest::of(bytes)),
+                    "application/octet-stream",
+                    bytes,
+                );
+            }
+            registry
+                .flaky
+                .push(format!("test/app/blobs/{}", Digest::of(&app)));
+        }
+        let addr = FakeRegistry::serve(registry.clone());
+
+        let root = TempDir::new().unwrap();
+        let store = ImageStore::new(root.path());
+        let reference: Reference = format!("{}/test/app:1", addr).parse().unwrap();
+        let mut client = Client::new(&reference, None, false);
+        let image = store
+            .pull(&mut client, &reference, &Platform::host())
+            .unwrap();
+        assert_eq!(image.manifest, Digest::of(&manifest));
+        assert_eq!(image.id, Digest::of(&config));
+        assert_eq!(image.repo_tags, [format!("{}/test/app:1", addr)]);
+        let dirs = store.layer_dirs(&image).unwrap();
+        assert_eq!(fs::read_to_string(dirs[1].join("app")).unwrap(), "1");
+        assert!(!store.dir.join(BLOBS_DIR).read_dir().unwrap().any(|e| {
+            e.unwrap()
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "partial")
+        }));
+
+        // Pulling again only fetches manifests; by digest it adds no tag.
+        let before = registry.lock().unwrap().requests.len();
+        let pinned: Reference = format!("{}/test/app@{}", addr, Digest::of(&manifest))
+            .parse()
+            .unwrap();
+        assert_eq!(
+            store.pull(&mut client, &pinned, &Platform::host()).unwrap(),
+            image
+        );
+        let requests = registry.lock().unwrap().requests[before..].to_vec();
+        assert!(
+            requests.iter().all(|r| r.contains("/manifests/")),
+            "{:?}",
+            requests
+        );
+
+        let other: Platform = "linux/s390x".parse().unwrap();
+        assert!(store.pull(&mut client, &reference, &other).is_err());
+    }
+
+    #[test]
+    fn test_remove_untags_then_deletes_unshared_layers() {
+        let root = TempDir::new().unwrap();
+        let store = ImageStore::new(root.path());
+        let app = load(&store, &[BASE, &[("app", Some("1"))]], "app:1");
+        let tool = load(&store, &[BASE, &[("tool", Some("1"))]], "tool:1");
+        let mut tags = store.tags().unwrap();
+        tags.insert("app:stable".to_string(), app.manifest.clone());
+        store.save_tags(&tags).unwrap();
+
+        // Removing by ID needs --force while several tags name the image.
+        assert!(store.remove(app.id.short(), false).is_err());
+        assert_eq!(
+            store.remove("app:stable", false).unwrap(),
+            [Removed::Untagged("app:stable".to_string())]
+        );
+
+        let removed = store.remove("app:1", false).unwrap();
+        assert_eq!(
+            removed,
+            [
+                Removed::Untagged("app:1".to_string()),
+                Removed::Deleted(app.id.clone()),
+                Removed::Deleted(app.layers[1].diff_id.clone()),
+            ]
+        );
+        // The shared base layer stays for `tool`.
+        assert_eq!(store.layer_dirs(&tool).unwrap().len(), 2);
+        assert!(!store.blob_path(&app.manifest).exists());
+        assert!(store.blob_path(&tool.manifest).exists());
+        assert!(store.resolve("app:1").is_err());
+    }
+
+    #[test]
+    fn test_containers_pin_images() {
+        let root = TempDir::new().unwrap();
+        let store = ImageStore::new(root.path());
+        let image = load(&store, &[BASE], "app:1");
+
+        let mut state = ContainerState::new("c1", Path::new("/"), "/bin/sh", &[]);
+        state.image = Some(image.manifest.clone());
+        StateStore::new(root.path()).save(&state).unwrap();
+
+        let err = store.remove("app:1", false).unwrap_err();
+        assert!(err.to_string().contains("container c1"), "{:#}", err);
+        assert!(store.prune(true).unwrap().is_empty());
+
+        assert!(store.remove("app:1", true).is_ok());
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_removes_untagged_images() {
+        let root = TempDir::new().unwrap();
+        let store = ImageStore::new(root.path());
+        let old = load(&store, &[&[("v", Some("1"))]], "app:1");
+        let new = load(&store, &[&[("v", Some("2"))]], "app:1");
+
+        let removed = store.prune(false).unwrap();
+        assert!(removed.contains(&Removed::Deleted(old.id)));
+        assert_eq!(store.list().unwrap(), [new]);
+
+        store.prune(true).unwrap();
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(entries(&store.dir.join(LAYERS_DIR)), 0);
+        assert_eq!(entries(&store.dir.join(BLOBS_DIR)), 0);
+    }
+
+    #[test]
+    fn test_changes_hold_the_store_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(dir.path());
+        let lock = store.lock().unwrap();
+
+        let other = File::open(dir.path().join("images").join(LOCK_FILE)).unwrap();
+        assert!(flock(other.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err());
+        drop(lock);
+        assert!(flock(other.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_ok());
+        // Once released, changes can take it again.
+        flock(other.as_raw_fd(), FlockArg::Unlock).unwrap();
+        assert!(store.prune(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_format_table() {
+        let image = Image {
+            id: Digest::of(b"config"),
+            manifest: Digest::of(b"manifest"),
+            repo_tags: vec!["localhost:5000/app:v1".to_string()],
+            platform: Platform::host(),
+            layers: Vec::new(),
+            loaded: 0,
+        };
+        let untagged = Image {
+            repo_tags: Vec::new(),
+            ..image.clone()
+        };
+        let table = format_table(&[image, untagged]);
+        let lines: Vec<_> = table.lines().collect();
+        assert!(lines[0].starts_with("REPOSITORY"));
+        assert!(lines[1].starts_with("localhost:5000/app"));
+        assert!(lines[1].contains(" v1 "));
+        assert!(lines[1].contains(Digest::of(b"config").short()));
+        assert!(lines[2].starts_with("<none>"));
+    }
+}
@@ -0,0 +1,353 @@
+use super::oci::{Digest, DigestReader, verify};
+use anyhow::{Context, Result, bail};
+use flate2::read::GzDecoder;
+use nix::sys::stat::{Mode, SFlag, makedev, mknod};
+use nix::unistd::getuid;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+/// A file hiding `<name>` from the layers below: `.wh.<name>`.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Hides everything the layers below put in its directory.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// Marks an opaque directory for overlay mounted with `userxattr`, which is
+/// how containers mount it.
+const OPAQUE_XATTR: &CStr = c"user.overlay.opaque";
+const TRUSTED_OPAQUE_XATTR: &CStr = c"trusted.overlay.opaque";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+fn magic(path: &Path) -> Result<Vec<u8>> {
+    let mut magic = Vec::with_capacity(4);
+    File::open(path)
+        .with_context(|| format!("Failed to open {:?}", path))?
+        .take(4)
+        .read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+/// Whether the file at `path` is gzip-compressed.
+pub fn is_gzip(path: &Path) -> Result<bool> {
+    Ok(magic(path)?.starts_with(&GZIP_MAGIC))
+}
+
+/// Opens a tar stream that may be gzip-compressed. Compression is detected
+/// from the content rather than trusted from a media type.
+pub fn open_tar(path: &Path) -> Result<Box<dyn Read>> {
+    let magic = magic(path)?;
+    let file = BufReader::new(File::open(path)?);
+
+    if magic.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        bail!("{:?} is zstd-compressed, which is not supported", path)
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Turns a path from a tar entry into a relative path with only normal
+/// components, or `None` for the archive root. `..` is rejected outright.
+fn entry_path(path: &Path) -> Result<Option<PathBuf>> {
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => bail!("Layer entry {:?} escapes the rootfs", path),
+        }
+    }
+    Ok((!clean.as_os_str().is_empty()).then_some(clean))
+}
+
+/// Returns `dir/rel`, creating missing directories on the way. Refuses to
+/// go through anything that is not a real directory, so a symlink in the
+/// layer cannot redirect a whiteout outside it.
+fn real_dir(dir: &Path, rel: &Path) -> Result<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for part in rel.components() {
+        path.push(part);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => bail!("Whiteout below {:?}, which is not a directory", path),
+            Err(_) => {
+                fs::create_dir(&path).with_context(|| format!("Failed to create {:?}", path))?
+            }
+        }
+    }
+    Ok(path)
+}
+
+fn set_xattr(path: &Path, name: &CStr, value: &[u8]) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whiteout entries of one layer, which are not unpacked as files.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Whiteouts {
+    /// Paths hidden by `.wh.<name>` files.
+    files: Vec<PathBuf>,
+    /// Directories whose lower contents are hidden by `.wh..wh..opq`.
+    opaque: Vec<PathBuf>,
+}
+
+impl Whiteouts {
+    /// Records `rel` if it is a whiteout; returns whether it was one.
+    fn record(&mut self, rel: &Path) -> bool {
+        let Some(name) = rel.file_name().and_then(|n| n.to_str()) else {
+            return false;
+        };
+        let parent = rel.parent().unwrap_or(Path::new(""));
+        if name == OPAQUE_WHITEOUT {
+            self.opaque.push(parent.to_path_buf());
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            self.files.push(parent.join(hidden));
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Writes the whiteouts into `dir` the way overlayfs expects them: a 0/0
+    /// character device per hidden file and an xattr on opaque directories.
+    fn apply(&self, dir: &Path) -> Result<()> {
+        for rel in &self.files {
+            let parent = real_dir(dir, rel.parent().unwrap_or(Path::new("")))?;
+            let path = parent.join(rel.file_name().unwrap_or_default());
+            mknod(&path, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))
+                .with_context(|| format!("Failed to create whiteout {:?}", path))?;
+        }
+        for rel in &self.opaque {
+            let path = real_dir(dir, rel)?;
+            set_xattr(&path, OPAQUE_XATTR, b"y")
+                .with_context(|| format!("Failed to mark {:?} opaque", path))?;
+            // Only readable by overlay mounted without `userxattr`, and only
+            // settable with CAP_SYS_ADMIN.
+            let _ = set_xattr(&path, TRUSTED_OPAQUE_XATTR, b"y");
+        }
+        Ok(())
+    }
+}
+
+/// Unpacks one layer tarball into the empty directory `dir`, converting its
+/// whiteouts to the overlayfs format so layers can be stacked as lower dirs.
+/// Fails if the uncompressed content does not hash to `diff_id`.
+pub fn unpack_layer(blob: &Path, diff_id: &Digest, dir: &Path) -> Result<()> {
+    let mut reader = DigestReader::new(open_tar(blob)?);
+    let mut whiteouts = Whiteouts::default();
+    {
+        let mut archive = tar::Archive::new(&mut reader);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_preserve_ownerships(getuid().is_root());
+        archive.set_overwrite(true);
+
+        for entry in archive.entries()? {
+            let mut entry = entry.with_context(|| format!("Corrupt layer {:?}", blob))?;
+            let Some(rel) = entry_path(&entry.path()?)? else {
+                continue;
+            };
+            if whiteouts.record(&rel) {
+                continue;
+            }
+            entry
+                .unpack_in(dir)
+                .with_context(|| format!("Failed to unpack {:?} from {:?}", rel, blob))?;
+        }
+    }
+    let (actual, _) = reader
+        .finish()
+        .with_context(|| format!("Failed to read layer {:?}", blob))?;
+    verify(&format!("layer {:?}", blob), diff_id, &actual)?;
+    whiteouts.apply(dir)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    /// A tar entry: a path, and file contents or `None` for a directory.
+    pub(crate) type Entry<'a> = (&'a str, Option<&'a str>);
+
+    pub(crate) fn build_tar(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            match contents {
+                Some(data) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    header.set_mode(0o644);
+                    builder
+                        .append_data(&mut header, path, data.as_bytes())
+                        .unwrap();
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                    builder.append_data(&mut header, path, &[][..]).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn write_layer(dir: &Path, name: &str, entries: &[Entry], gzip: bool) -> (PathBuf, Digest) {
+        let tar = build_tar(entries);
+        let diff_id = Digest::of(&tar);
+        let path = dir.join(name);
+        if gzip {
+            let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::fast());
+            encoder.write_all(&tar).unwrap();
+            encoder.finish().unwrap();
+        } else {
+            fs::write(&path, tar).unwrap();
+        }
+        (path, diff_id)
+    }
+
+    #[test]
+    fn test_entry_path() {
+        assert_eq!(
+            entry_path(Path::new("./etc/passwd")).unwrap(),
+            Some(PathBuf::from("etc/passwd"))
+        );
+        assert_eq!(
+            entry_path(Path::new("/usr/bin")).unwrap(),
+            Some(PathBuf::from("usr/bin"))
+        );
+        assert_eq!(entry_path(Path::new("./")).unwrap(), None);
+        assert!(entry_path(Path::new("etc/../../escape")).is_err());
+    }
+
+    #[test]
+    fn test_record_whiteouts() {
+        let mut whiteouts = Whiteouts::default();
+        assert!(whiteouts.record(Path::new("etc/.wh.motd")));
+        assert!(whiteouts.record(Path::new("var/cache/.wh..wh..opq")));
+        assert!(!whiteouts.record(Path::new("etc/hosts")));
+        assert_eq!(
+            whiteouts,
+            Whiteouts {
+                files: vec![PathBuf::from("etc/motd")],
+                opaque: vec![PathBuf::from("var/cache")],
+            }
+        );
+    }
+
+    fn opaque_xattr(path: &Path) -> Option<Vec<u8>> {
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let mut value = [0u8; 8];
+        let n = unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                OPAQUE_XATTR.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        (n >= 0).then(|| value[..n as usize].to_vec())
+    }
+
+    #[test]
+    fn test_unpack_layer_converts_whiteouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer_dir = dir.path().join("layer");
+        fs::create_dir(&layer_dir).unwrap();
+
+        let (layer, diff_id) = write_layer(
+            dir.path(),
+            "layer.tar.gz",
+            &[
+                ("etc", None),
+                ("etc/.wh.motd", Some("")),
+                ("etc/hostname", Some("box")),
+                ("var/cache/new", Some("fresh")),
+                ("var/cache/.wh..wh..opq", Some("")),
+                ("gone/.wh.file", Some("")),
+            ],
+            true,
+        );
+        unpack_layer(&layer, &diff_id, &layer_dir).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(layer_dir.join("etc/hostname")).unwrap(),
+            "box"
+        );
+        for whiteout in ["etc/motd", "gone/file"] {
+            let meta = fs::symlink_metadata(layer_dir.join(whiteout)).unwrap();
+            assert!(meta.file_type().is_char_device(), "{}", whiteout);
+            assert_eq!(meta.rdev(), 0);
+        }
+        assert!(!layer_dir.join("etc/.wh.motd").exists());
+        assert!(!layer_dir.join("var/cache/.wh..wh..opq").exists());
+        assert_eq!(
+            fs::read_to_string(layer_dir.join("var/cache/new")).unwrap(),
+            "fresh"
+        );
+        assert_eq!(
+            opaque_xattr(&layer_dir.join("var/cache")).as_deref(),
+            Some(&b"y"[..])
+        );
+        assert_eq!(opaque_xattr(&layer_dir.join("etc")), None);
+    }
+
+    #[test]
+    fn test_whiteouts_do_not_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        let layer_dir = dir.path().join("layer");
+        fs::create_dir(&outside).unwrap();
+        fs::create_dir(&layer_dir).unwrap();
+        std::os::unix::fs::symlink(&outside, layer_dir.join("lib")).unwrap();
+
+        let whiteouts = Whiteouts {
+            files: vec![PathBuf::from("lib/libc.so")],
+            opaque: vec![],
+        };
+        assert!(whiteouts.apply(&layer_dir).is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_unpack_rejects_wrong_diff_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let (layer, _) = write_layer(dir.path(), "layer", &[("file", Some("data"))], false);
+
+        let err = unpack_layer(&layer, &Digest::of(b"other"), dir.path()).unwrap_err();
+        assert!(err.to_string().contains("Digest mismatch"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_unpack_rejects_zstd() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = dir.path().join("layer");
+        fs::write(&layer, [0x28, 0xb5, 0x2f, 0xfd, 0, 0]).unwrap();
+        assert!(unpack_layer(&layer, &Digest::of(b""), dir.path()).is_err());
+    }
+}
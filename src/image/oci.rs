@@ -0,0 +1,442 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+pub const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const MEDIA_TYPE_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+/// Annotation holding the full image name, written by containerd and Docker.
+pub const ANNOTATION_IMAGE_NAME: &str = "io.containerd.image.name";
+/// Annotation holding the reference an image was saved under.
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+#[cfg(target_arch = "x86_64")]
+const HOST_ARCH: &str = "amd64";
+#[cfg(target_arch = "aarch64")]
+const HOST_ARCH: &str = "arm64";
+
+/// A `sha256:<hex>` content digest. Other algorithms are rejected, so the
+/// hex part is always safe to use as a file name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Digest(String);
+
+impl Digest {
+    pub fn of(bytes: &[u8]) -> Self {
+        Digest(hex(&Sha256::digest(bytes)))
+    }
+
+    /// Parses a bare 64 character hex string, as used in file names.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        if hex.len() != 64 || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            bail!("Invalid sha256 digest {:?}", hex);
+        }
+        Ok(Digest(hex.to_string()))
+    }
+
+    pub fn hex(&self) -> &str {
+        &self.0
+    }
+
+    /// The first 12 hex characters, as shown in listings.
+    pub fn short(&self) -> &str {
+        &self.0[..12]
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl FromStr for Digest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("sha256", hex)) => Digest::from_hex(hex),
+            Some((algorithm, _)) => bail!("Unsupported digest algorithm {:?}", algorithm),
+            None => bail!("Invalid digest {:?}: expected sha256:<hex>", s),
+        }
+    }
+}
+
+impl TryFrom<String> for Digest {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Digest> for String {
+    fn from(digest: Digest) -> String {
+        digest.to_string()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sha256:{}", self.0)
+    }
+}
+
+/// Hashes everything read through it, so content can be verified while it
+/// is streamed.
+pub struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(inner: R) -> Self {
+        DigestReader {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Reads whatever the consumer left unread (such as tar padding) and
+    /// returns the digest and size of the whole stream.
+    pub fn finish(mut self) -> io::Result<(Digest, u64)> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok((Digest(hex(&self.hasher.finalize())), self.size))
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+/// Fails unless `actual` is what `what` was expected to hash to.
+pub fn verify(what: &str, expected: &Digest, actual: &Digest) -> Result<()> {
+    if expected != actual {
+        bail!(
+            "Digest mismatch for {}: expected {}, got {}",
+            what,
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
+
+/// The OS and CPU an image or manifest was built for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    pub fn host() -> Self {
+        Platform {
+            architecture: HOST_ARCH.to_string(),
+            os: "linux".to_string(),
+            variant: None,
+        }
+    }
+
+    /// Whether images for `self` run on `other`. A missing variant matches
+    /// any variant.
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && match (&self.variant, &other.variant) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    /// Parses `os/arch[/variant]`, as in `linux/arm64/v8`.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('/').collect();
+        let (os, architecture, variant) = match parts.as_slice() {
+            [os, arch] => (os, arch, None),
+            [os, arch, variant] => (os, arch, Some(variant.to_string())),
+            _ => bail!("Invalid platform {:?}: expected OS/ARCH[/VARIANT]", s),
+        };
+        if os.is_empty() || architecture.is_empty() || variant.as_deref() == Some("") {
+            bail!("Invalid platform {:?}: expected OS/ARCH[/VARIANT]", s);
+        }
+        Ok(Platform {
+            architecture: architecture.to_string(),
+            os: os.to_string(),
+            variant,
+        })
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// A reference to a blob, as found in indexes and manifests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: Digest,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+impl Descriptor {
+    pub fn is_index(&self) -> bool {
+        matches!(
+            self.media_type.as_str(),
+            MEDIA_TYPE_INDEX | MEDIA_TYPE_DOCKER_MANIFEST_LIST
+        )
+    }
+
+    pub fn is_manifest(&self) -> bool {
+        matches!(
+            self.media_type.as_str(),
+            MEDIA_TYPE_MANIFEST | MEDIA_TYPE_DOCKER_MANIFEST
+        )
+    }
+}
+
+/// An image index (or Docker manifest list): manifests for several
+/// platforms, or the images in an OCI layout's `index.json`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Index {
+    pub manifests: Vec<Descriptor>,
+}
+
+impl Index {
+    /// The manifest to use on `platform`. Entries without a platform, which
+    /// single-platform indexes often leave out, match anything.
+    pub fn select(&self, platform: &Platform) -> Result<&Descriptor> {
+        self.manifests
+            .iter()
+            .filter(|m| m.is_manifest())
+            .find(|m| m.platform.as_ref().is_none_or(|p| p.matches(platform)))
+            .with_context(|| format!("No image for platform {} in the index", platform))
+    }
+}
+
+/// A single-platform image: its config and its layers, bottom first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+/// The parts of an image config needed to unpack and run it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImageConfig {
+    pub architecture: String,
+    pub os: String,
+    pub rootfs: RootFs,
+    /// Defaults for containers run from the image.
+    #[serde(default)]
+    pub config: Option<ExecConfig>,
+}
+
+/// How a container runs unless `run` says otherwise. Every field may be
+/// missing or null.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ExecConfig {
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    /// `KEY=VALUE` entries.
+    pub env: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+    /// `user[:group]`, by name or number.
+    pub user: Option<String>,
+    /// Keys like `80/tcp`; the values are always empty objects.
+    pub exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
+    /// Paths that get a fresh volume in each container.
+    pub volumes: Option<BTreeMap<String, serde_json::Value>>,
+    pub stop_signal: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RootFs {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Digests of the uncompressed layer tarballs, bottom first.
+    pub diff_ids: Vec<Digest>,
+}
+
+impl ImageConfig {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let config: ImageConfig =
+            serde_json::from_slice(bytes).context("Failed to parse image config")?;
+        if config.rootfs.type_ != "layers" {
+            bail!("Unsupported rootfs type {:?}", config.rootfs.type_);
+        }
+        Ok(config)
+    }
+
+    pub fn platform(&self) -> Platform {
+        Platform {
+            architecture: self.architecture.clone(),
+            os: self.os.clone(),
+            variant: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn test_digest_parse_and_hash() {
+        let digest: Digest = format!("sha256:{}", EMPTY).parse().unwrap();
+        assert_eq!(digest, Digest::of(b""));
+        assert_eq!(digest.hex(), EMPTY);
+        assert_eq!(digest.short(), "e3b0c44298fc");
+        assert_eq!(digest.to_string(), format!("sha256:{}", EMPTY));
+
+        for bad in [
+            EMPTY,
+            "sha512:abcd",
+            "sha256:../../etc/passwd",
+            "sha256:E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855",
+        ] {
+            assert!(bad.parse::<Digest>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_digest_reader_hashes_unread_tail() {
+        let mut reader = DigestReader::new(&b"hello world"[..]);
+        let mut head = [0u8; 5];
+        reader.read_exact(&mut head).unwrap();
+        let (digest, size) = reader.finish().unwrap();
+        assert_eq!(digest, Digest::of(b"hello world"));
+        assert_eq!(size, 11);
+    }
+
+    #[test]
+    fn test_index_selects_host_platform() {
+        let index: Index = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [
+                {
+                    "mediaType": MEDIA_TYPE_MANIFEST,
+                    "digest": format!("sha256:{}", "1".repeat(64)),
+                    "size": 1,
+                    "platform": {"architecture": "s390x", "os": "linux"}
+                },
+                {
+                    "mediaType": MEDIA_TYPE_MANIFEST,
+                    "digest": format!("sha256:{}", "2".repeat(64)),
+                    "size": 1,
+                    "platform": {"architecture": HOST_ARCH, "os": "linux"}
+                }
+            ]
+        }))
+        .unwrap();
+        let selected = index.select(&Platform::host()).unwrap();
+        assert_eq!(selected.digest.hex(), "2".repeat(64));
+
+        let windows = Platform {
+            os: "windows".to_string(),
+            ..Platform::host()
+        };
+        assert!(index.select(&windows).is_err());
+    }
+
+    #[test]
+    fn test_parse_platform() {
+        let platform: Platform = "linux/arm64/v8".parse().unwrap();
+        assert_eq!(platform.architecture, "arm64");
+        assert_eq!(platform.variant.as_deref(), Some("v8"));
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+        assert_eq!(
+            "linux/amd64".parse::<Platform>().unwrap().to_string(),
+            "linux/amd64"
+        );
+        for bad in ["linux", "linux/", "/amd64", "linux/arm/v7/x", "linux/arm/"] {
+            assert!(bad.parse::<Platform>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_image_config() {
+        let config = ImageConfig::parse(
+            format!(
+                r#"{{"architecture":"amd64","os":"linux","rootfs":{{"type":"layers","diff_ids":["sha256:{}"]}}}}"#,
+                EMPTY
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(config.rootfs.diff_ids, [Digest::of(b"")]);
+        assert_eq!(config.platform().to_string(), "linux/amd64");
+
+        assert!(ImageConfig::parse(br#"{"architecture":"amd64","os":"linux"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_exec_config() {
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "config": {
+                "Entrypoint": null,
+                "Cmd": ["/bin/sh"],
+                "Env": ["PATH=/usr/bin:/bin"],
+                "WorkingDir": "/srv",
+                "ExposedPorts": {"80/tcp": {}, "53/udp": {}},
+                "Volumes": {"/data": {}},
+                "StopSignal": "SIGQUIT",
+                "Labels": {"maintainer": "someone"}
+            },
+            "rootfs": {"type": "layers", "diff_ids": []}
+        }))
+        .unwrap();
+        let exec = config.config.unwrap();
+        assert_eq!(exec.entrypoint, None);
+        assert_eq!(exec.cmd.unwrap(), ["/bin/sh"]);
+        assert_eq!(exec.working_dir.as_deref(), Some("/srv"));
+        assert_eq!(exec.user, None);
+        let ports: Vec<_> = exec.exposed_ports.unwrap().into_keys().collect();
+        assert_eq!(ports, ["53/udp", "80/tcp"]);
+        assert_eq!(exec.volumes.unwrap().len(), 1);
+        assert_eq!(exec.stop_signal.as_deref(), Some("SIGQUIT"));
+    }
+}
@@ -0,0 +1,198 @@
+use super::oci::Digest;
+use anyhow::{Result, bail};
+use std::fmt;
+use std::str::FromStr;
+
+/// Registry used for names without one, as in `alpine:3.19`.
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_TAG: &str = "latest";
+/// Docker Hub keeps single-component names under `library/`.
+const OFFICIAL_NAMESPACE: &str = "library/";
+
+/// An image reference: `[registry/]repository[:tag][@digest]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    /// Repository path, with `library/` added for official Docker Hub images.
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<Digest>,
+}
+
+fn valid_tag(tag: &str) -> bool {
+    tag.len() <= 128
+        && tag
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+fn valid_component(component: &str) -> bool {
+    let alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    component.starts_with(alnum)
+        && component.ends_with(alnum)
+        && component
+            .chars()
+            .all(|c| alnum(c) || matches!(c, '.' | '_' | '-'))
+}
+
+impl Reference {
+    /// The tag to use: the given one, or `latest` when there is neither a
+    /// tag nor a digest.
+    pub fn tag_or_default(&self) -> Option<&str> {
+        match (&self.tag, &self.digest) {
+            (Some(tag), _) => Some(tag),
+            (None, None) => Some(DEFAULT_TAG),
+            (None, Some(_)) => None,
+        }
+    }
+
+    /// The repository as `docker` prints it: no default registry, and no
+    /// `library/` for official images.
+    pub fn familiar_name(&self) -> String {
+        if self.registry == DEFAULT_REGISTRY {
+            self.repository
+                .strip_prefix(OFFICIAL_NAMESPACE)
+                .unwrap_or(&self.repository)
+                .to_string()
+        } else {
+            format!("{}/{}", self.registry, self.repository)
+        }
+    }
+
+    /// `name:tag`, the key images are tagged under, or `None` for a
+    /// reference pinned only by digest.
+    pub fn tag_key(&self) -> Option<String> {
+        self.tag_or_default()
+            .map(|tag| format!("{}:{}", self.familiar_name(), tag))
+    }
+}
+
+impl FromStr for Reference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (rest, digest) = match s.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest.parse()?)),
+            None => (s, None),
+        };
+        let (name, tag) = match rest.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => {
+                if !valid_tag(tag) {
+                    bail!("Invalid tag {:?} in image reference {:?}", tag, s);
+                }
+                (name, Some(tag.to_string()))
+            }
+            _ => (rest, None),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, path))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first.to_string(), path.to_string())
+            }
+            Some(_) => (DEFAULT_REGISTRY.to_string(), name.to_string()),
+            None => (
+                DEFAULT_REGISTRY.to_string(),
+                format!("{}{}", OFFICIAL_NAMESPACE, name),
+            ),
+        };
+        if !repository.split('/').all(valid_component) {
+            bail!("Invalid image reference {:?}", s);
+        }
+
+        Ok(Reference {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.familiar_name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Reference {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_docker_hub_references() {
+        let alpine = parse("alpine");
+        assert_eq!(alpine.registry, "docker.io");
+        assert_eq!(alpine.repository, "library/alpine");
+        assert_eq!(alpine.tag, None);
+        assert_eq!(alpine.tag_key().as_deref(), Some("alpine:latest"));
+
+        let loki = parse("grafana/loki:2.9");
+        assert_eq!(loki.repository, "grafana/loki");
+        assert_eq!(loki.tag_key().as_deref(), Some("grafana/loki:2.9"));
+
+        let full = parse("docker.io/library/alpine:3.19");
+        assert_eq!(full, parse("alpine:3.19"));
+        assert_eq!(full.to_string(), "alpine:3.19");
+    }
+
+    #[test]
+    fn test_parse_registry_references() {
+        let local = parse("localhost:5000/team/app:v1");
+        assert_eq!(local.registry, "localhost:5000");
+        assert_eq!(local.repository, "team/app");
+        assert_eq!(local.tag.as_deref(), Some("v1"));
+        assert_eq!(local.to_string(), "localhost:5000/team/app:v1");
+
+        let ghcr = parse("ghcr.io/org/tool");
+        assert_eq!(ghcr.registry, "ghcr.io");
+        assert_eq!(ghcr.repository, "org/tool");
+
+        let no_tag = parse("localhost:5000/app");
+        assert_eq!(no_tag.registry, "localhost:5000");
+        assert_eq!(no_tag.tag, None);
+    }
+
+    #[test]
+    fn test_parse_digest_references() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let pinned = parse(&format!("alpine@{}", digest));
+        assert_eq!(pinned.digest, Some(digest.parse().unwrap()));
+        assert_eq!(pinned.tag_key(), None);
+        assert_eq!(pinned.to_string(), format!("alpine@{}", digest));
+
+        let both = parse(&format!("alpine:3.19@{}", digest));
+        assert_eq!(both.tag_key().as_deref(), Some("alpine:3.19"));
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_references() {
+        for bad in [
+            "",
+            "Alpine",
+            "alpine:",
+            "alpine:-bad",
+            "alpine@sha256:abc",
+            "/alpine",
+            "a//b",
+            "app_:1",
+        ] {
+            assert!(bad.parse::<Reference>().is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,673 @@
+use super::oci::{
+    Descriptor, Digest, DigestReader, MEDIA_TYPE_DOCKER_MANIFEST, MEDIA_TYPE_DOCKER_MANIFEST_LIST,
+    MEDIA_TYPE_INDEX, MEDIA_TYPE_MANIFEST, verify,
+};
+use super::reference::{DEFAULT_REGISTRY, Reference};
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Where Docker Hub's registry API is served.
+const DOCKER_HUB_HOST: &str = "registry-1.docker.io";
+/// Manifest types `pull` understands, most preferred first.
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+     application/vnd.oci.image.manifest.v1+json, \
+     application/vnd.docker.distribution.manifest.list.v2+json, \
+     application/vnd.docker.distribution.manifest.v2+json";
+/// Indexes and manifests are small; anything bigger is not one.
+const MAX_MANIFEST_SIZE: u64 = 4 << 20;
+/// Attempts per blob, each resuming where the previous one stopped.
+const MAX_ATTEMPTS: usize = 3;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `--creds USER:PASSWORD` for registries that require a login.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl FromStr for Credentials {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Credentials {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => bail!("Invalid credentials: expected USER:PASSWORD"),
+        }
+    }
+}
+
+impl Credentials {
+    fn basic(&self) -> String {
+        let pair = format!("{}:{}", self.username, self.password);
+        format!("Basic {}", STANDARD.encode(pair))
+    }
+}
+
+/// A `WWW-Authenticate` challenge: the scheme and its parameters.
+#[derive(Debug, PartialEq, Eq)]
+struct Challenge {
+    scheme: String,
+    params: HashMap<String, String>,
+}
+
+/// Parses `Bearer realm="...",service="...",scope="..."`. Quoted values may
+/// contain commas, as scopes with several actions do.
+fn parse_challenge(header: &str) -> Option<Challenge> {
+    let (scheme, rest) = header.trim().split_once(' ').unwrap_or((header.trim(), ""));
+    if scheme.is_empty() {
+        return None;
+    }
+
+    let mut params = HashMap::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        while chars.next_if(|c| *c == ',' || c.is_whitespace()).is_some() {}
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=')).collect();
+        if key.is_empty() {
+            break;
+        }
+        chars.next()?;
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => value.push(chars.next()?),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value.extend(std::iter::from_fn(|| chars.next_if(|c| *c != ',')));
+        }
+        params.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    Some(Challenge {
+        scheme: scheme.to_ascii_lowercase(),
+        params,
+    })
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    errors: Vec<RegistryError>,
+}
+
+#[derive(Deserialize)]
+struct RegistryError {
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+/// Describes a failed response, using the registry's error list if it sent
+/// one.
+fn status_error(response: ureq::Response) -> anyhow::Error {
+    let status = format!("{} {}", response.status(), response.status_text());
+    let mut body = String::new();
+    let _ = response
+        .into_reader()
+        .take(MAX_MANIFEST_SIZE)
+        .read_to_string(&mut body);
+    match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(errors) if !errors.errors.is_empty() => {
+            let errors: Vec<_> = errors
+                .errors
+                .iter()
+                .map(|e| format!("{}: {}", e.code, e.message))
+                .collect();
+            anyhow::anyhow!("Registry returned {} ({})", status, errors.join("; "))
+        }
+        _ => anyhow::anyhow!("Registry returned {}", status),
+    }
+}
+
+/// Whether `registry` is on this machine, where plain HTTP is used as
+/// Docker does for local registries.
+fn is_local(registry: &str) -> bool {
+    let host = match registry.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => registry,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// A manifest or index as fetched, with the digest it was verified against.
+#[derive(Debug)]
+pub struct Fetched {
+    pub bytes: Vec<u8>,
+    pub media_type: String,
+    pub digest: Digest,
+}
+
+impl Fetched {
+    pub fn is_index(&self) -> bool {
+        matches!(
+            self.media_type.as_str(),
+            MEDIA_TYPE_INDEX | MEDIA_TYPE_DOCKER_MANIFEST_LIST
+        )
+    }
+
+    pub fn is_manifest(&self) -> bool {
+        matches!(
+            self.media_type.as_str(),
+            MEDIA_TYPE_MANIFEST | MEDIA_TYPE_DOCKER_MANIFEST
+        )
+    }
+}
+
+/// A client for one repository of an OCI distribution registry. Auth
+/// challenges are answered as they come, and the token is reused for the
+/// rest of the pull.
+pub struct Client {
+    agent: ureq::Agent,
+    base: String,
+    repository: String,
+    credentials: Option<Credentials>,
+    authorization: Option<String>,
+}
+
+impl Client {
+    /// Connects over HTTPS, or plain HTTP for registries on this machine
+    /// and when `insecure` is set.
+    pub fn new(reference: &Reference, credentials: Option<Credentials>, insecure: bool) -> Self {
+        let host = if reference.registry == DEFAULT_REGISTRY {
+            DOCKER_HUB_HOST
+        } else {
+            &reference.registry
+        };
+        let scheme = if insecure || is_local(host) {
+            "http"
+        } else {
+            "https"
+        };
+        Client {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(READ_TIMEOUT)
+                .redirect_auth_headers(ureq::RedirectAuthHeaders::SameHost)
+                .build(),
+            base: format!("{}://{}", scheme, host),
+            repository: reference.repository.clone(),
+            credentials,
+            authorization: None,
+        }
+    }
+
+    /// Sends a GET for `path` under the repository, answering one auth
+    /// challenge if the registry asks for it.
+    fn get(&mut self, path: &str, headers: &[(&str, &str)]) -> Result<ureq::Response> {
+        let url = format!("{}/v2/{}/{}", self.base, self.repository, path);
+        let mut challenged = false;
+        loop {
+            let mut request = self.agent.get(&url);
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+            if let Some(authorization) = &self.authorization {
+                request = request.set("Authorization", authorization);
+            }
+            match request.call() {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(401, response)) if !challenged => {
+                    let challenge = response
+                        .header("WWW-Authenticate")
+                        .and_then(parse_challenge)
+                        .with_context(|| {
+                            format!("Registry refused {} without an auth challenge", url)
+                        })?;
+                    self.authenticate(&challenge)?;
+                    challenged = true;
+                }
+                Err(ureq::Error::Status(_, response)) => return Err(status_error(response)),
+                Err(e) => return Err(e).with_context(|| format!("Failed to fetch {}", url)),
+            }
+        }
+    }
+
+    fn authenticate(&mut self, challenge: &Challenge) -> Result<()> {
+        match challenge.scheme.as_str() {
+            "basic" => {
+                let credentials = self
+                    .credentials
+                    .as_ref()
+                    .context("Registry requires a login; pass --creds USER:PASSWORD")?;
+                self.authorization = Some(credentials.basic());
+            }
+            "bearer" => {
+                let realm = challenge
+                    .params
+                    .get("realm")
+                    .context("Bearer challenge has no realm")?;
+                let scope = challenge
+                    .params
+                    .get("scope")
+                    .cloned()
+                    .unwrap_or_else(|| format!("repository:{}:pull", self.repository));
+                let mut request = self.agent.get(realm).query("scope", &scope);
+                if let Some(service) = challenge.params.get("service") {
+                    request = request.query("service", service);
+                }
+                if let Some(credentials) = &self.credentials {
+                    request = request.set("Authorization", &credentials.basic());
+                }
+                let response = match request.call() {
+                    Ok(response) => response,
+                    Err(ureq::Error::Status(_, response)) => {
+                        return Err(status_error(response)).context("Failed to get a token");
+                    }
+                    Err(e) => return Err(e).with_context(|| format!("Failed to reach {}", realm)),
+                };
+                let token: TokenResponse =
+                    serde_json::from_reader(response.into_reader().take(MAX_MANIFEST_SIZE))
+                        .context("Failed to parse token response")?;
+                let token = token
+                    .token
+                    .or(token.access_token)
+                    .context("Token response has no token")?;
+                self.authorization = Some(format!("Bearer {}", token));
+            }
+            scheme => bail!("Unsupported auth scheme {:?}", scheme),
+        }
+        Ok(())
+    }
+
+    /// Fetches the manifest or index a tag or digest points at. Content is
+    /// checked against the requested digest, or the one the registry
+    /// reports for a tag.
+    pub fn manifest(&mut self, reference: &str) -> Result<Fetched> {
+        let response = self.get(
+            &format!("manifests/{}", reference),
+            &[("Accept", MANIFEST_ACCEPT)],
+        )?;
+        let media_type = response.content_type().to_string();
+        let reported = response.header("Docker-Content-Digest").map(str::to_string);
+        let mut bytes = Vec::new();
+        response
+            .into_reader()
+            .take(MAX_MANIFEST_SIZE + 1)
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to read manifest {}", reference))?;
+        if bytes.len() as u64 > MAX_MANIFEST_SIZE {
+            bail!("Manifest {} is too large", reference);
+        }
+
+        let digest = Digest::of(&bytes);
+        if let Ok(expected) = reference.parse::<Digest>() {
+            verify("manifest", &expected, &digest)?;
+        } else if let Some(expected) = reported {
+            verify("manifest", &expected.parse()?, &digest)?;
+        }
+
+        // Some registries send a generic content type; the manifest names
+        // its own.
+        let media_type =
+            match serde_json::from_slice::<serde_json::Value>(&bytes)?["mediaType"].as_str() {
+                Some(declared) => declared.to_string(),
+                None => media_type,
+            };
+        Ok(Fetched {
+            bytes,
+            media_type,
+            digest,
+        })
+    }
+
+    /// Downloads a blob to `dest`, verified against its descriptor. Data is
+    /// kept in `<dest>.partial` while downloading, so an interrupted pull
+    /// picks up where it stopped with a range request.
+    pub fn fetch_blob(&mut self, descriptor: &Descriptor, dest: &Path) -> Result<()> {
+        let partial = dest.with_extension("partial");
+        let mut attempt = 1;
+        while let Err(e) = self.download(descriptor, &partial) {
+            if attempt == MAX_ATTEMPTS {
+                return Err(e);
+            }
+            eprintln!("{}: {:#}; retrying", descriptor.digest.short(), e);
+            attempt += 1;
+        }
+
+        let mut reader = DigestReader::new(File::open(&partial)?);
+        io::copy(&mut reader, &mut io::sink())?;
+        let (digest, _) = reader.finish()?;
+        if let Err(e) = verify("blob", &descriptor.digest, &digest) {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, dest)
+            .with_context(|| format!("Failed to store blob {}", descriptor.digest))
+    }
+
+    /// Appends the rest of the blob to `partial`.
+    fn download(&mut self, descriptor: &Descriptor, partial: &Path) -> Result<()> {
+        let mut offset = fs::metadata(partial).map_or(0, |m| m.len());
+        if offset > descriptor.size {
+            offset = 0;
+        }
+        if offset == descriptor.size && offset > 0 {
+            return Ok(());
+        }
+
+        let range = format!("bytes={}-", offset);
+        let headers: &[(&str, &str)] = if offset > 0 {
+            &[("Range", &range)]
+        } else {
+            &[]
+        };
+        let response = self.get(&format!("blobs/{}", descriptor.digest), headers)?;
+        // A registry that ignores the range sends the whole blob again.
+        let resumed = offset > 0 && response.status() == 206;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resumed)
+            .truncate(!resumed)
+            .open(partial)
+            .with_context(|| format!("Failed to open {:?}", partial))?;
+        let limit = descriptor.size - if resumed { offset } else { 0 };
+        io::copy(&mut response.into_reader().take(limit), &mut file)
+            .with_context(|| format!("Download of {} interrupted", descriptor.digest))?;
+
+        let size = file.metadata()?.len();
+        if size < descriptor.size {
+            bail!(
+                "Download of {} stopped at {} of {} bytes",
+                descriptor.digest,
+                size,
+                descriptor.size
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const TOKEN: &str = "test-token";
+
+    /// A registry serving fixed content on 127.0.0.1, behind a bearer token.
+    /// Blobs listed in `flaky` are cut off halfway the first time they are
+    /// requested.
+    #[derive(Default)]
+    pub(crate) struct FakeRegistry {
+        /// Path under `/v2/` to content type and body.
+        pub(crate) content: HashMap<String, (String, Vec<u8>)>,
+        pub(crate) flaky: Vec<String>,
+        /// Request lines and `Range` headers received, in order.
+        pub(crate) requests: Vec<String>,
+    }
+
+    impl FakeRegistry {
+        /// Serves the registry in a background thread and returns its
+        /// `host:port`.
+        pub(crate) fn serve(registry: Arc<Mutex<FakeRegistry>>) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let realm = format!("http://{}/token", addr);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let _ = handle(stream.unwrap(), &registry, &realm);
+                }
+            });
+            addr
+        }
+    }
+
+    fn respond(
+        stream: &mut TcpStream,
+        status: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+        length: usize,
+    ) -> io::Result<()> {
+        write!(stream, "HTTP/1.1 {}\r\nConnection: close\r\n", status)?;
+        write!(stream, "Content-Length: {}\r\n", length)?;
+        for (name, value) in headers {
+            write!(stream, "{}: {}\r\n", name, value)?;
+        }
+        stream.write_all(b"\r\n")?;
+        stream.write_all(body)
+    }
+
+    fn handle(
+        mut stream: TcpStream,
+        registry: &Mutex<FakeRegistry>,
+        realm: &str,
+    ) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+        let mut headers = HashMap::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            match header.trim_end().split_once(": ") {
+                Some((name, value)) => {
+                    headers.insert(name.to_ascii_lowercase(), value.to_string());
+                }
+                None => break,
+            }
+        }
+
+        let mut registry = registry.lock().unwrap();
+        registry.requests.push(match headers.get("range") {
+            Some(range) => format!("{} {}", path, range),
+            None => path.clone(),
+        });
+
+        if path.starts_with("/token?") {
+            let body = format!(r#"{{"token": "{}"}}"#, TOKEN);
+            return respond(&mut stream, "200 OK", &[], body.as_bytes(), body.len());
+        }
+        if headers.get("authorization").map(String::as_str) != Some(&format!("Bearer {}", TOKEN)) {
+            let challenge = format!(
+                r#"Bearer realm="{}",service="fake",scope="repository:test/app:pull""#,
+                realm
+            );
+            return respond(
+                &mut stream,
+                "401 Unauthorized",
+                &[("WWW-Authenticate", challenge)],
+                b"",
+                0,
+            );
+        }
+
+        let key = path.trim_start_matches("/v2/").to_string();
+        let Some((content_type, body)) = registry.content.get(&key).cloned() else {
+            let body = br#"{"errors": [{"code": "MANIFEST_UNKNOWN", "message": "unknown"}]}"#;
+            return respond(&mut stream, "404 Not Found", &[], body, body.len());
+        };
+        let mut headers_out = vec![("Content-Type", content_type)];
+        if key.contains("/manifests/") {
+            headers_out.push(("Docker-Content-Digest", Digest::of(&body).to_string()));
+        }
+
+        let start = headers
+            .get("range")
+            .and_then(|r| r.strip_prefix("bytes="))
+            .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+        if let Some(start) = start {
+            headers_out.push((
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, body.len() - 1, body.len()),
+            ));
+            let rest = &body[start..];
+            return respond(
+                &mut stream,
+                "206 Partial Content",
+                &headers_out,
+                rest,
+                rest.len(),
+            );
+        }
+        if let Some(i) = registry.flaky.iter().position(|p| *p == key) {
+            registry.flaky.remove(i);
+            return respond(
+                &mut stream,
+                "200 OK",
+                &headers_out,
+                &body[..body.len() / 2],
+                body.len(),
+            );
+        }
+        respond(&mut stream, "200 OK", &headers_out, &body, body.len())
+    }
+
+    fn descriptor(bytes: &[u8]) -> Descriptor {
+        Descriptor {
+            media_type: "application/octet-stream".to_string(),
+            digest: Digest::of(bytes),
+            size: bytes.len() as u64,
+            platform: None,
+            annotations: HashMap::new(),
+        }
+    }
+
+    fn client(addr: &str) -> Client {
+        let reference: Reference = format!("{}/test/app:1", addr).parse().unwrap();
+        Client::new(&reference, None, false)
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let challenge = parse_challenge(
+            r#"Bearer realm="https://auth.example.com/token",service="registry",scope="repository:a/b:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(challenge.scheme, "bearer");
+        assert_eq!(challenge.params["realm"], "https://auth.example.com/token");
+        assert_eq!(challenge.params["scope"], "repository:a/b:pull,push");
+
+        let basic = parse_challenge(r#"Basic realm="Registry Realm""#).unwrap();
+        assert_eq!(basic.scheme, "basic");
+        assert_eq!(basic.params["realm"], "Registry Realm");
+        assert_eq!(parse_challenge(r#"Bearer realm="unterminated"#), None);
+    }
+
+    #[test]
+    fn test_parse_credentials() {
+        let creds: Credentials = "user:pa:ss".parse().unwrap();
+        assert_eq!(creds.username, "user");
+        assert_eq!(creds.password, "pa:ss");
+        assert_eq!(creds.basic(), "Basic dXNlcjpwYTpzcw==");
+        assert!("user".parse::<Credentials>().is_err());
+        assert!(":pass".parse::<Credentials>().is_err());
+    }
+
+    #[test]
+    fn test_local_registries_use_http() {
+        assert!(is_local("localhost:5000"));
+        assert!(is_local("127.0.0.1:5000"));
+        assert!(is_local("[::1]:5000"));
+        assert!(!is_local("ghcr.io"));
+        assert!(!is_local("registry.local:5000"));
+
+        let hub = Client::new(&"alpine".parse().unwrap(), None, false);
+        assert_eq!(hub.base, "https://registry-1.docker.io");
+        assert_eq!(hub.repository, "library/alpine");
+        let insecure = Client::new(&"registry.lan/app".parse().unwrap(), None, true);
+        assert_eq!(insecure.base, "http://registry.lan");
+    }
+
+    #[test]
+    fn test_fetch_manifest_with_token() {
+        let manifest =
+            br#"{"schemaVersion": 2, "mediaType": "application/vnd.oci.image.manifest.v1+json"}"#;
+        let registry = Arc::new(Mutex::new(FakeRegistry::default()));
+        registry.lock().unwrap().content.insert(
+            "test/app/manifests/1".to_string(),
+            ("application/json".to_string(), manifest.to_vec()),
+        );
+        let addr = FakeRegistry::serve(registry.clone());
+
+        let mut client = client(&addr);
+        let fetched = client.manifest("1").unwrap();
+        assert_eq!(fetched.bytes, manifest);
+        assert_eq!(fetched.digest, Digest::of(manifest));
+        assert!(fetched.is_manifest());
+
+        let err = client.manifest("2").unwrap_err();
+        assert!(err.to_string().contains("MANIFEST_UNKNOWN"), "{:#}", err);
+        // The token is fetched once and reused.
+        let requests = registry.lock().unwrap().requests.clone();
+        assert_eq!(
+            requests.iter().filter(|r| r.starts_with("/token")).count(),
+            1
+        );
+        assert!(requests[1].contains("scope=repository%3Atest%2Fapp%3Apull"));
+    }
+
+    #[test]
+    fn test_fetch_blob_resumes_interrupted_download() {
+        let blob: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let descriptor = descriptor(&blob);
+        let path = format!("test/app/blobs/{}", descriptor.digest);
+        let registry = Arc::new(Mutex::new(FakeRegistry::default()));
+        {
+            let mut registry = registry.lock().unwrap();
+            registry.content.insert(
+                path.clone(),
+                ("application/octet-stream".to_string(), blob.clone()),
+            );
+            registry.flaky.push(path.clone());
+        }
+        let addr = FakeRegistry::serve(registry.clone());
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let dest = dir.path().join(descriptor.digest.hex());
+        client(&addr).fetch_blob(&descriptor, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), blob);
+        assert!(!dest.with_extension("partial").exists());
+        let requests = registry.lock().unwrap().requests.clone();
+        assert!(
+            requests
+                .iter()
+                .any(|r| r.ends_with(&format!("bytes={}-", blob.len() / 2))),
+            "{:?}",
+            requests
+        );
+
+        // Content that does not match the digest is thrown away.
+        let wrong = Descriptor {
+            digest: Digest::of(b"other"),
+            ..descriptor
+        };
+        registry.lock().unwrap().content.insert(
+            format!("test/app/blobs/{}", wrong.digest),
+            ("application/octet-stream".to_string(), blob),
+        );
+        let dest = dir.path().join(wrong.digest.hex());
+        assert!(client(&addr).fetch_blob(&wrong, &dest).is_err());
+        assert!(!dest.exists());
+        assert!(!dest.with_extension("partial").exists());
+    }
+}
@@ -0,0 +1,335 @@
+use anyhow::{Context, Result, bail};
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: u32 = 1;
+
+// Filesystem access rights, from `linux/landlock.h`.
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+/// Removing and creating entries of every kind (bits 4 to 12).
+const ACCESS_MODIFY_DIR: u64 = 0x1ff << 4;
+/// ABI 2: linking or renaming a file into another directory.
+const ACCESS_REFER: u64 = 1 << 13;
+/// ABI 3: truncating a file.
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+/// ABI 5: ioctl on character and block devices.
+const ACCESS_IOCTL_DEV: u64 = 1 << 15;
+
+const READ_ONLY: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+/// Rights that make sense on a file rather than a directory; the kernel
+/// rejects rules granting anything else on one.
+const FILE_RIGHTS: u64 =
+    ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_TRUNCATE | ACCESS_IOCTL_DEV;
+
+/// Paths the container may access once Landlock is enforced, as absolute
+/// paths inside the container. Everything else on the filesystem is denied,
+/// including the command itself and its libraries unless listed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LandlockRules {
+    pub read_only: Vec<PathBuf>,
+    pub read_write: Vec<PathBuf>,
+}
+
+impl LandlockRules {
+    pub fn is_empty(&self) -> bool {
+        self.read_only.is_empty() && self.read_write.is_empty()
+    }
+}
+
+/// Parses a `--landlock-ro`/`--landlock-rw` path, which must be absolute.
+pub fn parse_rule_path(s: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(s);
+    if !path.is_absolute() {
+        bail!("Landlock path {:?} must be absolute", s);
+    }
+    Ok(path)
+}
+
+/// What `apply_landlock` managed to enforce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Enforcement {
+    /// The kernel was built without Landlock or it is disabled at boot.
+    Unsupported,
+    Enforced {
+        abi: u32,
+        read_only: usize,
+        read_write: usize,
+        /// Rights newer than the running ABI, which stay unrestricted.
+        missing: Vec<&'static str>,
+    },
+}
+
+impl fmt::Display for Enforcement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Enforcement::Unsupported => {
+                write!(
+                    f,
+                    "Landlock is not available; filesystem rules not enforced"
+                )
+            }
+            Enforcement::Enforced {
+                abi,
+                read_only,
+                read_write,
+                missing,
+            } => {
+                write!(
+                    f,
+                    "Landlock ABI v{} enforced: {} read-only and {} read-write path(s)",
+                    abi, read_only, read_write
+                )?;
+                if !missing.is_empty() {
+                    write!(f, " (kernel too old to restrict {})", missing.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The Landlock ABI version of the running kernel, or `None` without
+/// Landlock.
+pub fn abi_version() -> Option<u32> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<u8>(),
+            0usize,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    (ret > 0).then_some(ret as u32)
+}
+
+/// Filesystem rights a kernel with this ABI can restrict, and the names of
+/// the ones it cannot.
+fn handled_access(abi: u32) -> (u64, Vec<&'static str>) {
+    let mut handled = READ_ONLY | ACCESS_WRITE_FILE | ACCESS_MODIFY_DIR;
+    let mut missing = Vec::new();
+    for (min_abi, right, name) in [
+        (2, ACCESS_REFER, "refer"),
+        (3, ACCESS_TRUNCATE, "truncate"),
+        (5, ACCESS_IOCTL_DEV, "ioctl_dev"),
+    ] {
+        if abi >= min_abi {
+            handled |= right;
+        } else {
+            missing.push(name);
+        }
+    }
+    (handled, missing)
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid Landlock path {:?}", path))?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to open Landlock path {:?}", path));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to stat Landlock path {:?}", path));
+    }
+    let access = if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+        access
+    } else {
+        access & FILE_RIGHTS
+    };
+
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: fd.as_raw_fd(),
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0u32,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to add Landlock rule for {:?}", path));
+    }
+    Ok(())
+}
+
+/// Restricts the calling process to `rules`. Paths are resolved in the
+/// current mount namespace, so this must run after pivoting into the
+/// container's root. Needs `no_new_privs` or CAP_SYS_ADMIN in the user
+/// namespace. Kernels without Landlock are reported, not treated as errors.
+pub fn apply_landlock(rules: &LandlockRules) -> Result<Enforcement> {
+    let Some(abi) = abi_version() else {
+        return Ok(Enforcement::Unsupported);
+    };
+    let (handled, missing) = handled_access(abi);
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0u32,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to create Landlock ruleset");
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    for path in &rules.read_only {
+        add_rule(&ruleset, path, READ_ONLY)?;
+    }
+    for path in &rules.read_write {
+        add_rule(&ruleset, path, handled)?;
+    }
+
+    let ret = unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to enforce Landlock ruleset");
+    }
+
+    Ok(Enforcement::Enforced {
+        abi,
+        read_only: rules.read_only.len(),
+        read_write: rules.read_write.len(),
+        missing,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork};
+    use std::fs;
+
+    #[test]
+    fn test_parse_rule_path() {
+        assert_eq!(parse_rule_path("/usr").unwrap(), PathBuf::from("/usr"));
+        assert!(parse_rule_path("usr/lib").is_err());
+    }
+
+    #[test]
+    fn test_handled_access_by_abi() {
+        let (v1, missing) = handled_access(1);
+        assert_eq!(v1, 0x1fff);
+        assert_eq!(missing, ["refer", "truncate", "ioctl_dev"]);
+
+        let (v3, missing) = handled_access(3);
+        assert_eq!(v3, 0x7fff);
+        assert_eq!(missing, ["ioctl_dev"]);
+
+        let (v7, missing) = handled_access(7);
+        assert_eq!(v7, 0xffff);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn test_enforcement_report() {
+        let report = Enforcement::Enforced {
+            abi: 2,
+            read_only: 3,
+            read_write: 1,
+            missing: vec!["truncate", "ioctl_dev"],
+        };
+        assert_eq!(
+            report.to_string(),
+            "Landlock ABI v2 enforced: 3 read-only and 1 read-write path(s) \
+             (kernel too old to restrict truncate, ioctl_dev)"
+        );
+        assert!(
+            Enforcement::Unsupported
+                .to_string()
+                .contains("not enforced")
+        );
+    }
+
+    #[test]
+    fn test_apply_landlock_in_child() {
+        if abi_version().is_none() {
+            eprintln!("skipping: Landlock is not available");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let (ro, rw, hidden) = (
+            dir.path().join("ro"),
+            dir.path().join("rw"),
+            dir.path().join("hidden"),
+        );
+        for d in [&ro, &rw, &hidden] {
+            fs::create_dir(d).unwrap();
+            fs::write(d.join("file"), "data").unwrap();
+        }
+        let rules = LandlockRules {
+            read_only: vec![ro.clone()],
+            read_write: vec![rw.clone()],
+        };
+        let path = |p: &Path| CString::new(p.as_os_str().as_bytes()).unwrap();
+        let (ro_file, rw_new, hidden_file) = (
+            path(&ro.join("file")),
+            path(&rw.join("new")),
+            path(&hidden.join("file")),
+        );
+        let ro_new = path(&ro.join("new"));
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let open = |p: &CString, flags| {
+                    let fd = unsafe { libc::open(p.as_ptr(), flags | libc::O_CLOEXEC, 0o644) };
+                    if fd >= 0 {
+                        unsafe { libc::close(fd) };
+                    }
+                    fd >= 0
+                };
+                let code = if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0
+                    || apply_landlock(&rules).is_err()
+                {
+                    2
+                } else if open(&ro_file, libc::O_RDONLY)
+                    && !open(&ro_new, libc::O_WRONLY | libc::O_CREAT)
+                    && open(&rw_new, libc::O_WRONLY | libc::O_CREAT)
+                    && !open(&hidden_file, libc::O_RDONLY)
+                {
+                    0
+                } else {
+                    1
+                };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
@@ -1,10 +1,29 @@
+pub mod capabilities;
 pub mod cgroup;
+pub mod config;
+pub mod devices;
+pub mod idmap;
+pub mod image;
+pub mod landlock;
+pub mod lsm;
+pub mod masking;
+pub mod mounts;
 pub mod namespace;
+pub mod overlay;
 pub mod pivot_root;
+pub mod process;
+pub mod psi;
+pub mod resources;
 pub mod runtime;
+pub mod seccomp;
+pub mod security;
+pub mod state;
+pub mod stats;
+pub mod ulimit;
 
 // Re-export main types and functions for easier testing
 pub use cgroup::setup_cgroup;
+pub use config::ContainerConfig;
 pub use namespace::setup_user_namespace;
 pub use pivot_root::setup_rootfs;
 pub use runtime::run_container;
@@ -0,0 +1,293 @@
+use anyhow::{Context, Result, bail};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Set to `Y` when AppArmor is enabled on the host.
+const APPARMOR_ENABLED: &str = "/sys/module/apparmor/parameters/enabled";
+/// Profiles loaded into the kernel, one `name (mode)` per line.
+const APPARMOR_PROFILES: &str = "/sys/kernel/security/apparmor/profiles";
+/// Present when selinuxfs is mounted, which it is whenever SELinux is on.
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+
+/// Label parts used for any part `--security-opt label=...` leaves out.
+const DEFAULT_USER: &str = "system_u";
+const DEFAULT_ROLE: &str = "system_r";
+const DEFAULT_TYPE: &str = "container_t";
+const DEFAULT_LEVEL: &str = "s0";
+const MOUNT_ROLE: &str = "object_r";
+const MOUNT_TYPE: &str = "container_file_t";
+/// MCS categories handed out to containers, as in Docker.
+const CATEGORIES: u32 = 1024;
+
+/// Paths whose relabelling would break the host, as Docker refuses them.
+const PROTECTED_PATHS: &[&str] = &[
+    "/", "/bin", "/boot", "/dev", "/etc", "/home", "/lib", "/lib64", "/opt", "/proc", "/root",
+    "/run", "/sbin", "/srv", "/sys", "/tmp", "/usr", "/var",
+];
+
+/// The SELinux context a container process runs in. Files it is given get
+/// the matching `container_file_t` mount context at the same level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelinuxLabel {
+    pub user: String,
+    pub role: String,
+    pub type_: String,
+    pub level: String,
+}
+
+impl Default for SelinuxLabel {
+    fn default() -> Self {
+        SelinuxLabel {
+            user: DEFAULT_USER.to_string(),
+            role: DEFAULT_ROLE.to_string(),
+            type_: DEFAULT_TYPE.to_string(),
+            level: DEFAULT_LEVEL.to_string(),
+        }
+    }
+}
+
+impl SelinuxLabel {
+    pub fn process_label(&self) -> String {
+        format!("{}:{}:{}:{}", self.user, self.role, self.type_, self.level)
+    }
+
+    pub fn mount_label(&self) -> String {
+        format!("{}:{}:{}:{}", self.user, MOUNT_ROLE, MOUNT_TYPE, self.level)
+    }
+
+    /// The mount context without MCS categories, for content shared between
+    /// containers (the `z` volume option).
+    pub fn shared_mount_label(&self) -> String {
+        let sensitivity = self.level.split(':').next().unwrap_or(DEFAULT_LEVEL);
+        format!(
+            "{}:{}:{}:{}",
+            self.user, MOUNT_ROLE, MOUNT_TYPE, sensitivity
+        )
+    }
+}
+
+fn apparmor_enabled(flag: &Path) -> bool {
+    fs::read_to_string(flag).is_ok_and(|s| s.starts_with('Y'))
+}
+
+/// Whether `profile` appears in the kernel's list of loaded profiles.
+fn profile_loaded(profiles: &str, profile: &str) -> bool {
+    profiles.lines().any(|line| {
+        line.rsplit_once(" (")
+            .map_or(line, |(name, _mode)| name)
+            .trim()
+            == profile
+    })
+}
+
+/// Fails unless AppArmor is active and, when the kernel lets us see the
+/// list, `profile` is loaded. Runs on the host before the container starts.
+pub fn check_apparmor(profile: &str) -> Result<()> {
+    if !apparmor_enabled(Path::new(APPARMOR_ENABLED)) {
+        bail!(
+            "AppArmor is not enabled on this host; cannot apply profile {:?}",
+            profile
+        );
+    }
+    if let Ok(profiles) = fs::read_to_string(APPARMOR_PROFILES)
+        && !profile_loaded(&profiles, profile)
+    {
+        bail!(
+            "AppArmor profile {:?} is not loaded (load it with apparmor_parser)",
+            profile
+        );
+    }
+    Ok(())
+}
+
+/// Picks an `s0:cA,cB` level with a category pair no level in `used` has,
+/// so each container's `Z` volumes are private to it.
+pub fn unique_level(used: &HashSet<String>) -> Result<String> {
+    let mut urandom = fs::File::open("/dev/urandom").context("Failed to open /dev/urandom")?;
+    for _ in 0..1000 {
+        let mut bytes = [0u8; 4];
+        urandom
+            .read_exact(&mut bytes)
+            .context("Failed to read /dev/urandom")?;
+        let a = u16::from_ne_bytes([bytes[0], bytes[1]]) as u32 % CATEGORIES;
+        let b = u16::from_ne_bytes([bytes[2], bytes[3]]) as u32 % CATEGORIES;
+        if a == b {
+            continue;
+        }
+        let level = format!("{}:c{},c{}", DEFAULT_LEVEL, a.min(b), a.max(b));
+        if !used.contains(&level) {
+            return Ok(level);
+        }
+    }
+    bail!("No free SELinux MCS categories for a new container")
+}
+
+/// Whether SELinux is active on the host.
+pub fn selinux_enabled() -> bool {
+    Path::new(SELINUX_ENFORCE).exists()
+}
+
+/// Fails unless SELinux is active on the host.
+pub fn check_selinux() -> Result<()> {
+    if !selinux_enabled() {
+        bail!("SELinux is not enabled on this host; cannot apply a label");
+    }
+    Ok(())
+}
+
+/// Makes the next exec run under AppArmor `profile`. Kernels with LSM
+/// stacking have an AppArmor-specific attribute directory; older ones only
+/// have the shared `/proc/self/attr/exec`.
+pub fn set_apparmor_exec(profile: &str) -> Result<()> {
+    let value = format!("exec {}", profile);
+    let path = if Path::new("/proc/self/attr/apparmor/exec").exists() {
+        "/proc/self/attr/apparmor/exec"
+    } else {
+        "/proc/self/attr/exec"
+    };
+    fs::write(path, value)
+        .with_context(|| format!("Failed to set AppArmor profile {:?} via {}", profile, path))
+}
+
+/// Makes the next exec run in the SELinux context `label`.
+pub fn set_selinux_exec(label: &str) -> Result<()> {
+    fs::write("/proc/self/attr/exec", label)
+        .with_context(|| format!("Failed to set SELinux label {:?}", label))
+}
+
+/// Sets the SELinux context of `path` and, for directories, everything
+/// below it, so a container running under the matching process label can
+/// use a bind-mounted volume. Symlinks are labelled, not followed. System
+/// directories such as `/usr` and the user's home are refused.
+pub fn relabel(path: &Path, label: &str) -> Result<()> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {:?}", path))?;
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    if PROTECTED_PATHS.iter().any(|p| canonical == Path::new(p)) || home == Some(canonical) {
+        bail!("Relabelling {:?} is not allowed", path);
+    }
+    relabel_tree(path, label)
+}
+
+fn relabel_tree(path: &Path, label: &str) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid path {:?}", path))?;
+    let value = CString::new(label).with_context(|| format!("Invalid label {:?}", label))?;
+    let bytes = value.as_bytes_with_nul();
+    let ret = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c"security.selinux".as_ptr(),
+            bytes.as_ptr().cast(),
+            bytes.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to relabel {:?} as {:?}", path, label));
+    }
+
+    let metadata =
+        fs::symlink_metadata(path).with_context(|| format!("Failed to stat {:?}", path))?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path).with_context(|| format!("Failed to read {:?}", path))? {
+            relabel_tree(&entry?.path(), label)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selinux_labels() {
+        let label = SelinuxLabel::default();
+        assert_eq!(label.process_label(), "system_u:system_r:container_t:s0");
+        assert_eq!(label.mount_label(), "system_u:object_r:container_file_t:s0");
+
+        let label = SelinuxLabel {
+            type_: "spc_t".to_string(),
+            level: "s0:c100,c200".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            label.process_label(),
+            "system_u:system_r:spc_t:s0:c100,c200"
+        );
+        assert_eq!(
+            label.mount_label(),
+            "system_u:object_r:container_file_t:s0:c100,c200"
+        );
+        assert_eq!(
+            label.shared_mount_label(),
+            "system_u:object_r:container_file_t:s0"
+        );
+    }
+
+    #[test]
+    fn test_unique_level() {
+        let mut used = HashSet::new();
+        for _ in 0..100 {
+            let level = unique_level(&used).unwrap();
+            let (a, b) = level
+                .strip_prefix("s0:c")
+                .and_then(|cats| cats.split_once(",c"))
+                .unwrap();
+            let (a, b): (u32, u32) = (a.parse().unwrap(), b.parse().unwrap());
+            assert!(a < b && b < CATEGORIES);
+            assert!(used.insert(level));
+        }
+    }
+
+    #[test]
+    fn test_relabel_refuses_system_paths() {
+        for path in ["/", "/usr", "/etc/", "/usr/../home"] {
+            let err =
+                relabel(Path::new(path), "system_u:object_r:container_file_t:s0").unwrap_err();
+            assert!(err.to_string().contains("not allowed"), "{:?}", path);
+        }
+        if let Some(home) = std::env::var_os("HOME") {
+            assert!(relabel(Path::new(&home), "system_u:object_r:container_file_t:s0").is_err());
+        }
+    }
+
+    #[test]
+    fn test_apparmor_enabled_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let flag = dir.path().join("enabled");
+        assert!(!apparmor_enabled(&flag));
+        fs::write(&flag, "Y\n").unwrap();
+        assert!(apparmor_enabled(&flag));
+        fs::write(&flag, "N\n").unwrap();
+        assert!(!apparmor_enabled(&flag));
+    }
+
+    #[test]
+    fn test_profile_loaded() {
+        let profiles = "docker-default (enforce)\n/usr/bin/man (enforce)\nmy profile (complain)\n";
+        assert!(profile_loaded(profiles, "docker-default"));
+        assert!(profile_loaded(profiles, "/usr/bin/man"));
+        assert!(profile_loaded(profiles, "my profile"));
+        assert!(!profile_loaded(profiles, "docker"));
+    }
+
+    #[test]
+    fn test_checks_fail_without_lsm() {
+        if !apparmor_enabled(Path::new(APPARMOR_ENABLED)) {
+            let err = check_apparmor("docker-default").unwrap_err();
+            assert!(err.to_string().contains("AppArmor is not enabled"));
+        }
+        if !Path::new(SELINUX_ENFORCE).exists() {
+            assert!(check_selinux().is_err());
+        }
+    }
+}
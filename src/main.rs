@@ -1,42 +1,579 @@
 use clap::Parser;
-mod cgroup;
-mod namespace;
-mod pivot_root;
-mod runtime;
-use runtime::run_container;
+use docker_clone::capabilities::{CapArg, Capability, resolve_capabilities};
+use docker_clone::cgroup::prune_cgroups;
+use docker_clone::config::ContainerConfig;
+use docker_clone::devices::{Device, DeviceRule};
+use docker_clone::image::oci::Platform;
+use docker_clone::image::reference::Reference;
+use docker_clone::image::registry::{Client, Credentials};
+use docker_clone::image::{ImageStore, format_table};
+use docker_clone::landlock::{LandlockRules, parse_rule_path};
+use docker_clone::lsm::{selinux_enabled, unique_level};
+use docker_clone::masking::unmask;
+use docker_clone::mounts::{Tmpfs, Volume, container_path};
+use docker_clone::pivot_root::Propagation;
+use docker_clone::process::{User, command_line, merge_env, parse_signal};
+use docker_clone::psi::PressureTrigger;
+use docker_clone::resources::{CpuSet, MemorySwap, Resources, ThrottleDevice, parse_size};
+use docker_clone::runtime::{pause_container, run_container, unpause_container};
+use docker_clone::seccomp::Profile;
+use docker_clone::security::{LabelOpt, SecurityOpt};
+use docker_clone::state::{StateStore, default_root};
+use docker_clone::stats::{StatsFormat, show_stats};
+use docker_clone::ulimit::Ulimit;
+use nix::sys::signal::Signal;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
 #[command(name = "container")]
 #[command(about = "A simple container runtime")]
 struct Cli {
+    /// Directory holding container state
+    #[arg(long, global = true)]
+    root: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
 
+// Parsed once at startup, so the size of `Run` doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(clap::Subcommand, Debug)]
 enum Commands {
     Run {
+        /// Mount the container's root filesystem read-only
+        #[arg(long)]
+        read_only: bool,
+        /// Run on a copy-on-write overlay so the rootfs is left untouched
+        #[arg(long)]
+        overlay: bool,
+        /// Stack an extra read-only layer above the rootfs (implies --overlay)
+        #[arg(long = "layer")]
+        layers: Vec<PathBuf>,
+        /// Mount propagation for the container root (private, rprivate, slave, rslave, shared, rshared)
+        #[arg(long, default_value = "rprivate")]
+        rootfs_propagation: Propagation,
+        /// Bind mount a host path into the container (SOURCE:DEST[:ro|rw][,idmap])
+        #[arg(short = 'v', long = "volume")]
+        volumes: Vec<Volume>,
+        /// Mount a tmpfs inside the container (DEST[:OPTIONS])
+        #[arg(long)]
+        tmpfs: Vec<Tmpfs>,
+        /// Hide an additional path from the container
+        #[arg(long = "masked-path")]
+        masked_paths: Vec<String>,
+        /// Make an additional path read-only inside the container
+        #[arg(long = "readonly-path")]
+        readonly_paths: Vec<String>,
+        /// Drop a path from the masked and read-only lists (ALL drops every path)
+        #[arg(long = "unmask")]
+        unmask_paths: Vec<String>,
+        /// Limit read rate from a device (DEVICE:RATE, e.g. /dev/sda:10mb)
+        #[arg(long = "device-read-bps")]
+        device_read_bps: Vec<ThrottleDevice>,
+        /// Limit write rate to a device (DEVICE:RATE, e.g. /dev/sda:10mb)
+        #[arg(long = "device-write-bps")]
+        device_write_bps: Vec<ThrottleDevice>,
+        /// Limit read operations per second from a device (DEVICE:IOPS)
+        #[arg(long = "device-read-iops", value_parser = ThrottleDevice::parse_iops)]
+        device_read_iops: Vec<ThrottleDevice>,
+        /// Limit write operations per second to a device (DEVICE:IOPS)
+        #[arg(long = "device-write-iops", value_parser = ThrottleDevice::parse_iops)]
+        device_write_iops: Vec<ThrottleDevice>,
+        /// Relative block I/O weight (10 to 1000)
+        #[arg(long, value_parser = clap::value_parser!(u16).range(10..=1000))]
+        blkio_weight: Option<u16>,
+        /// CPUs the container may run on (e.g. 0-3,8)
+        #[arg(long)]
+        cpuset_cpus: Option<CpuSet>,
+        /// NUMA memory nodes the container may allocate from (e.g. 0,1)
+        #[arg(long)]
+        cpuset_mems: Option<CpuSet>,
+        /// Relative CPU weight in Docker units (2 to 262144, default 1024)
+        #[arg(long, value_parser = clap::value_parser!(u64).range(2..=262144), conflicts_with = "cpu_weight")]
+        cpu_shares: Option<u64>,
+        /// Relative CPU weight in cgroup v2 units (1 to 10000, default 100)
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..=10000))]
+        cpu_weight: Option<u64>,
+        /// Hard memory limit (e.g. 512m, default 100m)
+        #[arg(short = 'm', long, value_parser = parse_size)]
+        memory: Option<u64>,
+        /// Throttle the container above this much memory (memory.high)
+        #[arg(long, value_parser = parse_size)]
+        memory_high: Option<u64>,
+        /// Memory protected from reclaim under pressure (memory.low)
+        #[arg(long, value_parser = parse_size)]
+        memory_reservation: Option<u64>,
+        /// Memory never reclaimed from the container (memory.min)
+        #[arg(long, value_parser = parse_size)]
+        memory_min: Option<u64>,
+        /// Memory plus swap limit (-1 for unlimited swap)
+        #[arg(long, allow_hyphen_values = true)]
+        memory_swap: Option<MemorySwap>,
+        /// Adjust the container's OOM killer preference (-1000 to 1000)
+        #[arg(long, allow_hyphen_values = true, value_parser = clap::value_parser!(i32).range(-1000..=1000))]
+        oom_score_adj: Option<i32>,
+        /// Log an event when pressure exceeds a threshold (RESOURCE[:full]=STALL/WINDOW, e.g. memory=150ms/1s)
+        #[arg(long = "pressure-trigger")]
+        pressure_triggers: Vec<PressureTrigger>,
+        /// Security options (seccomp=PROFILE.json|unconfined, no-new-privileges=false,
+        /// apparmor=PROFILE, label=user:|role:|type:|level:VALUE or label=disable)
+        #[arg(long = "security-opt")]
+        security_opts: Vec<SecurityOpt>,
+        /// Add a Linux capability to the default set (e.g. NET_ADMIN, or ALL)
+        #[arg(long = "cap-add")]
+        cap_add: Vec<CapArg>,
+        /// Drop a Linux capability from the default set (e.g. MKNOD, or ALL)
+        #[arg(long = "cap-drop")]
+        cap_drop: Vec<CapArg>,
+        /// Keep every capability and device, and skip path masking and seccomp
+        #[arg(long)]
+        privileged: bool,
+        /// Resource limits for the container process (e.g. nofile=1024:4096,nproc=512)
+        #[arg(long = "ulimit", value_delimiter = ',')]
+        ulimits: Vec<Ulimit>,
+        /// Restrict the container with Landlock, allowing read access beneath this path
+        #[arg(long = "landlock-ro", value_parser = parse_rule_path)]
+        landlock_ro: Vec<PathBuf>,
+        /// Restrict the container with Landlock, allowing full access beneath this path
+        #[arg(long = "landlock-rw", value_parser = parse_rule_path)]
+        landlock_rw: Vec<PathBuf>,
+        /// Expose a host device to the container (HOST[:CONTAINER][:PERMISSIONS], e.g. /dev/fuse:rwm)
+        #[arg(long = "device")]
+        devices: Vec<Device>,
+        /// Run this instead of the image's entrypoint (an empty string clears it)
+        #[arg(long)]
+        entrypoint: Option<String>,
+        /// Set an environment variable (KEY=VALUE, or KEY to copy it from the host)
+        #[arg(short = 'e', long = "env")]
+        env: Vec<String>,
+        /// Working directory inside the container, created if missing
+        #[arg(short = 'w', long, value_parser = container_path)]
+        workdir: Option<PathBuf>,
+        /// User to run as (USER[:GROUP], by name or number). Without root, users
+        /// other than root need subordinate ids in /etc/subuid and /etc/subgid
+        #[arg(short = 'u', long)]
+        user: Option<User>,
+        /// Signal sent to the container when the runtime is interrupted (default SIGTERM)
+        #[arg(long, value_parser = parse_signal)]
+        stop_signal: Option<Signal>,
+        /// Root filesystem directory or image reference
         rootfs: String,
-        command: String,
+        /// Command to run; images default to their entrypoint and Cmd
+        command: Option<String>,
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Remove a stopped container and its overlay changes
+    Delete {
+        id: String,
+        /// Keep the overlay upper directory
+        #[arg(long)]
+        keep: bool,
+    },
+    /// Remove cgroups left behind by crashed runs
+    Prune,
+    /// Freeze all processes of a running container
+    Pause { id: String },
+    /// Resume a paused container
+    Unpause { id: String },
+    /// Show resource usage of running containers
+    Stats {
+        /// Containers to show (all running containers when omitted)
+        ids: Vec<String>,
+        /// Print a single sample instead of refreshing
+        #[arg(long)]
+        no_stream: bool,
+        /// Output format (table or json)
+        #[arg(long, default_value = "table")]
+        format: StatsFormat,
+    },
+    /// Download an image from a registry into the image store
+    Pull {
+        /// Image reference, such as alpine:3.19 or localhost:5000/app@sha256:...
+        reference: Reference,
+        /// Platform to pull from multi-platform images (OS/ARCH[/VARIANT]; default: this host)
+        #[arg(long)]
+        platform: Option<Platform>,
+        /// Log in to the registry (USER:PASSWORD)
+        #[arg(long)]
+        creds: Option<Credentials>,
+        /// Use plain HTTP (always used for registries on localhost)
+        #[arg(long)]
+        insecure: bool,
+    },
+    /// Manage images
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ImageCommands {
+    /// Import images from an OCI image layout or a `docker save` archive
+    Load {
+        /// Directory or tarball (optionally gzip-compressed) to import
+        #[arg(short, long)]
+        input: PathBuf,
+    },
+    /// List stored images
+    Ls,
+    /// Remove images, and the layers no other image uses
+    Rm {
+        /// Image names, IDs or ID prefixes
+        #[arg(required = true)]
+        images: Vec<String>,
+        /// Remove images with several tags or used by stopped containers
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Remove untagged images no container uses
+    Prune {
+        /// Remove every image no container uses, tagged or not
+        #[arg(short, long)]
+        all: bool,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
+    let root = cli.root.unwrap_or_else(default_root);
 
     match cli.command {
         Commands::Run {
+            read_only,
+            overlay,
+            layers,
+            rootfs_propagation,
+            volumes,
+            tmpfs,
+            masked_paths,
+            readonly_paths,
+            unmask_paths,
+            device_read_bps,
+            device_write_bps,
+            device_read_iops,
+            device_write_iops,
+            blkio_weight,
+            cpuset_cpus,
+            cpuset_mems,
+            cpu_shares,
+            cpu_weight,
+            memory,
+            memory_high,
+            memory_reservation,
+            memory_min,
+            memory_swap,
+            oom_score_adj,
+            pressure_triggers,
+            security_opts,
+            cap_add,
+            cap_drop,
+            privileged,
+            ulimits,
+            landlock_ro,
+            landlock_rw,
+            devices,
+            entrypoint,
+            env,
+            workdir,
+            user,
+            stop_signal,
             rootfs,
             command,
             args,
         } => {
-            if let Err(e) = run_container(&rootfs, &command, args) {
+            // Anything that is not a directory names a stored image, whose
+            // layers become the overlay's lower dirs beneath any --layer.
+            let (rootfs, layers, image) = if Path::new(&rootfs).is_dir() {
+                (rootfs, layers, None)
+            } else {
+                let store = ImageStore::new(&root);
+                let found = store.resolve(&rootfs).and_then(|image| {
+                    let dirs = store.layer_dirs(&image)?;
+                    let defaults = store.config(&image)?.config.unwrap_or_default();
+                    Ok((dirs, image.manifest, defaults))
+                });
+                match found {
+                    Ok((mut dirs, manifest, defaults)) => {
+                        let base = dirs.remove(0).to_string_lossy().to_string();
+                        dirs.extend(layers);
+                        (base, dirs, Some((manifest, defaults)))
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to find image: {:#}", e);
+                        std::process::exit(1);
+                    }
+                }
+            };
+            let (image_entrypoint, image_cmd) = image
+                .as_ref()
+                .map(|(_, defaults)| (defaults.entrypoint.clone(), defaults.cmd.clone()))
+                .unwrap_or_default();
+            let argv = match command_line(
+                entrypoint,
+                command.into_iter().chain(args).collect(),
+                image_entrypoint,
+                image_cmd,
+            ) {
+                Ok(argv) => argv,
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    std::process::exit(1);
+                }
+            };
+            let mut config = ContainerConfig {
+                root,
+                read_only,
+                overlay: overlay || !layers.is_empty() || image.is_some(),
+                layers,
+                image: image.as_ref().map(|(manifest, _)| manifest.clone()),
+                working_dir: workdir,
+                user,
+                stop_signal,
+                rootfs_propagation,
+                volumes,
+                tmpfs,
+                resources: Resources {
+                    device_read_bps,
+                    device_write_bps,
+                    device_read_iops,
+                    device_write_iops,
+                    blkio_weight,
+                    cpuset_cpus,
+                    cpuset_mems,
+                    cpu_shares,
+                    cpu_weight,
+                    memory,
+                    memory_high,
+                    memory_reservation,
+                    memory_min,
+                    memory_swap,
+                },
+                oom_score_adj,
+                pressure_triggers,
+                capabilities: resolve_capabilities(&cap_add, &cap_drop),
+                ulimits,
+                landlock: LandlockRules {
+                    read_only: landlock_ro,
+                    read_write: landlock_rw,
+                },
+                devices,
+                ..ContainerConfig::new(&rootfs, &argv[0], argv[1..].to_vec())
+            };
+            merge_env(&mut config.env, &env);
+            if let Some((_, defaults)) = &image
+                && let Err(e) = config.apply_image_defaults(defaults)
+            {
+                eprintln!("Invalid image config: {:#}", e);
+                std::process::exit(1);
+            }
+            config.masked_paths.extend(masked_paths);
+            config.readonly_paths.extend(readonly_paths);
+            unmask(&mut config.masked_paths, &unmask_paths);
+            unmask(&mut config.readonly_paths, &unmask_paths);
+            let mut label_disabled = false;
+            let mut level_set = false;
+            for opt in security_opts {
+                match opt {
+                    SecurityOpt::SeccompUnconfined => config.seccomp = None,
+                    SecurityOpt::NoNewPrivileges(enabled) => config.no_new_privileges = enabled,
+                    SecurityOpt::SeccompProfile(path) => match Profile::load(&path) {
+                        Ok(profile) => config.seccomp = Some(profile),
+                        Err(e) => {
+                            eprintln!("{:#}", e);
+                            std::process::exit(1);
+                        }
+                    },
+                    SecurityOpt::AppArmor(profile) => config.apparmor_profile = Some(profile),
+                    SecurityOpt::AppArmorUnconfined => config.apparmor_profile = None,
+                    SecurityOpt::Label(opt) => {
+                        let label = config.selinux_label.get_or_insert_with(Default::default);
+                        match opt {
+                            LabelOpt::User(user) => label.user = user,
+                            LabelOpt::Role(role) => label.role = role,
+                            LabelOpt::Type(type_) => label.type_ = type_,
+                            LabelOpt::Level(level) => {
+                                label.level = level;
+                                level_set = true;
+                            }
+                            LabelOpt::Disable => label_disabled = true,
+                        }
+                    }
+                }
+            }
+            // `z` and `Z` volumes are relabeled for the container's label,
+            // so on an SELinux host they get the default one.
+            if !label_disabled
+                && config.selinux_label.is_none()
+                && config.volumes.iter().any(|v| v.relabel.is_some())
+                && selinux_enabled()
+            {
+                config.selinux_label = Some(Default::default());
+            }
+            if label_disabled {
+                config.selinux_label = None;
+            }
+            if let Some(label) = &mut config.selinux_label
+                && !level_set
+            {
+                let used = match StateStore::new(&config.root).list() {
+                    Ok(states) => states
+                        .into_iter()
+                        .filter(|s| s.is_alive())
+                        .filter_map(|s| s.selinux_level)
+                        .collect(),
+                    Err(e) => {
+                        eprintln!("Failed to list containers: {:#}", e);
+                        std::process::exit(1);
+                    }
+                };
+                match unique_level(&used) {
+                    Ok(level) => label.level = level,
+                    Err(e) => {
+                        eprintln!("{:#}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if privileged {
+                config.capabilities = Capability::all();
+                config.masked_paths.clear();
+                config.readonly_paths.clear();
+                config.seccomp = None;
+                config.device_rules = vec![DeviceRule::allow_all()];
+            }
+            if let Err(e) = run_container(&config) {
                 eprintln!("Container error: {}", e);
                 std::process::exit(1);
             }
         }
+        Commands::Delete { id, keep } => match StateStore::new(&root).remove(&id, keep) {
+            Ok(Some(upper)) => println!(
+                "Kept the changes of container {} in {}; remove that directory to reclaim the space",
+                id,
+                upper.display()
+            ),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Failed to delete container: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Commands::Prune => match prune_cgroups(&StateStore::new(&root)) {
+            Ok(pruned) => {
+                for cgroup in pruned {
+                    println!("Removed {}", cgroup.display());
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to prune cgroups: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Commands::Pause { id } => {
+            if let Err(e) = pause_container(&StateStore::new(&root), &id) {
+                eprintln!("Failed to pause container: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Unpause { id } => {
+            if let Err(e) = unpause_container(&StateStore::new(&root), &id) {
+                eprintln!("Failed to unpause container: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Stats {
+            ids,
+            no_stream,
+            format,
+        } => {
+            if let Err(e) = show_stats(&StateStore::new(&root), &ids, no_stream, format) {
+                eprintln!("Failed to read stats: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Pull {
+            reference,
+            platform,
+            creds,
+            insecure,
+        } => {
+            let mut client = Client::new(&reference, creds, insecure);
+            let platform = platform.unwrap_or_else(Platform::host);
+            match ImageStore::new(&root).pull(&mut client, &reference, &platform) {
+                Ok(image) => {
+                    println!("Digest: {}", image.manifest);
+                    println!("Pulled {}", reference);
+                }
+                Err(e) => {
+                    eprintln!("Failed to pull {}: {:#}", reference, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Image {
+            command: ImageCommands::Load { input },
+        } => match ImageStore::new(&root).load(&input) {
+            Ok(images) => {
+                for image in images {
+                    if image.repo_tags.is_empty() {
+                        println!("Loaded image ID: {}", image.id);
+                    }
+                    for tag in &image.repo_tags {
+                        println!("Loaded image: {}", tag);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to load image: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        Commands::Image {
+            command: ImageCommands::Ls,
+        } => match ImageStore::new(&root).list() {
+            Ok(images) => print!("{}", format_table(&images)),
+            Err(e) => {
+                eprintln!("Failed to list images: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        Commands::Image {
+            command: ImageCommands::Rm { images, force },
+        } => {
+            let store = ImageStore::new(&root);
+            let mut failed = false;
+            for image in images {
+                match store.remove(&image, force) {
+                    Ok(removed) => {
+                        for line in removed {
+                            println!("{}", line);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to remove image {}: {:#}", image, e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        }
+        Commands::Image {
+            command: ImageCommands::Prune { all },
+        } => match ImageStore::new(&root).prune(all) {
+            Ok(removed) => {
+                for line in removed {
+                    println!("{}", line);
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to prune images: {:#}", e);
+                std::process::exit(1);
+            }
+        },
     }
     Ok(())
 }
@@ -57,11 +594,13 @@ mod tests {
                 rootfs,
                 command,
                 args,
+                ..
             } => {
                 assert_eq!(rootfs, "/tmp/rootfs");
-                assert_eq!(command, "/bin/ls");
+                assert_eq!(command.as_deref(), Some("/bin/ls"));
                 assert!(args.is_empty());
             }
+            _ => panic!("Expected run command"),
         }
     }
 
@@ -76,13 +615,15 @@ mod tests {
                 rootfs,
                 command,
                 args,
+                ..
             } => {
                 assert_eq!(rootfs, "/tmp/rootfs");
-                assert_eq!(command, "/bin/ls");
+                assert_eq!(command.as_deref(), Some("/bin/ls"));
                 assert_eq!(args.len(), 2);
                 assert_eq!(args[0], "-la");
                 assert_eq!(args[1], "/tmp");
             }
+            _ => panic!("Expected run command"),
         }
     }
 
@@ -104,13 +645,15 @@ mod tests {
                 rootfs,
                 command,
                 args,
+                ..
             } => {
                 assert_eq!(rootfs, "/tmp/rootfs");
-                assert_eq!(command, "/bin/bash");
+                assert_eq!(command.as_deref(), Some("/bin/bash"));
                 assert_eq!(args.len(), 2);
                 assert_eq!(args[0], "-c");
                 assert_eq!(args[1], "echo hello");
             }
+            _ => panic!("Expected run command"),
         }
     }
 
@@ -133,12 +676,14 @@ mod tests {
                     rootfs,
                     command,
                     args,
+                    ..
                 } => {
                     assert_eq!(rootfs, rootfs_path);
-                    assert_eq!(command, "/bin/echo");
+                    assert_eq!(command.as_deref(), Some("/bin/echo"));
                     assert_eq!(args.len(), 1);
                     assert_eq!(args[0], "test");
                 }
+                _ => panic!("Expected run command"),
             }
         }
     }
@@ -164,11 +709,13 @@ mod tests {
                     rootfs,
                     command: parsed_command,
                     args: parsed_args,
+                    ..
                 } => {
                     assert_eq!(rootfs, "/tmp/rootfs");
-                    assert_eq!(parsed_command, command);
+                    assert_eq!(parsed_command.as_deref(), Some(command));
                     assert_eq!(parsed_args, args);
                 }
+                _ => panic!("Expected run command"),
             }
         }
     }
@@ -223,18 +770,21 @@ mod tests {
                 rootfs,
                 command,
                 args,
+                ..
             } => {
                 // Verify rootfs is a non-empty string
                 assert!(!rootfs.is_empty());
                 assert!(rootfs.len() > 1);
 
                 // Verify command is a non-empty string
+                let command = command.expect("command should be parsed");
                 assert!(!command.is_empty());
                 assert!(command.len() > 1);
 
                 // Verify args vector is properly initialized
                 assert!(args.is_empty()); // No args in this test case
             }
+            _ => panic!("Expected run command"),
         }
     }
 
@@ -257,9 +807,10 @@ mod tests {
                 rootfs,
                 command,
                 args,
+                ..
             } => {
                 assert_eq!(rootfs, "/var/lib/alpine-rootfs");
-                assert_eq!(command, "/bin/sh");
+                assert_eq!(command.as_deref(), Some("/bin/sh"));
                 assert_eq!(args.len(), 2);
                 assert_eq!(args[0], "-c");
                 assert_eq!(
@@ -267,6 +818,7 @@ mod tests {
                     "echo 'Hello World' && ls -la /tmp && cat /etc/os-release"
                 );
             }
+            _ => panic!("Expected run command"),
         }
     }
 
@@ -291,11 +843,757 @@ mod tests {
                 rootfs,
                 command,
                 args,
+                ..
             } => {
                 assert_eq!(rootfs, "/tmp/rootfs");
-                assert_eq!(command, "echo"); // First arg after rootfs is the command
+                assert_eq!(command.as_deref(), Some("echo")); // First arg after rootfs is the command
                 assert_eq!(args, vec!["arg1", "arg2", "--flag", "arg3"]);
             }
+            _ => panic!("Expected run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_read_only_and_mounts() {
+        let args = vec![
+            "container",
+            "run",
+            "--read-only",
+            "-v",
+            "/srv/data:/data:ro",
+            "--tmpfs",
+            "/tmp",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                read_only,
+                volumes,
+                tmpfs,
+                command,
+                ..
+            } => {
+                assert!(read_only);
+                assert_eq!(volumes.len(), 1);
+                assert!(volumes[0].read_only);
+                assert_eq!(tmpfs.len(), 1);
+                assert_eq!(command.as_deref(), Some("/bin/sh"));
+            }
+            _ => panic!("Expected run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_rejects_invalid_volume() {
+        let result = Cli::try_parse_from([
+            "container",
+            "run",
+            "-v",
+            "/srv/data:relative",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_masking_options() {
+        let args = vec![
+            "container",
+            "run",
+            "--masked-path",
+            "/proc/cpuinfo",
+            "--readonly-path",
+            "/proc/sysvipc",
+            "--unmask",
+            "ALL",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                masked_paths,
+                readonly_paths,
+                unmask_paths,
+                ..
+            } => {
+                assert_eq!(masked_paths, vec!["/proc/cpuinfo"]);
+                assert_eq!(readonly_paths, vec!["/proc/sysvipc"]);
+                assert_eq!(unmask_paths, vec!["ALL"]);
+            }
+            _ => panic!("Expected run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_overlay_options() {
+        let args = vec![
+            "container",
+            "--root",
+            "/tmp/state",
+            "run",
+            "--layer",
+            "/layers/app",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        assert_eq!(cli.root, Some(PathBuf::from("/tmp/state")));
+        match cli.command {
+            Commands::Run {
+                overlay, layers, ..
+            } => {
+                assert!(!overlay);
+                assert_eq!(layers, vec![PathBuf::from("/layers/app")]);
+            }
+            _ => panic!("Expected run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_delete_command() {
+        let cli = Cli::try_parse_from(["container", "delete", "--keep", "abc123"]).unwrap();
+
+        match cli.command {
+            Commands::Delete { id, keep } => {
+                assert_eq!(id, "abc123");
+                assert!(keep);
+            }
+            _ => panic!("Expected delete command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_rootfs_propagation() {
+        let cli = Cli::try_parse_from(["container", "run", "/tmp/rootfs", "/bin/sh"]).unwrap();
+        match cli.command {
+            Commands::Run {
+                rootfs_propagation, ..
+            } => assert_eq!(rootfs_propagation, Propagation::RPrivate),
+            _ => panic!("Expected run command"),
+        }
+
+        let cli = Cli::try_parse_from([
+            "container",
+            "run",
+            "--rootfs-propagation",
+            "rslave",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Run {
+                rootfs_propagation, ..
+            } => assert_eq!(rootfs_propagation, Propagation::RSlave),
+            _ => panic!("Expected run command"),
+        }
+
+        assert!(
+            Cli::try_parse_from([
+                "container",
+                "run",
+                "--rootfs-propagation",
+                "bogus",
+                "/tmp/rootfs",
+                "/bin/sh",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_parse_io_limits() {
+        let args = vec![
+            "container",
+            "run",
+            "--device-read-bps",
+            "/dev/sda:10mb",
+            "--device-write-iops",
+            "/dev/sda:100",
+            "--blkio-weight",
+            "300",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                device_read_bps,
+                device_write_iops,
+                blkio_weight,
+                ..
+            } => {
+                assert_eq!(device_read_bps[0].rate, 10 * 1024 * 1024);
+                assert_eq!(device_write_iops[0].rate, 100);
+                assert_eq!(blkio_weight, Some(300));
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        for bad in [
+            ["--blkio-weight", "5"],
+            ["--device-read-iops", "/dev/sda:1mb"],
+        ] {
+            let args = ["container", "run", bad[0], bad[1], "/tmp/rootfs", "/bin/sh"];
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_cpu_options() {
+        let args = vec![
+            "container",
+            "run",
+            "--cpuset-cpus",
+            "0-2,4",
+            "--cpuset-mems",
+            "0",
+            "--cpu-shares",
+            "512",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                cpuset_cpus,
+                cpuset_mems,
+                cpu_shares,
+                cpu_weight,
+                ..
+            } => {
+                assert_eq!(cpuset_cpus.unwrap().to_string(), "0-2,4");
+                assert_eq!(cpuset_mems.unwrap().to_string(), "0");
+                assert_eq!(cpu_shares, Some(512));
+                assert_eq!(cpu_weight, None);
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        for bad in [
+            vec!["--cpuset-cpus", "3-1"],
+            vec!["--cpu-weight", "0"],
+            vec!["--cpu-shares", "512", "--cpu-weight", "50"],
+        ] {
+            let mut args = vec!["container", "run"];
+            args.extend(bad);
+            args.extend(["/tmp/rootfs", "/bin/sh"]);
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_memory_options() {
+        let args = vec![
+            "container",
+            "run",
+            "-m",
+            "512m",
+            "--memory-high",
+            "400m",
+            "--memory-reservation",
+            "256m",
+            "--memory-swap",
+            "-1",
+            "--oom-score-adj",
+            "-500",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                memory,
+                memory_high,
+                memory_reservation,
+                memory_min,
+                memory_swap,
+                oom_score_adj,
+                ..
+            } => {
+                assert_eq!(memory, Some(512 * 1024 * 1024));
+                assert_eq!(memory_high, Some(400 * 1024 * 1024));
+                assert_eq!(memory_reservation, Some(256 * 1024 * 1024));
+                assert_eq!(memory_min, None);
+                assert_eq!(memory_swap, Some(MemorySwap::Unlimited));
+                assert_eq!(oom_score_adj, Some(-500));
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        for bad in [["--oom-score-adj", "1001"], ["--memory", "lots"]] {
+            let args = ["container", "run", bad[0], bad[1], "/tmp/rootfs", "/bin/sh"];
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_security_opts() {
+        let args = vec![
+            "container",
+            "run",
+            "--security-opt",
+            "seccomp=unconfined",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run { security_opts, .. } => {
+                assert_eq!(security_opts, vec![SecurityOpt::SeccompUnconfined]);
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        let args = [
+            "container",
+            "run",
+            "--security-opt",
+            "seccomp",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_capabilities() {
+        let args = vec![
+            "container",
+            "run",
+            "--cap-add",
+            "NET_ADMIN",
+            "--cap-drop",
+            "ALL",
+            "--cap-drop",
+            "cap_mknod",
+            "--privileged",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                cap_add,
+                cap_drop,
+                privileged,
+                ..
+            } => {
+                assert_eq!(cap_add, vec![CapArg::Cap("CAP_NET_ADMIN".parse().unwrap())]);
+                assert_eq!(
+                    cap_drop,
+                    vec![CapArg::All, CapArg::Cap("CAP_MKNOD".parse().unwrap())]
+                );
+                assert!(privileged);
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        let args = [
+            "container",
+            "run",
+            "--cap-add",
+            "FLY",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_ulimits() {
+        let args = vec![
+            "container",
+            "run",
+            "--ulimit",
+            "nofile=1024:4096,nproc=512",
+            "--ulimit",
+            "core=0",
+            "--security-opt",
+            "no-new-privileges=false",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                ulimits,
+                security_opts,
+                ..
+            } => {
+                let names: Vec<_> = ulimits.iter().map(|u| u.name).collect();
+                assert_eq!(names, ["nofile", "nproc", "core"]);
+                assert_eq!((ulimits[0].soft, ulimits[0].hard), (1024, 4096));
+                assert_eq!(security_opts, vec![SecurityOpt::NoNewPrivileges(false)]);
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        let args = [
+            "container",
+            "run",
+            "--ulimit",
+            "nofile=9:1",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_landlock_paths() {
+        let args = vec![
+            "container",
+            "run",
+            "--landlock-ro",
+            "/usr",
+            "--landlock-ro",
+            "/bin",
+            "--landlock-rw",
+            "/tmp",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                landlock_ro,
+                landlock_rw,
+                ..
+            } => {
+                assert_eq!(landlock_ro, [PathBuf::from("/usr"), PathBuf::from("/bin")]);
+                assert_eq!(landlock_rw, [PathBuf::from("/tmp")]);
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        let args = [
+            "container",
+            "run",
+            "--landlock-rw",
+            "tmp",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_devices() {
+        let args = vec![
+            "container",
+            "run",
+            "--device",
+            "/dev/fuse",
+            "--device",
+            "/dev/sda:/dev/xvda:r",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run { devices, .. } => {
+                assert_eq!(devices.len(), 2);
+                assert_eq!(devices[0].destination, PathBuf::from("/dev/fuse"));
+                assert_eq!(devices[1].destination, PathBuf::from("/dev/xvda"));
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        let args = [
+            "container",
+            "run",
+            "--device",
+            "fuse",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_lsm_options() {
+        let args = vec![
+            "container",
+            "run",
+            "--security-opt",
+            "apparmor=docker-default",
+            "--security-opt",
+            "label=type:spc_t",
+            "-v",
+            "/data:/data:Z",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run { security_opts, .. } => {
+                assert_eq!(
+                    security_opts,
+                    vec![
+                        SecurityOpt::AppArmor("docker-default".to_string()),
+                        SecurityOpt::Label(LabelOpt::Type("spc_t".to_string())),
+                    ]
+                );
+            }
+            _ => panic!("Expected run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_pressure_triggers() {
+        let args = vec![
+            "container",
+            "run",
+            "--pressure-trigger",
+            "memory=150ms/1s",
+            "--pressure-trigger",
+            "io:full=1s/2s",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Run {
+                pressure_triggers, ..
+            } => {
+                assert_eq!(pressure_triggers.len(), 2);
+                assert!(pressure_triggers[1].full);
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        let args = vec![
+            "container",
+            "run",
+            "--pressure-trigger",
+            "memory=150ms",
+            "/tmp/rootfs",
+            "/bin/sh",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_prune_command() {
+        let cli = Cli::try_parse_from(["container", "prune"]).unwrap();
+        assert!(matches!(cli.command, Commands::Prune));
+    }
+
+    #[test]
+    fn test_cli_parse_pause_commands() {
+        let cli = Cli::try_parse_from(["container", "pause", "abc123"]).unwrap();
+        assert!(matches!(cli.command, Commands::Pause { id } if id == "abc123"));
+
+        let cli = Cli::try_parse_from(["container", "unpause", "abc123"]).unwrap();
+        assert!(matches!(cli.command, Commands::Unpause { id } if id == "abc123"));
+
+        assert!(Cli::try_parse_from(["container", "pause"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_stats_command() {
+        let cli = Cli::try_parse_from(["container", "stats"]).unwrap();
+        match cli.command {
+            Commands::Stats {
+                ids,
+                no_stream,
+                format,
+            } => {
+                assert!(ids.is_empty());
+                assert!(!no_stream);
+                assert_eq!(format, StatsFormat::Table);
+            }
+            _ => panic!("Expected stats command"),
+        }
+
+        let args = [
+            "container",
+            "stats",
+            "--no-stream",
+            "--format",
+            "json",
+            "abc",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Stats {
+                ids,
+                no_stream,
+                format,
+            } => {
+                assert_eq!(ids, vec!["abc"]);
+                assert!(no_stream);
+                assert_eq!(format, StatsFormat::Json);
+            }
+            _ => panic!("Expected stats command"),
         }
+
+        assert!(Cli::try_parse_from(["container", "stats", "--format", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_image_load() {
+        let cli = Cli::try_parse_from(["container", "image", "load", "-i", "alpine.tar"]).unwrap();
+        match cli.command {
+            Commands::Image {
+                command: ImageCommands::Load { input },
+            } => assert_eq!(input, PathBuf::from("alpine.tar")),
+            _ => panic!("Expected image load command"),
+        }
+
+        assert!(Cli::try_parse_from(["container", "image", "load"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_run_image_overrides() {
+        let cli = Cli::try_parse_from(["container", "run", "alpine"]).unwrap();
+        match cli.command {
+            Commands::Run {
+                rootfs,
+                command,
+                args,
+                entrypoint,
+                ..
+            } => {
+                assert_eq!(rootfs, "alpine");
+                assert_eq!(command, None);
+                assert!(args.is_empty());
+                assert_eq!(entrypoint, None);
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        let cli = Cli::try_parse_from([
+            "container",
+            "run",
+            "--entrypoint",
+            "",
+            "-e",
+            "LANG=C.UTF-8",
+            "--env",
+            "HOME",
+            "-w",
+            "/srv",
+            "-u",
+            "nobody:nogroup",
+            "--stop-signal",
+            "QUIT",
+            "alpine:3.19",
+            "ls",
+            "-la",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Run {
+                entrypoint,
+                env,
+                workdir,
+                user,
+                stop_signal,
+                command,
+                args,
+                ..
+            } => {
+                assert_eq!(entrypoint.as_deref(), Some(""));
+                assert_eq!(env, ["LANG=C.UTF-8", "HOME"]);
+                assert_eq!(workdir, Some(PathBuf::from("/srv")));
+                let user = user.unwrap();
+                assert_eq!(user.user, "nobody");
+                assert_eq!(user.group.as_deref(), Some("nogroup"));
+                assert_eq!(stop_signal, Some(Signal::SIGQUIT));
+                assert_eq!(command.as_deref(), Some("ls"));
+                assert_eq!(args, ["-la"]);
+            }
+            _ => panic!("Expected run command"),
+        }
+
+        for bad in [["-w", "srv"], ["-u", "root:"], ["--stop-signal", "SIGNOPE"]] {
+            let args = ["container", "run", bad[0], bad[1], "alpine"];
+            assert!(Cli::try_parse_from(args).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_pull() {
+        let cli = Cli::try_parse_from([
+            "container",
+            "pull",
+            "--platform",
+            "linux/arm64/v8",
+            "--creds",
+            "user:secret",
+            "localhost:5000/team/app:v1",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Pull {
+                reference,
+                platform,
+                creds,
+                insecure,
+            } => {
+                assert_eq!(reference.registry, "localhost:5000");
+                assert_eq!(reference.repository, "team/app");
+                assert_eq!(platform.unwrap().to_string(), "linux/arm64/v8");
+                assert_eq!(creds.unwrap().username, "user");
+                assert!(!insecure);
+            }
+            _ => panic!("Expected pull command"),
+        }
+
+        assert!(Cli::try_parse_from(["container", "pull"]).is_err());
+        assert!(Cli::try_parse_from(["container", "pull", "Bad:Ref"]).is_err());
+        assert!(
+            Cli::try_parse_from(["container", "pull", "--platform", "linux", "alpine"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_parse_image_rm_and_prune() {
+        let cli =
+            Cli::try_parse_from(["container", "image", "rm", "-f", "alpine", "3f2a"]).unwrap();
+        match cli.command {
+            Commands::Image {
+                command: ImageCommands::Rm { images, force },
+            } => {
+                assert_eq!(images, ["alpine", "3f2a"]);
+                assert!(force);
+            }
+            _ => panic!("Expected image rm command"),
+        }
+        assert!(Cli::try_parse_from(["container", "image", "rm"]).is_err());
+
+        let cli = Cli::try_parse_from(["container", "image", "prune", "--all"]).unwrap();
+        match cli.command {
+            Commands::Image {
+                command: ImageCommands::Prune { all },
+            } => assert!(all),
+            _ => panic!("Expected image prune command"),
+        }
+        let cli = Cli::try_parse_from(["container", "image", "ls"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Image {
+                command: ImageCommands::Ls
+            }
+        ));
     }
 }
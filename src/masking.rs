@@ -0,0 +1,161 @@
+use crate::pivot_root::remount_tree_readonly;
+use anyhow::{Context, Result};
+use nix::mount::{MsFlags, mount};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// Paths hidden from the container, matching the list Docker uses by default.
+pub const DEFAULT_MASKED_PATHS: &[&str] = &[
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/sysrq-trigger",
+    "/sys/firmware",
+    "/sys/devices/virtual/powercap",
+];
+
+/// Paths the container may read but never write.
+pub const DEFAULT_READONLY_PATHS: &[&str] = &["/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys"];
+
+pub fn default_masked_paths() -> Vec<String> {
+    DEFAULT_MASKED_PATHS.iter().map(|p| p.to_string()).collect()
+}
+
+pub fn default_readonly_paths() -> Vec<String> {
+    DEFAULT_READONLY_PATHS
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
+}
+
+/// Hides each path that exists: directories get an empty read-only tmpfs,
+/// files get `dev_null` bind-mounted over them. `dev_null` is opened on the
+/// host before `pivot_root` since the container may not have a `/dev/null`.
+pub fn apply_masked_paths(paths: &[String], dev_null: &File) -> Result<()> {
+    let dev_null_path = format!("/proc/self/fd/{}", dev_null.as_raw_fd());
+
+    for path in paths {
+        let target = Path::new(path);
+        if !target.exists() {
+            continue;
+        }
+
+        if target.is_dir() {
+            mount(
+                Some("tmpfs"),
+                target,
+                Some("tmpfs"),
+                MsFlags::MS_RDONLY,
+                None::<&str>,
+            )
+            .with_context(|| format!("Failed to mask directory {}", path))?;
+        } else {
+            mount(
+                Some(dev_null_path.as_str()),
+                target,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .with_context(|| format!("Failed to mask file {}", path))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Bind-mounts each existing path onto itself and remounts it, along with
+/// any submounts such as binfmt_misc under `/proc/sys`, read-only.
+pub fn apply_readonly_paths(paths: &[String]) -> Result<()> {
+    for path in paths {
+        let target = Path::new(path);
+        if !target.exists() {
+            continue;
+        }
+
+        mount(
+            Some(target),
+            target,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .with_context(|| format!("Failed to bind mount {}", path))?;
+
+        remount_tree_readonly(target)?;
+    }
+
+    Ok(())
+}
+
+/// Removes `unmask` entries from `paths`; `ALL` clears the list entirely.
+pub fn unmask(paths: &mut Vec<String>, unmask: &[String]) {
+    if unmask.iter().any(|p| p == "ALL") {
+        paths.clear();
+    } else {
+        paths.retain(|p| !unmask.contains(p));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_paths_are_absolute() {
+        for path in DEFAULT_MASKED_PATHS.iter().chain(DEFAULT_READONLY_PATHS) {
+            assert!(path.starts_with('/'), "{} is not absolute", path);
+        }
+    }
+
+    #[test]
+    fn test_default_paths_include_sensitive_entries() {
+        let masked = default_masked_paths();
+        let readonly = default_readonly_paths();
+
+        for path in [
+            "/proc/kcore",
+            "/proc/keys",
+            "/proc/sysrq-trigger",
+            "/sys/firmware",
+        ] {
+            assert!(masked.contains(&path.to_string()));
+        }
+        for path in ["/proc/sys", "/proc/bus", "/proc/irq"] {
+            assert!(readonly.contains(&path.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_unmask_single_path() {
+        let mut paths = default_masked_paths();
+        unmask(&mut paths, &["/proc/kcore".to_string()]);
+
+        assert!(!paths.contains(&"/proc/kcore".to_string()));
+        assert_eq!(paths.len(), DEFAULT_MASKED_PATHS.len() - 1);
+    }
+
+    #[test]
+    fn test_unmask_all() {
+        let mut paths = default_masked_paths();
+        unmask(&mut paths, &["ALL".to_string()]);
+
+        assert!(paths.is_empty());
+    }
+
+    #[test]
+    fn test_missing_paths_are_skipped() {
+        let dev_null = File::open("/dev/null").unwrap();
+        let paths = vec!["/nonexistent/docker-clone-mask".to_string()];
+
+        assert!(apply_masked_paths(&paths, &dev_null).is_ok());
+        assert!(apply_readonly_paths(&paths).is_ok());
+    }
+}
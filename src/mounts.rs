@@ -0,0 +1,548 @@
+use crate::idmap::attach_tree;
+use crate::pivot_root::{remount_readonly, remount_tree_readonly};
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{OFlag, open, openat, readlinkat};
+use nix::mount::{MsFlags, mount};
+use nix::sys::stat::{Mode, mkdirat};
+use nix::unistd::close;
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Most symlinks followed while creating a mountpoint, as for the kernel's
+/// own path lookups.
+const MAX_SYMLINKS: usize = 40;
+
+/// A host path bind-mounted into the container
+/// (`-v /host:/container[:ro|rw][,idmap][,z|Z]`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub read_only: bool,
+    /// Map file ownership into the container's user namespace.
+    pub idmap: bool,
+    /// Give the source the container's SELinux mount context.
+    pub relabel: Option<Relabel>,
+}
+
+/// How a volume is relabelled when the container runs with an SELinux label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relabel {
+    /// `z`: a label any container can use.
+    Shared,
+    /// `Z`: a label only this container's level can use.
+    Private,
+}
+
+/// A fresh tmpfs mounted inside the container (`--tmpfs /path[:options]`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tmpfs {
+    pub destination: PathBuf,
+    pub options: Option<String>,
+}
+
+impl FromStr for Volume {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (source, destination, options) = match parts.as_slice() {
+            [source, destination] => (source, destination, ""),
+            [source, destination, options] => (source, destination, *options),
+            _ => anyhow::bail!("Invalid volume '{}', expected SOURCE:DEST[:OPTIONS]", s),
+        };
+
+        if source.is_empty() {
+            anyhow::bail!("Volume source must not be empty: {}", s);
+        }
+
+        let mut volume = Volume {
+            source: PathBuf::from(source),
+            destination: container_path(destination)?,
+            read_only: false,
+            idmap: false,
+            relabel: None,
+        };
+
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            match option {
+                "ro" => volume.read_only = true,
+                "rw" => volume.read_only = false,
+                "idmap" => volume.idmap = true,
+                "z" => volume.relabel = Some(Relabel::Shared),
+                "Z" => volume.relabel = Some(Relabel::Private),
+                _ => anyhow::bail!(
+                    "Invalid volume option '{}', expected ro, rw, idmap, z or Z",
+                    option
+                ),
+            }
+        }
+
+        Ok(volume)
+    }
+}
+
+impl FromStr for Tmpfs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (destination, options) = match s.split_once(':') {
+            Some((destination, options)) => (destination, Some(options.to_string())),
+            None => (s, None),
+        };
+
+        Ok(Tmpfs {
+            destination: container_path(destination)?,
+            options,
+        })
+    }
+}
+
+/// Validates a path as seen from inside the container: it must be absolute
+/// and must not climb out of the rootfs with `..`.
+pub fn container_path(path: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(path);
+    if !path.is_absolute() {
+        anyhow::bail!("Container path must be absolute: {:?}", path);
+    }
+    if path.components().any(|c| c == Component::ParentDir) {
+        anyhow::bail!("Container path must not contain '..': {:?}", path);
+    }
+    Ok(path)
+}
+
+/// What [`open_in_root`] creates when a mountpoint does not exist yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mountpoint {
+    Dir,
+    File,
+}
+
+/// Opens `destination` inside `rootfs` as an `O_PATH` descriptor, creating
+/// it as `kind`, and any missing parents as directories, when it does not
+/// exist. Symlinks resolve as they would inside the container, so an image
+/// cannot point a mountpoint at the host. Mount on it through [`fd_path`].
+pub fn open_in_root(rootfs: &Path, destination: &Path, kind: Mountpoint) -> Result<OwnedFd> {
+    let root = open(
+        rootfs,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )
+    .with_context(|| format!("Failed to open {:?}", rootfs))?;
+    let root = unsafe { OwnedFd::from_raw_fd(root) };
+
+    let relative = destination.strip_prefix("/").unwrap_or(destination);
+    create_in_root(&root, relative, kind, &mut 0)
+        .with_context(|| format!("Failed to create mountpoint {:?}", destination))
+}
+
+/// The path the kernel resolves to the file `fd` refers to.
+pub fn fd_path(fd: &OwnedFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// [`open_in_root`] for `path` relative to `root`; `links` counts the
+/// symlinks followed so far.
+fn create_in_root(
+    root: &OwnedFd,
+    path: &Path,
+    kind: Mountpoint,
+    links: &mut usize,
+) -> Result<OwnedFd> {
+    if path.as_os_str().is_empty() {
+        return Ok(root.try_clone()?);
+    }
+    match resolve_in_root(root, path) {
+        Err(Errno::ENOENT) => {}
+        result => return result.with_context(|| format!("Failed to open {:?}", path)),
+    }
+
+    let parent_path = path.parent().unwrap_or(Path::new(""));
+    let parent = create_in_root(root, parent_path, Mountpoint::Dir, links)?;
+    // A trailing `.` or `..` exists once the parent does.
+    if let Some(name) = path.file_name() {
+        match readlinkat(parent.as_raw_fd(), name) {
+            // A dangling symlink: create its target instead, still inside
+            // the root.
+            Ok(target) => {
+                *links += 1;
+                if *links > MAX_SYMLINKS {
+                    anyhow::bail!("Too many levels of symbolic links at {:?}", path);
+                }
+                let target = Path::new(&target);
+                let next = match target.strip_prefix("/") {
+                    Ok(absolute) => absolute.to_path_buf(),
+                    Err(_) => parent_path.join(target),
+                };
+                return create_in_root(root, &next, kind, links);
+            }
+            Err(_) => create_entry(&parent, name.as_ref(), kind)
+                .with_context(|| format!("Failed to create {:?}", path))?,
+        }
+    }
+
+    resolve_in_root(root, path).with_context(|| format!("Failed to open {:?}", path))
+}
+
+/// Opens `path` with `root` as the root directory for absolute symlinks and
+/// `..`, refusing `/proc` magic links that could lead elsewhere.
+fn resolve_in_root(root: &OwnedFd, path: &Path) -> nix::Result<OwnedFd> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+    // open_how is non-exhaustive, so it cannot be built field by field.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_PATH | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS;
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    Errno::result(fd).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Creates `name` in `parent`, leaving an entry that already exists alone.
+fn create_entry(parent: &OwnedFd, name: &Path, kind: Mountpoint) -> nix::Result<()> {
+    let result = match kind {
+        Mountpoint::Dir => mkdirat(parent.as_raw_fd(), name, Mode::from_bits_truncate(0o755)),
+        Mountpoint::File => openat(
+            parent.as_raw_fd(),
+            name,
+            OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_WRONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        )
+        .and_then(close),
+    };
+    match result {
+        Err(Errno::EEXIST) => Ok(()),
+        result => result,
+    }
+}
+
+/// Mounts volumes and tmpfs into `rootfs`. Must run before `pivot_root` so
+/// that host sources are still reachable. `idmapped` holds the detached
+/// mounts prepared by the host for each `idmap` volume, in volume order.
+/// tmpfs mounts get `mount_label` as their SELinux context when set.
+pub fn setup_mounts(
+    rootfs: &Path,
+    volumes: &[Volume],
+    tmpfs: &[Tmpfs],
+    idmapped: Vec<OwnedFd>,
+    mount_label: Option<&str>,
+) -> Result<()> {
+    let mut idmapped = idmapped.into_iter();
+    for volume in volumes {
+        if volume.idmap {
+            let tree = idmapped.next().with_context(|| {
+                format!("No idmapped mount prepared for {:?}", volume.destination)
+            })?;
+            let target = create_mountpoint(rootfs, volume)?;
+            attach_tree(&tree, &fd_path(&target))?;
+        } else {
+            bind_volume(rootfs, volume)?;
+        }
+    }
+
+    for tmp in tmpfs {
+        let target = open_in_root(rootfs, &tmp.destination, Mountpoint::Dir)?;
+
+        let context = mount_label.map(|label| format!("context=\"{}\"", label));
+        let options = match (tmp.options.as_deref(), context) {
+            (Some(options), Some(context)) => Some(format!("{},{}", options, context)),
+            (options, context) => options.map(str::to_string).or(context),
+        };
+        mount(
+            Some("tmpfs"),
+            &fd_path(&target),
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            options.as_deref(),
+        )
+        .with_context(|| format!("Failed to mount tmpfs at {:?}", tmp.destination))?;
+    }
+
+    Ok(())
+}
+
+/// Mounts a read-only sysfs at `rootfs/sys`. A fresh sysfs needs a network
+/// namespace owned by our user namespace, so fall back to a recursive bind
+/// of the host's `/sys`, read-only all the way down, when the kernel
+/// refuses.
+pub fn mount_sysfs(rootfs: &Path) -> Result<()> {
+    let target = open_in_root(rootfs, Path::new("/sys"), Mountpoint::Dir)?;
+
+    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | MsFlags::MS_RDONLY;
+    if mount(
+        Some("sysfs"),
+        &fd_path(&target),
+        Some("sysfs"),
+        flags,
+        None::<&str>,
+    )
+    .is_ok()
+    {
+        return Ok(());
+    }
+
+    mount(
+        Some("/sys"),
+        &fd_path(&target),
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .context("Failed to bind mount /sys in the container")?;
+
+    // Submounts are found by path, so use where the mountpoint really is.
+    let target = std::fs::read_link(fd_path(&target)).context("Failed to locate /sys")?;
+    remount_tree_readonly(&target)
+}
+
+fn bind_volume(rootfs: &Path, volume: &Volume) -> Result<()> {
+    let source = volume
+        .source
+        .canonicalize()
+        .with_context(|| format!("Volume source does not exist: {:?}", volume.source))?;
+    let target = create_mountpoint(rootfs, volume)?;
+
+    mount(
+        Some(source.as_path()),
+        &fd_path(&target),
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to bind mount {:?} at {:?}",
+            source, volume.destination
+        )
+    })?;
+
+    if volume.read_only {
+        // The descriptor still refers to the directory underneath, so open
+        // the new mount to remount it.
+        let mounted = create_mountpoint(rootfs, volume)?;
+        remount_readonly(fd_path(&mounted))?;
+    }
+
+    Ok(())
+}
+
+/// Opens the file or directory a volume is mounted over, creating it to
+/// match the type of its source.
+fn create_mountpoint(rootfs: &Path, volume: &Volume) -> Result<OwnedFd> {
+    let kind = if volume.source.is_dir() {
+        Mountpoint::Dir
+    } else {
+        Mountpoint::File
+    };
+    open_in_root(rootfs, &volume.destination, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sched::{CloneFlags, unshare};
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_parse_volume() {
+        let volume: Volume = "/data:/mnt/data".parse().unwrap();
+
+        assert_eq!(volume.source, PathBuf::from("/data"));
+        assert_eq!(volume.destination, PathBuf::from("/mnt/data"));
+        assert!(!volume.read_only);
+    }
+
+    #[test]
+    fn test_parse_volume_modes() {
+        let ro: Volume = "/data:/data:ro".parse().unwrap();
+        let rw: Volume = "/data:/data:rw".parse().unwrap();
+
+        assert!(ro.read_only);
+        assert!(!rw.read_only);
+        assert!("/data:/data:rx".parse::<Volume>().is_err());
+    }
+
+    #[test]
+    fn test_parse_volume_idmap() {
+        let idmap: Volume = "/data:/data:idmap".parse().unwrap();
+        let ro_idmap: Volume = "/data:/data:ro,idmap".parse().unwrap();
+        let plain: Volume = "/data:/data".parse().unwrap();
+
+        assert!(idmap.idmap && !idmap.read_only);
+        assert!(ro_idmap.idmap && ro_idmap.read_only);
+        assert!(!plain.idmap);
+    }
+
+    #[test]
+    fn test_parse_volume_relabel() {
+        let shared: Volume = "/data:/data:z".parse().unwrap();
+        let private: Volume = "/data:/data:ro,Z".parse().unwrap();
+        let plain: Volume = "/data:/data".parse().unwrap();
+
+        assert_eq!(shared.relabel, Some(Relabel::Shared));
+        assert_eq!(private.relabel, Some(Relabel::Private));
+        assert!(private.read_only);
+        assert_eq!(plain.relabel, None);
+    }
+
+    #[test]
+    fn test_parse_volume_invalid() {
+        let test_cases = vec![
+            "/data",
+            ":/data",
+            "/data:relative",
+            "/data:/../etc",
+            "/a:/b:ro:extra",
+        ];
+
+        for spec in test_cases {
+            assert!(
+                spec.parse::<Volume>().is_err(),
+                "{} should be rejected",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_tmpfs() {
+        let plain: Tmpfs = "/tmp".parse().unwrap();
+        let with_options: Tmpfs = "/run:size=64m,mode=755".parse().unwrap();
+
+        assert_eq!(plain.destination, PathBuf::from("/tmp"));
+        assert_eq!(plain.options, None);
+        assert_eq!(with_options.destination, PathBuf::from("/run"));
+        assert_eq!(with_options.options.as_deref(), Some("size=64m,mode=755"));
+        assert!("tmp".parse::<Tmpfs>().is_err());
+    }
+
+    #[test]
+    fn test_open_in_root_follows_symlinks_inside_root() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let host = tempfile::tempdir().unwrap();
+        // What a layer could ship: an absolute link to a host directory and
+        // a relative one climbing above the root.
+        symlink(host.path(), rootfs.path().join("data")).unwrap();
+        symlink("../../..", rootfs.path().join("up")).unwrap();
+
+        let file = open_in_root(rootfs.path(), Path::new("/data/foo"), Mountpoint::File).unwrap();
+        let dir = open_in_root(rootfs.path(), Path::new("/up/etc/x"), Mountpoint::Dir).unwrap();
+
+        let inside = rootfs
+            .path()
+            .join(host.path().strip_prefix("/").unwrap())
+            .join("foo");
+        assert!(!host.path().join("foo").exists());
+        assert!(inside.is_file());
+        assert_eq!(std::fs::read_link(fd_path(&file)).unwrap(), inside);
+        assert!(rootfs.path().join("etc/x").is_dir());
+        assert_eq!(
+            std::fs::read_link(fd_path(&dir)).unwrap(),
+            rootfs.path().join("etc/x")
+        );
+    }
+
+    #[test]
+    fn test_open_in_root_uses_existing_entries() {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("srv/app")).unwrap();
+        std::fs::write(rootfs.path().join("srv/app/config"), "keep").unwrap();
+        symlink("app", rootfs.path().join("srv/current")).unwrap();
+
+        let fd = open_in_root(
+            rootfs.path(),
+            Path::new("/srv/current/config"),
+            Mountpoint::File,
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_link(fd_path(&fd)).unwrap(),
+            rootfs.path().join("srv/app/config")
+        );
+        assert_eq!(
+            std::fs::read_to_string(rootfs.path().join("srv/app/config")).unwrap(),
+            "keep"
+        );
+        assert!(
+            open_in_root(
+                rootfs.path(),
+                Path::new("/srv/app/config/x"),
+                Mountpoint::Dir
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_open_in_root_rejects_symlink_loops() {
+        let rootfs = tempfile::tempdir().unwrap();
+        symlink("b", rootfs.path().join("a")).unwrap();
+        symlink("a", rootfs.path().join("b")).unwrap();
+
+        assert!(open_in_root(rootfs.path(), Path::new("/a/x"), Mountpoint::Dir).is_err());
+    }
+
+    #[test]
+    fn test_bind_volume_through_symlinked_destination() {
+        if !nix::unistd::Uid::effective().is_root() {
+            eprintln!("skipping: bind mounts need root");
+            return;
+        }
+        let rootfs = tempfile::tempdir().unwrap();
+        let host = tempfile::tempdir().unwrap();
+        let source = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file"), "volume").unwrap();
+        symlink(host.path(), rootfs.path().join("data")).unwrap();
+        let volume: Volume = format!("{}:/data/foo:ro", source.path().display())
+            .parse()
+            .unwrap();
+        let inside = rootfs
+            .path()
+            .join(host.path().strip_prefix("/").unwrap())
+            .join("foo");
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = if unshare(CloneFlags::CLONE_NEWNS).is_err()
+                    || mount(
+                        None::<&str>,
+                        "/",
+                        None::<&str>,
+                        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                        None::<&str>,
+                    )
+                    .is_err()
+                    || setup_mounts(rootfs.path(), &[volume], &[], Vec::new(), None).is_err()
+                {
+                    2
+                } else if host.path().join("foo").exists()
+                    || std::fs::read_to_string(inside.join("file")).ok().as_deref()
+                        != Some("volume")
+                    || std::fs::write(inside.join("new"), "").is_ok()
+                {
+                    1
+                } else {
+                    0
+                };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
@@ -1,21 +1,219 @@
-use nix::unistd::{getgid, getuid};
+use nix::sched::{CloneFlags, clone};
+use nix::sys::signal::Signal;
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::{User, close, getgid, getuid, pipe, read, write};
+use std::env;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::Command;
 
+const SUBUID_FILE: &str = "/etc/subuid";
+const SUBGID_FILE: &str = "/etc/subgid";
+
+/// Stack for the process that runs [`in_user_namespace`] callbacks.
+const HELPER_STACK_SIZE: usize = 256 * 1024;
+
+/// A range of host ids from `/etc/subuid` or `/etc/subgid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// How the ids of a container's user namespace map to the host's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdMapping {
+    /// Root maps every id to itself, as Docker does without userns-remap.
+    Identity,
+    /// Container root is the invoking user and ids from 1 up come from its
+    /// subordinate ranges, which only the setuid newuidmap and newgidmap
+    /// helpers may map.
+    Subordinate {
+        uid: u32,
+        gid: u32,
+        uids: IdRange,
+        gids: IdRange,
+    },
+    /// Container root is the invoking user and nothing else is mapped, for
+    /// users without subordinate ranges or helpers.
+    Single { uid: u32, gid: u32 },
+}
+
+impl IdMapping {
+    /// The mapping for the invoking user.
+    pub fn current() -> Self {
+        let (uid, gid) = (getuid().as_raw(), getgid().as_raw());
+        if uid == 0 {
+            return IdMapping::Identity;
+        }
+
+        let name = User::from_uid(getuid())
+            .ok()
+            .flatten()
+            .map(|user| user.name)
+            .unwrap_or_default();
+        let ranges =
+            |path| subordinate_range(&fs::read_to_string(path).unwrap_or_default(), &name, uid);
+        match (ranges(SUBUID_FILE), ranges(SUBGID_FILE)) {
+            (Some(uids), Some(gids)) if has_program("newuidmap") && has_program("newgidmap") => {
+                IdMapping::Subordinate {
+                    uid,
+                    gid,
+                    uids,
+                    gids,
+                }
+            }
+            _ => IdMapping::Single { uid, gid },
+        }
+    }
+
+    /// `uid_map` lines, in the form newuidmap takes them too.
+    pub fn uid_map(&self) -> String {
+        match self {
+            IdMapping::Identity => format!("0 0 {}", u32::MAX),
+            IdMapping::Subordinate { uid, uids, .. } => {
+                format!("0 {} 1\n1 {} {}", uid, uids.start, uids.count)
+            }
+            IdMapping::Single { uid, .. } => format!("0 {} 1", uid),
+        }
+    }
+
+    /// `gid_map` lines, in the form newgidmap takes them too.
+    pub fn gid_map(&self) -> String {
+        match self {
+            IdMapping::Identity => format!("0 0 {}", u32::MAX),
+            IdMapping::Subordinate { gid, gids, .. } => {
+                format!("0 {} 1\n1 {} {}", gid, gids.start, gids.count)
+            }
+            IdMapping::Single { gid, .. } => format!("0 {} 1", gid),
+        }
+    }
+
+    /// Writes the mapping for the user namespace of `pid`.
+    pub fn apply(&self, pid: i32) -> Result<(), Box<dyn std::error::Error>> {
+        if let IdMapping::Subordinate { .. } = self {
+            run_id_mapper("newuidmap", pid, &self.uid_map())?;
+            run_id_mapper("newgidmap", pid, &self.gid_map())?;
+            return Ok(());
+        }
+
+        // Without CAP_SETGID over the parent namespace, the kernel only
+        // takes a gid_map once setgroups is denied.
+        if let IdMapping::Single { .. } = self
+            && let Err(e) = fs::write(format!("/proc/{}/setgroups", pid), "deny")
+        {
+            return Err(format!("Failed to write setgroups: {}", e).into());
+        }
+
+        if let Err(e) = fs::write(format!("/proc/{}/uid_map", pid), self.uid_map()) {
+            return Err(format!("Failed to write uid_map: {}", e).into());
+        }
+
+        if let Err(e) = fs::write(format!("/proc/{}/gid_map", pid), self.gid_map()) {
+            return Err(format!("Failed to write gid_map: {}", e).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Maps the user namespace of `pid` for the invoking user: see
+/// [`IdMapping`].
 pub fn setup_user_namespace(pid: i32) -> Result<(), Box<dyn std::error::Error>> {
-    let uid = getuid().as_raw();
-    let gid = getgid().as_raw();
+    IdMapping::current().apply(pid)
+}
+
+/// Runs `f` in a child process inside a new user namespace mapped like a
+/// container's, as its root. Rootless, this is how files the container's
+/// other users created can be removed.
+pub fn in_user_namespace(
+    f: impl FnOnce() -> std::io::Result<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (wait_end, release_end) = pipe()?;
+    let (wait_end, release_end) = unsafe {
+        (
+            OwnedFd::from_raw_fd(wait_end),
+            OwnedFd::from_raw_fd(release_end),
+        )
+    };
 
-    if let Err(e) = std::fs::write(format!("/proc/{}/setgroups", pid), "deny") {
-        return Err(format!("Failed to write setgroups: {}", e).into());
+    let mut f = Some(f);
+    let mut stack = vec![0u8; HELPER_STACK_SIZE];
+    let child = unsafe {
+        clone(
+            Box::new(|| {
+                // Its copy of the write end would keep the read from ending.
+                let _ = close(release_end.as_raw_fd());
+                let mut buf = [0u8];
+                if read(wait_end.as_raw_fd(), &mut buf) != Ok(1) {
+                    return 1;
+                }
+                match f.take().map(|f| f()) {
+                    Some(Ok(())) => 0,
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        1
+                    }
+                    None => 1,
+                }
+            }),
+            &mut stack,
+            CloneFlags::CLONE_NEWUSER,
+            Some(Signal::SIGCHLD as i32),
+        )
     }
+    .map_err(|e| format!("Failed to create a user namespace: {}", e))?;
 
-    if let Err(e) = std::fs::write(format!("/proc/{}/uid_map", pid), format!("0 {} 1", uid)) {
-        return Err(format!("Failed to write uid_map: {}", e).into());
+    // The child only goes ahead once it reads a byte, so on failure it just
+    // sees the pipe close.
+    let mapped = IdMapping::current().apply(child.as_raw());
+    if mapped.is_ok() {
+        write(release_end.as_raw_fd(), &[0])?;
     }
+    drop(release_end);
+    let status = waitpid(child, None)?;
+    mapped?;
 
-    if let Err(e) = std::fs::write(format!("/proc/{}/gid_map", pid), format!("0 {} 1", gid)) {
-        return Err(format!("Failed to write gid_map: {}", e).into());
+    match status {
+        WaitStatus::Exited(_, 0) => Ok(()),
+        status => Err(format!("Process in the user namespace failed: {:?}", status).into()),
     }
+}
+
+/// Finds the first range owned by `name` (or the numeric `uid`) in a
+/// `subuid` or `subgid` file.
+fn subordinate_range(file: &str, name: &str, uid: u32) -> Option<IdRange> {
+    file.lines().find_map(|line| {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        let [owner, start, count] = fields[..] else {
+            return None;
+        };
+        if owner != name && owner.parse() != Ok(uid) {
+            return None;
+        }
+        let range = IdRange {
+            start: start.parse().ok()?,
+            count: count.parse().ok()?,
+        };
+        (range.count > 0).then_some(range)
+    })
+}
+
+/// Whether `program` is on `PATH`.
+fn has_program(program: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
 
+fn run_id_mapper(program: &str, pid: i32, map: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let status = Command::new(program)
+        .arg(pid.to_string())
+        .args(map.split_whitespace())
+        .status()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("{} failed with {}", program, status).into());
+    }
     Ok(())
 }
 
@@ -105,4 +303,72 @@ mod tests {
         assert!(uid <= 65535);
         assert!(gid <= 65535);
     }
+
+    #[test]
+    fn test_subordinate_range() {
+        let file = "alice:100000:65536\n1001:200000:65536\nbob:300000:0\n";
+
+        assert_eq!(
+            subordinate_range(file, "alice", 1000),
+            Some(IdRange {
+                start: 100000,
+                count: 65536
+            })
+        );
+        assert_eq!(
+            subordinate_range(file, "carol", 1001),
+            Some(IdRange {
+                start: 200000,
+                count: 65536
+            })
+        );
+        assert_eq!(subordinate_range(file, "bob", 1002), None);
+        assert_eq!(subordinate_range("garbage\n", "alice", 1000), None);
+    }
+
+    #[test]
+    fn test_id_mapping_lines() {
+        let subordinate = IdMapping::Subordinate {
+            uid: 1000,
+            gid: 100,
+            uids: IdRange {
+                start: 100000,
+                count: 65536,
+            },
+            gids: IdRange {
+                start: 200000,
+                count: 65536,
+            },
+        };
+
+        assert_eq!(IdMapping::Identity.uid_map(), "0 0 4294967295");
+        assert_eq!(subordinate.uid_map(), "0 1000 1\n1 100000 65536");
+        assert_eq!(subordinate.gid_map(), "0 100 1\n1 200000 65536");
+        assert_eq!(
+            IdMapping::Single {
+                uid: 1000,
+                gid: 100
+            }
+            .gid_map(),
+            "0 100 1"
+        );
+    }
+
+    #[test]
+    fn test_in_user_namespace_runs_as_root() {
+        in_user_namespace(|| {
+            let uid_map = fs::read_to_string("/proc/self/uid_map")?;
+            if getuid().is_root()
+                && uid_map
+                    .split_whitespace()
+                    .eq(IdMapping::current().uid_map().split_whitespace())
+            {
+                Ok(())
+            } else {
+                Err(std::io::Error::other(uid_map))
+            }
+        })
+        .unwrap();
+        assert!(in_user_namespace(|| Err(std::io::Error::other("failed"))).is_err());
+    }
 }
@@ -0,0 +1,412 @@
+use crate::idmap::open_tree;
+use anyhow::{Context, Result};
+use nix::mount::{MntFlags, MsFlags, mount, umount2};
+use nix::sched::{CloneFlags, setns, unshare};
+use std::fs::File;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// First kernel release that allows overlayfs mounts inside a user
+/// namespace, via the `userxattr` option.
+const USERNS_OVERLAY_KERNEL: (u32, u32) = (5, 11);
+
+/// How the merged rootfs gets mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayDriver {
+    /// In-kernel overlayfs, mounted with `userxattr`.
+    Kernel,
+    /// A `fuse-overlayfs` daemon, for kernels without user namespace support.
+    Fuse,
+}
+
+/// Directory of short links to the lower dirs, named by position.
+const LINKS_DIR: &str = "l";
+
+/// Directories making up a container's overlay. The upper, work and merged
+/// dirs live in the container's state directory so the lower dirs are never
+/// written to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverlayDirs {
+    /// The container's state directory. The overlay is mounted from here so
+    /// `lowerdir=` can name the lower dirs through short relative links:
+    /// the kernel takes at most one page of mount data, which an image
+    /// with many layers would overflow with full paths.
+    pub dir: PathBuf,
+    /// Read-only layers, ordered bottom to top.
+    pub lower: Vec<PathBuf>,
+    pub upper: PathBuf,
+    pub work: PathBuf,
+    pub merged: PathBuf,
+}
+
+impl OverlayDirs {
+    pub fn new(container_dir: &Path, lower: Vec<PathBuf>) -> Self {
+        Self {
+            dir: container_dir.to_path_buf(),
+            lower,
+            upper: container_dir.join("upper"),
+            work: container_dir.join("work"),
+            merged: container_dir.join("merged"),
+        }
+    }
+
+    pub fn create(&self) -> Result<()> {
+        for dir in [&self.upper, &self.work, &self.merged] {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create overlay directory {:?}", dir))?;
+        }
+
+        let links = self.dir.join(LINKS_DIR);
+        std::fs::create_dir_all(&links)
+            .with_context(|| format!("Failed to create overlay directory {:?}", links))?;
+        for (i, lower) in self.lower.iter().enumerate() {
+            let link = links.join(i.to_string());
+            std::os::unix::fs::symlink(lower, &link)
+                .with_context(|| format!("Failed to link {:?} to {:?}", link, lower))?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds the overlay mount data, relative to `dirs.dir`. `lowerdir=` lists
+/// layers top first, the reverse of how `dirs.lower` is stored.
+pub fn mount_options(dirs: &OverlayDirs) -> Result<String> {
+    if dirs.lower.is_empty() {
+        anyhow::bail!("Overlay needs at least one lower directory");
+    }
+
+    for path in [&dirs.upper, &dirs.work] {
+        let s = path.to_string_lossy();
+        if s.contains(':') || s.contains(',') {
+            anyhow::bail!("Overlay paths must not contain ':' or ',': {:?}", path);
+        }
+    }
+
+    let lower = (0..dirs.lower.len())
+        .rev()
+        .map(|i| format!("{}/{}", LINKS_DIR, i))
+        .collect::<Vec<_>>()
+        .join(":");
+
+    Ok(format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower,
+        dirs.upper.display(),
+        dirs.work.display()
+    ))
+}
+
+/// Mounts the overlay as the container's user namespace `userns` and
+/// returns it as a detached tree for the container to attach at
+/// `dirs.merged`.
+///
+/// This changes the namespaces of the calling process, so it runs in a
+/// helper forked for the purpose. The helper joins only the container's
+/// user namespace, not its PID namespace, and mounts in a mount namespace
+/// of its own: a `fuse-overlayfs` daemon it starts is then out of the
+/// container's reach, as are the daemon's descriptors into the host's
+/// mounts, and it opens `/dev/fuse` outside the container's cgroup.
+pub fn mount_detached_overlay(dirs: &OverlayDirs, userns: &File) -> Result<OwnedFd> {
+    setns(userns, CloneFlags::CLONE_NEWUSER)
+        .context("Failed to join the container's user namespace")?;
+    unshare(CloneFlags::CLONE_NEWNS).context("Failed to create a mount namespace")?;
+    // Keep the overlay from propagating back to the host.
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )
+    .context("Failed to make / a private mount")?;
+
+    let driver = mount_overlay(dirs)?;
+    println!("Mounted overlay rootfs using {:?} driver", driver);
+    let tree = open_tree(&dirs.merged)?;
+    // Only the container's copy may keep the overlay, and with it a
+    // fuse-overlayfs daemon, alive.
+    umount2(&dirs.merged, MntFlags::MNT_DETACH)
+        .with_context(|| format!("Failed to unmount {:?}", dirs.merged))?;
+    Ok(tree)
+}
+
+/// Mounts the merged view of the lower dirs and the container's upper dir
+/// at `dirs.merged`. This always runs inside the container's user namespace,
+/// so the kernel driver is only tried where the kernel supports that, and
+/// `fuse-overlayfs` is used otherwise or when the kernel mount is refused.
+fn mount_overlay(dirs: &OverlayDirs) -> Result<OverlayDriver> {
+    let fuse = find_in_path("fuse-overlayfs");
+
+    match select_driver(kernel_version(), fuse.is_some())? {
+        OverlayDriver::Kernel => match mount_kernel_overlay(dirs) {
+            Ok(()) => Ok(OverlayDriver::Kernel),
+            Err(e) => match &fuse {
+                Some(fuse) => {
+                    eprintln!("Kernel overlay failed ({:#}), using fuse-overlayfs", e);
+                    mount_fuse_overlay(fuse, dirs)?;
+                    Ok(OverlayDriver::Fuse)
+                }
+                None => Err(e),
+            },
+        },
+        OverlayDriver::Fuse => {
+            // select_driver only picks Fuse when the binary was found.
+            let fuse = fuse.context("fuse-overlayfs not found")?;
+            mount_fuse_overlay(&fuse, dirs)?;
+            Ok(OverlayDriver::Fuse)
+        }
+    }
+}
+
+/// Picks the overlay driver for a kernel `(major, minor)` release.
+pub fn select_driver(kernel: Option<(u32, u32)>, fuse_available: bool) -> Result<OverlayDriver> {
+    let kernel_supported = kernel.is_some_and(|version| version >= USERNS_OVERLAY_KERNEL);
+
+    if kernel_supported {
+        Ok(OverlayDriver::Kernel)
+    } else if fuse_available {
+        Ok(OverlayDriver::Fuse)
+    } else {
+        anyhow::bail!(
+            "Overlay inside a user namespace needs Linux {}.{}+ or fuse-overlayfs in PATH",
+            USERNS_OVERLAY_KERNEL.0,
+            USERNS_OVERLAY_KERNEL.1
+        )
+    }
+}
+
+fn mount_kernel_overlay(dirs: &OverlayDirs) -> Result<()> {
+    // Unprivileged mounts cannot write trusted.* xattrs, so overlay metadata
+    // goes into user.* instead.
+    let options = format!("{},userxattr", mount_options(dirs)?);
+
+    let cwd = std::env::current_dir().context("Failed to get current directory")?;
+    std::env::set_current_dir(&dirs.dir)
+        .with_context(|| format!("Failed to change to {:?}", dirs.dir))?;
+    let result = mount(
+        Some("overlay"),
+        &dirs.merged,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(options.as_str()),
+    )
+    .with_context(|| format!("Failed to mount overlay at {:?}", dirs.merged));
+    std::env::set_current_dir(&cwd).with_context(|| format!("Failed to change to {:?}", cwd))?;
+
+    result
+}
+
+/// Starts `fuse-overlayfs`, which daemonizes once the mount is ready. The
+/// daemon exits once the container's mount namespace, the last one holding
+/// the overlay, goes away.
+fn mount_fuse_overlay(fuse: &Path, dirs: &OverlayDirs) -> Result<()> {
+    let options = mount_options(dirs)?;
+
+    let status = Command::new(fuse)
+        .current_dir(&dirs.dir)
+        .arg("-o")
+        .arg(&options)
+        .arg(&dirs.merged)
+        .status()
+        .with_context(|| format!("Failed to run {:?}", fuse))?;
+
+    if !status.success() {
+        anyhow::bail!(
+            "fuse-overlayfs failed to mount {:?}: {}",
+            dirs.merged,
+            status
+        );
+    }
+
+    Ok(())
+}
+
+/// Running kernel's `(major, minor)` release.
+pub fn kernel_version() -> Option<(u32, u32)> {
+    let release = std::fs::read_to_string("/proc/sys/kernel/osrelease").ok()?;
+    parse_kernel_version(&release)
+}
+
+fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.trim().split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+fn find_in_path(binary: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(binary))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::idmap::owner_userns;
+    use nix::fcntl::{OFlag, openat};
+    use nix::sys::stat::Mode;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, Gid, Uid, fork};
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn test_overlay_dirs_layout() {
+        let dirs = OverlayDirs::new(
+            Path::new("/var/lib/docker-clone/containers/abc"),
+            vec![PathBuf::from("/rootfs")],
+        );
+
+        assert_eq!(
+            dirs.upper,
+            PathBuf::from("/var/lib/docker-clone/containers/abc/upper")
+        );
+        assert_eq!(
+            dirs.work,
+            PathBuf::from("/var/lib/docker-clone/containers/abc/work")
+        );
+        assert_eq!(
+            dirs.merged,
+            PathBuf::from("/var/lib/docker-clone/containers/abc/merged")
+        );
+    }
+
+    #[test]
+    fn test_mount_options_orders_lower_dirs_top_first() {
+        let lower = vec![PathBuf::from("/rootfs"), PathBuf::from("/layer1")];
+        let dirs = OverlayDirs::new(Path::new("/state/abc"), lower);
+
+        let options = mount_options(&dirs).unwrap();
+
+        assert_eq!(
+            options,
+            "lowerdir=l/1:l/0,upperdir=/state/abc/upper,workdir=/state/abc/work"
+        );
+    }
+
+    #[test]
+    fn test_mount_options_fit_in_a_page_for_many_layers() {
+        let lower = (0..500)
+            .map(|i| PathBuf::from(format!("/var/lib/docker-clone/images/layers/{:064x}", i)))
+            .collect();
+        let dirs = OverlayDirs::new(Path::new("/var/lib/docker-clone/containers/abc"), lower);
+
+        assert!(mount_options(&dirs).unwrap().len() < 4096);
+    }
+
+    #[test]
+    fn test_mount_options_rejects_bad_input() {
+        let lower = vec![PathBuf::from("/rootfs")];
+
+        assert!(mount_options(&OverlayDirs::new(Path::new("/state/abc"), vec![])).is_err());
+        for state in ["/state/a:b", "/state/a,b"] {
+            assert!(mount_options(&OverlayDirs::new(Path::new(state), lower.clone())).is_err());
+        }
+        // Lower dirs are only named through links.
+        let lower = vec![PathBuf::from("/root:fs,1")];
+        assert!(mount_options(&OverlayDirs::new(Path::new("/state/abc"), lower)).is_ok());
+    }
+
+    #[test]
+    fn test_create_overlay_dirs() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let lower = vec![PathBuf::from("/rootfs"), PathBuf::from("/layer1")];
+        let dirs = OverlayDirs::new(temp_dir.path(), lower);
+
+        dirs.create().unwrap();
+
+        assert!(dirs.upper.is_dir());
+        assert!(dirs.work.is_dir());
+        assert!(dirs.merged.is_dir());
+        let links = temp_dir.path().join(LINKS_DIR);
+        assert_eq!(
+            std::fs::read_link(links.join("0")).unwrap(),
+            PathBuf::from("/rootfs")
+        );
+        assert_eq!(
+            std::fs::read_link(links.join("1")).unwrap(),
+            PathBuf::from("/layer1")
+        );
+    }
+
+    #[test]
+    fn test_mount_detached_overlay() {
+        if !Uid::effective().is_root() {
+            eprintln!("skipping: joining another user namespace needs root");
+            return;
+        }
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let lower = temp_dir.path().join("rootfs");
+        std::fs::create_dir(&lower).unwrap();
+        std::fs::write(lower.join("file"), "lower").unwrap();
+        let dirs = OverlayDirs::new(&temp_dir.path().join("state"), vec![lower.clone()]);
+        dirs.create().unwrap();
+        let root = (Uid::from_raw(0), Gid::from_raw(0));
+        let userns = owner_userns(&lower, root.0, root.1).unwrap();
+
+        // Joining the namespaces would change them for the other tests.
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = match mount_detached_overlay(&dirs, &userns) {
+                    Ok(tree) => {
+                        let file = openat(tree.as_raw_fd(), "file", OFlag::O_RDONLY, Mode::empty());
+                        let merged_is_empty = std::fs::read_dir(&dirs.merged)
+                            .is_ok_and(|mut entries| entries.next().is_none());
+                        match file {
+                            Ok(_) if merged_is_empty => 0,
+                            Ok(_) => 1,
+                            Err(_) => 2,
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("skipping: {:#}", e);
+                        3
+                    }
+                };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                let status = waitpid(child, None).unwrap();
+                assert!(
+                    matches!(status, WaitStatus::Exited(_, 0 | 3)),
+                    "{:?}",
+                    status
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_kernel_version() {
+        assert_eq!(parse_kernel_version("5.11.0-41-generic\n"), Some((5, 11)));
+        assert_eq!(parse_kernel_version("6.1.55"), Some((6, 1)));
+        assert_eq!(parse_kernel_version("4.19-rc1"), Some((4, 19)));
+        assert_eq!(parse_kernel_version("garbage"), None);
+    }
+
+    #[test]
+    fn test_select_driver() {
+        assert_eq!(
+            select_driver(Some((5, 11)), false).unwrap(),
+            OverlayDriver::Kernel
+        );
+        assert_eq!(
+            select_driver(Some((6, 2)), true).unwrap(),
+            OverlayDriver::Kernel
+        );
+        assert_eq!(
+            select_driver(Some((5, 10)), true).unwrap(),
+            OverlayDriver::Fuse
+        );
+        assert_eq!(select_driver(None, true).unwrap(), OverlayDriver::Fuse);
+        assert!(select_driver(Some((4, 19)), false).is_err());
+    }
+
+    #[test]
+    fn test_find_in_path() {
+        assert!(find_in_path("sh").is_some());
+        assert!(find_in_path("docker-clone-no-such-binary").is_none());
+    }
+}
@@ -1,9 +1,68 @@
+use crate::config::ContainerConfig;
+use crate::devices::setup_devices;
+use crate::mounts::{mount_sysfs, setup_mounts};
 use anyhow::{Context, Result};
 use nix::mount::{MntFlags, MsFlags, mount, umount2};
+use nix::sys::statvfs::{FsFlags, statvfs};
 use nix::unistd::chdir;
-use std::path::Path;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-pub fn setup_rootfs(rootfs: impl AsRef<Path>) -> Result<()> {
+/// Propagation applied to the container's mounts before pivoting, as in
+/// the OCI `rootfsPropagation` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Propagation {
+    Private,
+    #[default]
+    RPrivate,
+    Slave,
+    RSlave,
+    Shared,
+    RShared,
+}
+
+impl Propagation {
+    pub fn flags(self) -> MsFlags {
+        match self {
+            Propagation::Private => MsFlags::MS_PRIVATE,
+            Propagation::RPrivate => MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+            Propagation::Slave => MsFlags::MS_SLAVE,
+            Propagation::RSlave => MsFlags::MS_SLAVE | MsFlags::MS_REC,
+            Propagation::Shared => MsFlags::MS_SHARED,
+            Propagation::RShared => MsFlags::MS_SHARED | MsFlags::MS_REC,
+        }
+    }
+
+    fn is_shared(self) -> bool {
+        matches!(self, Propagation::Shared | Propagation::RShared)
+    }
+}
+
+impl FromStr for Propagation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "private" => Ok(Propagation::Private),
+            "rprivate" => Ok(Propagation::RPrivate),
+            "slave" => Ok(Propagation::Slave),
+            "rslave" => Ok(Propagation::RSlave),
+            "shared" => Ok(Propagation::Shared),
+            "rshared" => Ok(Propagation::RShared),
+            _ => anyhow::bail!(
+                "Invalid propagation '{}', expected private, rprivate, slave, rslave, shared or rshared",
+                s
+            ),
+        }
+    }
+}
+
+pub fn setup_rootfs(
+    rootfs: impl AsRef<Path>,
+    config: &ContainerConfig,
+    idmapped: Vec<OwnedFd>,
+) -> Result<()> {
     let rootfs = rootfs.as_ref();
 
     let rootfs = if rootfs.is_absolute() {
@@ -20,14 +79,30 @@ pub fn setup_rootfs(rootfs: impl AsRef<Path>) -> Result<()> {
 
     println!("Setting up rootfs at: {:?}", rootfs);
 
+    let propagation = config.rootfs_propagation;
     mount(
         None::<&str>,
         "/",
         None::<&str>,
-        MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+        propagation.flags(),
         None::<&str>,
     )
-    .context("Failed to make / a private mount")?;
+    .with_context(|| format!("Failed to set {:?} propagation on /", propagation))?;
+
+    // pivot_root refuses a new root whose parent mount is shared.
+    if propagation.is_shared() {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+            .context("Failed to read /proc/self/mountinfo")?;
+        let parent = parent_mount(&rootfs, &mountinfo);
+        mount(
+            None::<&str>,
+            &parent,
+            None::<&str>,
+            MsFlags::MS_PRIVATE,
+            None::<&str>,
+        )
+        .with_context(|| format!("Failed to make {:?} a private mount", parent))?;
+    }
 
     mount(
         Some(rootfs.as_path()),
@@ -38,6 +113,17 @@ pub fn setup_rootfs(rootfs: impl AsRef<Path>) -> Result<()> {
     )
     .with_context(|| format!("Failed to bind mount at {:?}", rootfs))?;
 
+    mount_sysfs(&rootfs)?;
+    let mount_label = config.selinux_label.as_ref().map(|l| l.mount_label());
+    setup_mounts(
+        &rootfs,
+        &config.volumes,
+        &config.tmpfs,
+        idmapped,
+        mount_label.as_deref(),
+    )?;
+    setup_devices(&rootfs, &config.devices)?;
+
     let old_root = rootfs.join(".old_root");
     std::fs::create_dir_all(&old_root)
         .with_context(|| format!("Failed to create old_root at {:?}", old_root))?;
@@ -48,13 +134,128 @@ pub fn setup_rootfs(rootfs: impl AsRef<Path>) -> Result<()> {
 
     chdir("/").context("Failed to chdir to /")?;
 
+    // Keep the unmount of the old root from propagating to its peers.
+    mount(
+        None::<&str>,
+        "/.old_root",
+        None::<&str>,
+        MsFlags::MS_SLAVE | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .context("Failed to make old root a slave mount")?;
+
     umount2("/.old_root", MntFlags::MNT_DETACH).context("Failed to unmount old root")?;
 
     std::fs::remove_dir_all("/.old_root").context("Failed to remove old_root directory")?;
 
+    if propagation.is_shared() {
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            propagation.flags(),
+            None::<&str>,
+        )
+        .context("Failed to make the new root shared")?;
+    }
+
     Ok(())
 }
 
+/// Finds the mount point containing `path`, given `/proc/self/mountinfo`.
+fn parent_mount(path: &Path, mountinfo: &str) -> PathBuf {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .map(|mount_point| PathBuf::from(unescape_mountinfo(mount_point)))
+        .filter(|mount_point| path.starts_with(mount_point))
+        .max_by_key(|mount_point| mount_point.components().count())
+        .unwrap_or_else(|| PathBuf::from("/"))
+}
+
+/// Lists `path` and every mount point below it, parents before children,
+/// given `/proc/self/mountinfo`.
+fn mounts_under(path: &Path, mountinfo: &str) -> Vec<PathBuf> {
+    let mut mount_points: Vec<PathBuf> = Vec::new();
+    for mount_point in mountinfo
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .map(|mount_point| PathBuf::from(unescape_mountinfo(mount_point)))
+    {
+        if mount_point.starts_with(path) && !mount_points.contains(&mount_point) {
+            mount_points.push(mount_point);
+        }
+    }
+    mount_points
+}
+
+/// mountinfo escapes whitespace and backslashes as `\ooo` octal sequences.
+fn unescape_mountinfo(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let octal: String = chars.by_ref().take(3).collect();
+            match u8::from_str_radix(&octal, 8) {
+                Ok(byte) => out.push(byte as char),
+                Err(_) => {
+                    out.push(c);
+                    out.push_str(&octal);
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Remounts the bind mount at `path` read-only. Only that mount is affected,
+/// so volumes, tmpfs and `/proc` mounted below it stay writable.
+pub fn remount_readonly(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+
+    // Inside a user namespace the kernel refuses to drop flags that were
+    // locked by the parent mount, so carry the current ones over.
+    let current = statvfs(path).with_context(|| format!("Failed to statvfs {:?}", path))?;
+    let flags =
+        locked_flags(current.flags()) | MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+
+    mount(None::<&str>, path, None::<&str>, flags, None::<&str>)
+        .with_context(|| format!("Failed to remount {:?} read-only", path))?;
+
+    Ok(())
+}
+
+/// Remounts `path` and every mount below it read-only. A recursive bind
+/// copies submounts with their own flags, so remounting only the top one
+/// would leave them writable.
+pub fn remount_tree_readonly(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+        .context("Failed to read /proc/self/mountinfo")?;
+    for mount_point in mounts_under(path, &mountinfo) {
+        remount_readonly(&mount_point)?;
+    }
+    Ok(())
+}
+
+fn locked_flags(current: FsFlags) -> MsFlags {
+    let mapping = [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+
+    mapping
+        .iter()
+        .filter(|(st, _)| current.contains(*st))
+        .fold(MsFlags::empty(), |acc, (_, ms)| acc | *ms)
+}
+
 fn pivot_root<P1: ?Sized + nix::NixPath, P2: ?Sized + nix::NixPath>(
     new_root: &P1,
     put_old: &P2,
@@ -69,3 +270,97 @@ fn pivot_root<P1: ?Sized + nix::NixPath, P2: ?Sized + nix::NixPath>(
     Errno::result(res).map(drop)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locked_flags_are_preserved() {
+        let flags = locked_flags(FsFlags::ST_NOSUID | FsFlags::ST_NODEV | FsFlags::ST_RELATIME);
+
+        assert_eq!(
+            flags,
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_RELATIME
+        );
+    }
+
+    #[test]
+    fn test_parse_propagation() {
+        assert_eq!(
+            "rslave".parse::<Propagation>().unwrap(),
+            Propagation::RSlave
+        );
+        assert_eq!(
+            "shared".parse::<Propagation>().unwrap(),
+            Propagation::Shared
+        );
+        assert!("rshared-ish".parse::<Propagation>().is_err());
+        assert_eq!(Propagation::default(), Propagation::RPrivate);
+    }
+
+    #[test]
+    fn test_propagation_flags() {
+        assert_eq!(
+            Propagation::RPrivate.flags(),
+            MsFlags::MS_PRIVATE | MsFlags::MS_REC
+        );
+        assert_eq!(Propagation::Slave.flags(), MsFlags::MS_SLAVE);
+        assert_eq!(
+            Propagation::RShared.flags(),
+            MsFlags::MS_SHARED | MsFlags::MS_REC
+        );
+    }
+
+    #[test]
+    fn test_parent_mount() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+30 22 8:2 / /var/lib rw,relatime shared:2 - ext4 /dev/sda2 rw
+31 22 0:5 / /var/lib2 rw - tmpfs tmpfs rw
+32 30 0:6 / /var/lib/my\\040dir rw - tmpfs tmpfs rw";
+
+        assert_eq!(
+            parent_mount(Path::new("/var/lib/docker-clone/rootfs"), mountinfo),
+            PathBuf::from("/var/lib")
+        );
+        assert_eq!(
+            parent_mount(Path::new("/var/lib2/rootfs"), mountinfo),
+            PathBuf::from("/var/lib2")
+        );
+        assert_eq!(
+            parent_mount(Path::new("/var/lib/my dir/rootfs"), mountinfo),
+            PathBuf::from("/var/lib/my dir")
+        );
+        assert_eq!(
+            parent_mount(Path::new("/home/rootfs"), mountinfo),
+            PathBuf::from("/")
+        );
+    }
+
+    #[test]
+    fn test_mounts_under() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:20 / /rootfs/sys ro - sysfs sysfs rw
+41 40 0:21 / /rootfs/sys/fs/cgroup rw - cgroup2 cgroup2 rw
+42 40 0:22 / /rootfs/sys/kernel/my\\040debug rw - debugfs debugfs rw
+43 41 0:23 / /rootfs/sys/fs/cgroup rw - tmpfs tmpfs rw
+44 22 0:24 / /rootfs/system rw - tmpfs tmpfs rw";
+
+        assert_eq!(
+            mounts_under(Path::new("/rootfs/sys"), mountinfo),
+            [
+                PathBuf::from("/rootfs/sys"),
+                PathBuf::from("/rootfs/sys/fs/cgroup"),
+                PathBuf::from("/rootfs/sys/kernel/my debug"),
+            ]
+        );
+        assert!(mounts_under(Path::new("/rootfs/proc"), mountinfo).is_empty());
+    }
+
+    #[test]
+    fn test_locked_flags_ignores_rdonly() {
+        assert_eq!(locked_flags(FsFlags::ST_RDONLY), MsFlags::empty());
+        assert_eq!(locked_flags(FsFlags::empty()), MsFlags::empty());
+    }
+}
@@ -0,0 +1,335 @@
+use anyhow::{Context, Result, bail};
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::unistd::{Gid, Uid, setgroups, setresgid, setresuid};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// `PATH` for containers whose image or command line sets none, as Docker
+/// uses.
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// The command line to execute: the entrypoint followed by the command.
+///
+/// `--entrypoint` replaces the image's entrypoint and drops its `Cmd` (an
+/// empty value clears the entrypoint); a command given on the command line
+/// replaces the image's `Cmd`.
+pub fn command_line(
+    entrypoint: Option<String>,
+    command: Vec<String>,
+    image_entrypoint: Option<Vec<String>>,
+    image_cmd: Option<Vec<String>>,
+) -> Result<Vec<String>> {
+    let (mut argv, default_cmd) = match entrypoint {
+        Some(entrypoint) if entrypoint.is_empty() => (Vec::new(), Vec::new()),
+        Some(entrypoint) => (vec![entrypoint], Vec::new()),
+        None => (
+            image_entrypoint.unwrap_or_default(),
+            image_cmd.unwrap_or_default(),
+        ),
+    };
+    if command.is_empty() {
+        argv.extend(default_cmd);
+    } else {
+        argv.extend(command);
+    }
+    if argv.is_empty() {
+        bail!("No command specified and the image has no default command");
+    }
+    Ok(argv)
+}
+
+/// Adds `overrides` to `env`, replacing entries for the same variable. An
+/// override without `=` copies the variable from the host, or is dropped if
+/// the host does not have it.
+pub fn merge_env(env: &mut Vec<String>, overrides: &[String]) {
+    for entry in overrides {
+        let entry = match entry.split_once('=') {
+            Some(_) => entry.clone(),
+            None => match std::env::var(entry) {
+                Ok(value) => format!("{}={}", entry, value),
+                Err(_) => continue,
+            },
+        };
+        let key = entry.split('=').next().unwrap_or_default();
+        env.retain(|e| e.split('=').next() != Some(key));
+        env.push(entry);
+    }
+}
+
+/// The container's environment, with the default `PATH` if it sets none.
+pub fn container_env(env: &[String]) -> Vec<String> {
+    let mut env = env.to_vec();
+    if !env.iter().any(|e| e.starts_with("PATH=")) {
+        env.insert(0, format!("PATH={}", DEFAULT_PATH));
+    }
+    env
+}
+
+/// Parses a signal given as `SIGTERM`, `TERM` or `15`.
+pub fn parse_signal(s: &str) -> Result<Signal> {
+    if let Ok(number) = s.parse::<i32>() {
+        return Signal::try_from(number).with_context(|| format!("Invalid signal number {}", s));
+    }
+    let name = s.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).with_context(|| format!("Unknown signal {:?}", s))
+}
+
+/// A `--user` value: `user[:group]`, each by name or number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub user: String,
+    pub group: Option<String>,
+}
+
+impl FromStr for User {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group.to_string())),
+            None => (s, None),
+        };
+        if user.is_empty() || group.as_deref() == Some("") {
+            bail!("Invalid user {:?}: expected USER[:GROUP]", s);
+        }
+        Ok(User {
+            user: user.to_string(),
+            group,
+        })
+    }
+}
+
+/// Finds `name` in the first field of an `/etc/passwd` or `/etc/group` style
+/// file and returns its fields.
+fn lookup<'a>(file: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    file.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 4 && fields[0] == name)
+}
+
+impl User {
+    /// Resolves names against the container's `passwd` and `group` files.
+    /// Without a group, the user's primary group from `passwd` is used, or
+    /// the group with the same number for unknown numeric users.
+    pub fn resolve(&self, passwd: &str, group: &str) -> Result<(Uid, Gid)> {
+        let entry = lookup(passwd, &self.user).or_else(|| {
+            passwd
+                .lines()
+                .map(|line| line.split(':').collect::<Vec<_>>())
+                .find(|fields| fields.len() >= 4 && fields[2] == self.user)
+        });
+        let uid: u32 = match (&entry, self.user.parse()) {
+            (_, Ok(uid)) => uid,
+            (Some(fields), Err(_)) => fields[2]
+                .parse()
+                .with_context(|| format!("Invalid uid for user {}", self.user))?,
+            (None, Err(_)) => bail!("Unable to find user {} in /etc/passwd", self.user),
+        };
+
+        let gid: u32 = match &self.group {
+            Some(name) => match name.parse() {
+                Ok(gid) => gid,
+                Err(_) => lookup(group, name)
+                    .with_context(|| format!("Unable to find group {} in /etc/group", name))?[2]
+                    .parse()
+                    .with_context(|| format!("Invalid gid for group {}", name))?,
+            },
+            None => match &entry {
+                Some(fields) => fields[3]
+                    .parse()
+                    .with_context(|| format!("Invalid gid for user {}", self.user))?,
+                None => uid,
+            },
+        };
+        Ok((Uid::from_raw(uid), Gid::from_raw(gid)))
+    }
+}
+
+fn read_optional(path: &str) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path)),
+    }
+}
+
+/// Whether `id` falls inside one of the ranges of a `uid_map` or `gid_map`.
+fn is_mapped(map: &str, id: u32) -> bool {
+    map.lines().any(|line| {
+        let fields: Vec<u64> = line
+            .split_whitespace()
+            .filter_map(|f| f.parse().ok())
+            .collect();
+        matches!(fields[..], [inside, _, count] if (inside..inside + count).contains(&(id as u64)))
+    })
+}
+
+/// Resolves `user` against the container's `passwd` and `group` files once
+/// its root is in place, and checks both ids are mapped into its user
+/// namespace. This is done before Landlock is applied, since the ruleset
+/// may not allow `/etc` or `/proc`.
+///
+/// A user or group missing from the container's user namespace is rejected
+/// rather than silently running as root.
+pub fn resolve_user(user: &User) -> Result<(Uid, Gid)> {
+    let (uid, gid) = user.resolve(
+        &read_optional("/etc/passwd")?,
+        &read_optional("/etc/group")?,
+    )?;
+    if !is_mapped(&fs::read_to_string("/proc/self/uid_map")?, uid.as_raw()) {
+        bail!(
+            "User {} is not mapped into the container's user namespace; give the invoking user a range in /etc/subuid (newuidmap must be installed) or choose another --user",
+            uid
+        );
+    }
+    if !is_mapped(&fs::read_to_string("/proc/self/gid_map")?, gid.as_raw()) {
+        bail!(
+            "Group {} is not mapped into the container's user namespace; give the invoking user a range in /etc/subgid (newgidmap must be installed) or choose another --user",
+            gid
+        );
+    }
+    Ok((uid, gid))
+}
+
+/// Switches to the user from [`resolve_user`], after pivoting into the
+/// container's rootfs and dropping the bounding set. The permitted set is
+/// kept across the switch, as runc does; a non-root user still loses it at
+/// exec. Supplementary groups inherited from the runtime are dropped where
+/// the namespace allows it; with a single mapped id, it does not.
+pub fn switch_user(uid: Uid, gid: Gid) -> Result<()> {
+    match setgroups(&[]) {
+        Ok(()) | Err(Errno::EPERM) => {}
+        Err(e) => return Err(e).context("Failed to clear supplementary groups"),
+    }
+    set_keepcaps(true)?;
+    setresgid(gid, gid, gid).with_context(|| format!("Failed to switch to group {}", gid))?;
+    setresuid(uid, uid, uid).with_context(|| format!("Failed to switch to user {}", uid))?;
+    set_keepcaps(false)
+}
+
+fn set_keepcaps(keep: bool) -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, keep as libc::c_ulong, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to set PR_SET_KEEPCAPS");
+    }
+    Ok(())
+}
+
+/// Changes into the working directory, creating it first if the image does
+/// not have it.
+pub fn enter_working_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create working directory {:?}", dir))?;
+    std::env::set_current_dir(dir)
+        .with_context(|| format!("Failed to change to working directory {:?}", dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_command_line_merges_image_defaults() {
+        let entrypoint = Some(strings(&["/entrypoint.sh"]));
+        let cmd = Some(strings(&["postgres"]));
+
+        assert_eq!(
+            command_line(None, vec![], entrypoint.clone(), cmd.clone()).unwrap(),
+            ["/entrypoint.sh", "postgres"]
+        );
+        assert_eq!(
+            command_line(
+                None,
+                strings(&["psql", "-l"]),
+                entrypoint.clone(),
+                cmd.clone()
+            )
+            .unwrap(),
+            ["/entrypoint.sh", "psql", "-l"]
+        );
+        assert_eq!(
+            command_line(
+                Some("/bin/sh".to_string()),
+                vec![],
+                entrypoint.clone(),
+                cmd.clone()
+            )
+            .unwrap(),
+            ["/bin/sh"]
+        );
+        assert_eq!(
+            command_line(Some(String::new()), strings(&["id"]), entrypoint, cmd).unwrap(),
+            ["id"]
+        );
+        assert!(command_line(None, vec![], None, None).is_err());
+        assert!(command_line(Some(String::new()), vec![], None, Some(strings(&["sh"]))).is_err());
+    }
+
+    #[test]
+    fn test_merge_env_overrides_by_name() {
+        let mut env = strings(&["PATH=/bin", "LANG=C"]);
+        merge_env(
+            &mut env,
+            &strings(&["LANG=C.UTF-8", "EMPTY=", "DOCKER_CLONE_UNSET_VAR"]),
+        );
+        assert_eq!(env, ["PATH=/bin", "LANG=C.UTF-8", "EMPTY="]);
+
+        assert_eq!(container_env(&env), env);
+        assert_eq!(
+            container_env(&strings(&["A=1"])),
+            [format!("PATH={}", DEFAULT_PATH), "A=1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGQUIT").unwrap(), Signal::SIGQUIT);
+        assert_eq!(parse_signal("term").unwrap(), Signal::SIGTERM);
+        assert_eq!(parse_signal("9").unwrap(), Signal::SIGKILL);
+        assert!(parse_signal("SIGNOPE").is_err());
+        assert!(parse_signal("99").is_err());
+    }
+
+    #[test]
+    fn test_resolve_user() {
+        let passwd = "root:x:0:0:root:/root:/bin/sh\nnobody:x:65534:65534:nobody:/:/bin/false\n";
+        let group = "root:x:0:\nwheel:x:10:root\n";
+        let resolve = |spec: &str| {
+            let (uid, gid) = spec.parse::<User>()?.resolve(passwd, group)?;
+            Ok::<_, anyhow::Error>((uid.as_raw(), gid.as_raw()))
+        };
+
+        assert_eq!(resolve("root").unwrap(), (0, 0));
+        assert_eq!(resolve("nobody").unwrap(), (65534, 65534));
+        assert_eq!(resolve("65534").unwrap(), (65534, 65534));
+        assert_eq!(resolve("root:wheel").unwrap(), (0, 10));
+        assert_eq!(resolve("1000").unwrap(), (1000, 1000));
+        assert_eq!(resolve("1000:50").unwrap(), (1000, 50));
+        assert!(resolve("postgres").is_err());
+        assert!(resolve("root:staff").is_err());
+        assert!("root:".parse::<User>().is_err());
+        assert!(":0".parse::<User>().is_err());
+    }
+
+    #[test]
+    fn test_is_mapped() {
+        let map = "         0     100000          1\n      1000     101000      65536\n";
+        assert!(is_mapped(map, 0));
+        assert!(!is_mapped(map, 1));
+        assert!(is_mapped(map, 1000));
+        assert!(is_mapped(map, 66535));
+        assert!(!is_mapped(map, 66536));
+        assert!(!is_mapped("", 0));
+    }
+}
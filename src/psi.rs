@@ -0,0 +1,426 @@
+use anyhow::{Context, Result};
+use nix::poll::{PollFd, PollFlags, poll};
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Window limits the kernel accepts for PSI triggers.
+const MIN_WINDOW: Duration = Duration::from_millis(500);
+const MAX_WINDOW: Duration = Duration::from_secs(10);
+
+/// How often the monitor thread checks whether it should stop.
+const STOP_POLL_MS: i32 = 200;
+
+/// One line of a pressure file: the share of time some (or all) tasks were
+/// stalled, as percentages over 10s, 60s and 300s, plus the total stall
+/// time in microseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct PressureLine {
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    pub total: u64,
+}
+
+/// Contents of a `*.pressure` file. `cpu.pressure` has no `full` line on
+/// older kernels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Pressure {
+    pub some: PressureLine,
+    pub full: Option<PressureLine>,
+}
+
+impl FromStr for Pressure {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut pressure = Pressure::default();
+        let mut found_some = false;
+
+        for line in s.lines() {
+            let mut fields = line.split_whitespace();
+            let kind = fields.next();
+            let mut parsed = PressureLine::default();
+            for field in fields {
+                let (key, value) = field
+                    .split_once('=')
+                    .with_context(|| format!("Invalid pressure field {:?}", field))?;
+                let invalid = || format!("Invalid pressure value {:?}", field);
+                match key {
+                    "avg10" => parsed.avg10 = value.parse().with_context(invalid)?,
+                    "avg60" => parsed.avg60 = value.parse().with_context(invalid)?,
+                    "avg300" => parsed.avg300 = value.parse().with_context(invalid)?,
+                    "total" => parsed.total = value.parse().with_context(invalid)?,
+                    _ => {}
+                }
+            }
+
+            match kind {
+                Some("some") => {
+                    pressure.some = parsed;
+                    found_some = true;
+                }
+                Some("full") => pressure.full = Some(parsed),
+                _ => {}
+            }
+        }
+
+        if !found_some {
+            anyhow::bail!("Pressure data has no \"some\" line");
+        }
+        Ok(pressure)
+    }
+}
+
+/// The resources PSI reports on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Cpu,
+    Memory,
+    Io,
+}
+
+impl Resource {
+    /// The cgroup file reporting pressure for this resource.
+    pub fn file(&self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu.pressure",
+            Self::Memory => "memory.pressure",
+            Self::Io => "io.pressure",
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Cpu => "cpu",
+            Self::Memory => "memory",
+            Self::Io => "io",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Reads `resource`'s pressure file from `cgroup`.
+pub fn read_pressure(cgroup: &Path, resource: Resource) -> Result<Pressure> {
+    let path = cgroup.join(resource.file());
+    std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {:?}", path))?
+        .parse()
+}
+
+/// Fires when tasks were stalled on `resource` for at least `stall` within
+/// any `window`. Given on the command line as `RESOURCE[:full]=STALL/WINDOW`,
+/// for example `memory=150ms/1s` or `io:full=500ms/2s`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PressureTrigger {
+    pub resource: Resource,
+    /// Count only time where all tasks were stalled, not just some.
+    pub full: bool,
+    pub stall: Duration,
+    pub window: Duration,
+}
+
+impl PressureTrigger {
+    /// The string written to the pressure file to register the trigger.
+    pub fn registration(&self) -> String {
+        format!(
+            "{} {} {}",
+            if self.full { "full" } else { "some" },
+            self.stall.as_micros(),
+            self.window.as_micros()
+        )
+    }
+}
+
+impl FromStr for PressureTrigger {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (target, threshold) = s
+            .split_once('=')
+            .with_context(|| format!("Expected RESOURCE[:full]=STALL/WINDOW, got {:?}", s))?;
+        let (resource, full) = match target.split_once(':') {
+            Some((resource, "full")) => (resource, true),
+            Some((resource, "some")) => (resource, false),
+            Some((_, kind)) => {
+                anyhow::bail!("Unknown stall kind {:?} (expected some or full)", kind)
+            }
+            None => (target, false),
+        };
+        let resource = match resource {
+            "cpu" => Resource::Cpu,
+            "memory" => Resource::Memory,
+            "io" => Resource::Io,
+            _ => anyhow::bail!(
+                "Unknown resource {:?} (expected cpu, memory or io)",
+                resource
+            ),
+        };
+
+        let (stall, window) = threshold
+            .split_once('/')
+            .with_context(|| format!("Expected STALL/WINDOW, got {:?}", threshold))?;
+        let stall = parse_duration(stall)?;
+        let window = parse_duration(window)?;
+
+        if window < MIN_WINDOW || window > MAX_WINDOW {
+            anyhow::bail!("Pressure window must be between 500ms and 10s");
+        }
+        if stall.is_zero() || stall > window {
+            anyhow::bail!("Stall threshold must be positive and no longer than the window");
+        }
+
+        Ok(Self {
+            resource,
+            full,
+            stall,
+            window,
+        })
+    }
+}
+
+impl fmt::Display for PressureTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}={}ms/{}ms",
+            self.resource,
+            if self.full { ":full" } else { "" },
+            self.stall.as_millis(),
+            self.window.as_millis()
+        )
+    }
+}
+
+/// Parses durations like `500us`, `150ms` or `2s`.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .with_context(|| format!("Duration {:?} needs a unit (us, ms or s)", s))?;
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration {:?}", s))?;
+
+    match unit {
+        "us" => Ok(Duration::from_micros(number)),
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        _ => anyhow::bail!("Unknown duration unit in {:?}", s),
+    }
+}
+
+/// Background thread logging an event whenever one of a container's
+/// pressure triggers fires. Stops when dropped.
+pub struct PressureMonitor {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PressureMonitor {
+    /// Registers `triggers` on `cgroup`'s pressure files and starts watching
+    /// them. Registration errors (PSI disabled, or an unprivileged window
+    /// that isn't a multiple of 2s) are reported here rather than later.
+    pub fn start(id: &str, cgroup: &Path, triggers: &[PressureTrigger]) -> Result<Self> {
+        let mut watched = Vec::new();
+        for trigger in triggers {
+            watched.push((register(cgroup, trigger)?, trigger.clone()));
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let id = id.to_string();
+        let cgroup = cgroup.to_path_buf();
+        let thread = std::thread::spawn(move || {
+            watch(&id, &cgroup, &watched, &thread_stop);
+        });
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for PressureMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Opens the pressure file and writes the trigger. The trigger lives as
+/// long as the returned file stays open.
+fn register(cgroup: &Path, trigger: &PressureTrigger) -> Result<File> {
+    let path = cgroup.join(trigger.resource.file());
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+
+    // The kernel replaces the last byte written with a NUL, so include one.
+    let registration = format!("{}\0", trigger.registration());
+    match file.write_all(registration.as_bytes()) {
+        Ok(()) => Ok(file),
+        // Without CAP_SYS_RESOURCE the kernel only accepts whole multiples
+        // of 2s as the window.
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => anyhow::bail!(
+            "Pressure trigger {} was rejected; without CAP_SYS_RESOURCE the window must be a multiple of 2s",
+            trigger
+        ),
+        Err(e) => {
+            Err(e).with_context(|| format!("Failed to register pressure trigger {}", trigger))
+        }
+    }
+}
+
+fn watch(id: &str, cgroup: &Path, watched: &[(File, PressureTrigger)], stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        let mut fds: Vec<PollFd> = watched
+            .iter()
+            .map(|(file, _)| PollFd::new(file, PollFlags::POLLPRI))
+            .collect();
+
+        match poll(&mut fds, STOP_POLL_MS) {
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => {
+                eprintln!("Pressure monitor for {} stopped: {}", id, e);
+                return;
+            }
+        }
+
+        for (fd, (_, trigger)) in fds.iter().zip(watched) {
+            let revents = fd.revents().unwrap_or(PollFlags::empty());
+            if revents.contains(PollFlags::POLLERR) {
+                // The cgroup is gone.
+                return;
+            }
+            if revents.contains(PollFlags::POLLPRI) {
+                log_event(id, cgroup, trigger);
+            }
+        }
+    }
+}
+
+fn log_event(id: &str, cgroup: &Path, trigger: &PressureTrigger) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let current = read_pressure(cgroup, trigger.resource)
+        .map(|p| {
+            let line = if trigger.full {
+                p.full.unwrap_or_default()
+            } else {
+                p.some
+            };
+            format!(" (avg10={:.2}%)", line.avg10)
+        })
+        .unwrap_or_default();
+
+    eprintln!(
+        "[{}] Container {}: {} pressure exceeded {}{}",
+        now, id, trigger.resource, trigger, current
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pressure() {
+        let pressure: Pressure = "some avg10=1.50 avg60=0.75 avg300=0.10 total=123456\n\
+                                  full avg10=0.50 avg60=0.25 avg300=0.00 total=4567\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(pressure.some.avg10, 1.5);
+        assert_eq!(pressure.some.total, 123456);
+        assert_eq!(pressure.full.unwrap().avg60, 0.25);
+    }
+
+    #[test]
+    fn test_parse_pressure_without_full_line() {
+        let pressure: Pressure = "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(pressure.full, None);
+        assert!("".parse::<Pressure>().is_err());
+        assert!("some avg10=x".parse::<Pressure>().is_err());
+    }
+
+    #[test]
+    fn test_parse_trigger() {
+        let trigger: PressureTrigger = "memory=150ms/1s".parse().unwrap();
+        assert_eq!(trigger.resource, Resource::Memory);
+        assert!(!trigger.full);
+        assert_eq!(trigger.registration(), "some 150000 1000000");
+
+        let trigger: PressureTrigger = "io:full=500ms/2s".parse().unwrap();
+        assert_eq!(trigger.registration(), "full 500000 2000000");
+        assert_eq!(trigger.to_string(), "io:full=500ms/2000ms");
+    }
+
+    #[test]
+    fn test_parse_trigger_rejects_bad_input() {
+        for bad in [
+            "memory",
+            "disk=100ms/1s",
+            "cpu:half=100ms/1s",
+            "cpu=100ms",
+            "cpu=100/1s",
+            "cpu=100ms/100ms",
+            "cpu=100ms/20s",
+            "cpu=2s/1s",
+            "cpu=0ms/1s",
+        ] {
+            assert!(
+                bad.parse::<PressureTrigger>().is_err(),
+                "{:?} should not parse",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500us").unwrap(), Duration::from_micros(500));
+        assert_eq!(parse_duration("150ms").unwrap(), Duration::from_millis(150));
+        assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+        assert!(parse_duration("2").is_err());
+        assert!(parse_duration("2m").is_err());
+    }
+
+    #[test]
+    fn test_read_pressure_from_fake_cgroup() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            temp_dir.path().join("cpu.pressure"),
+            "some avg10=3.00 avg60=2.00 avg300=1.00 total=99\n",
+        )
+        .unwrap();
+
+        let pressure = read_pressure(temp_dir.path(), Resource::Cpu).unwrap();
+
+        assert_eq!(pressure.some.avg10, 3.0);
+        assert!(read_pressure(temp_dir.path(), Resource::Io).is_err());
+    }
+}
@@ -0,0 +1,548 @@
+use anyhow::{Context, Result};
+use nix::sys::stat::{SFlag, major, minor, stat};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A per-device I/O limit, given on the command line as `PATH:RATE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThrottleDevice {
+    pub path: PathBuf,
+    pub rate: u64,
+}
+
+impl ThrottleDevice {
+    /// Parses `PATH:RATE` where the rate is a plain operation count, for the
+    /// `--device-*-iops` options.
+    pub fn parse_iops(s: &str) -> Result<Self> {
+        let (path, rate) = split_device(s)?;
+        let rate = rate
+            .parse()
+            .with_context(|| format!("Invalid IO rate {:?} in {:?}", rate, s))?;
+        Ok(Self { path, rate })
+    }
+}
+
+/// Parses `PATH:RATE` where the rate is a byte count that may carry a unit
+/// (`10mb`, `1g`), for the `--device-*-bps` options.
+impl FromStr for ThrottleDevice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (path, rate) = split_device(s)?;
+        let rate = parse_size(rate).with_context(|| format!("Invalid rate in {:?}", s))?;
+        Ok(Self { path, rate })
+    }
+}
+
+fn split_device(s: &str) -> Result<(PathBuf, &str)> {
+    let (path, rate) = s
+        .rsplit_once(':')
+        .with_context(|| format!("Expected DEVICE:RATE, got {:?}", s))?;
+    if !path.starts_with('/') {
+        anyhow::bail!("Device path must be absolute: {:?}", path);
+    }
+    Ok((PathBuf::from(path), rate))
+}
+
+/// Parses a byte count such as `512`, `64k`, `10MB` or `1GiB`. Units are
+/// binary, as in Docker.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let lower = s.trim().to_ascii_lowercase();
+    let number_end = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(number_end);
+
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid size {:?}", s))?;
+    // Exactly one `b` or `ib` suffix, so `1bb` and `64mm` are rejected.
+    let prefix = match unit.strip_suffix("ib") {
+        Some(prefix) if !prefix.is_empty() => prefix,
+        _ => unit.strip_suffix('b').unwrap_or(unit),
+    };
+    let shift = match prefix {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => anyhow::bail!("Unknown size unit in {:?}", s),
+    };
+
+    number
+        .checked_mul(1 << shift)
+        .with_context(|| format!("Size {:?} is too large", s))
+}
+
+/// Hard memory limit used when `--memory` is not given.
+pub const DEFAULT_MEMORY_LIMIT: u64 = 100 * 1024 * 1024;
+
+/// Docker's `--memory-swap`: the combined memory and swap limit, or `-1`
+/// for unlimited swap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySwap {
+    Limit(u64),
+    Unlimited,
+}
+
+impl FromStr for MemorySwap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim() == "-1" {
+            return Ok(Self::Unlimited);
+        }
+        Ok(Self::Limit(parse_size(s)?))
+    }
+}
+
+/// A set of CPU or memory node numbers in the kernel's list format, such as
+/// `0-3,8`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpuSet(BTreeSet<u32>);
+
+impl CpuSet {
+    pub fn is_subset(&self, other: &CpuSet) -> bool {
+        self.0.is_subset(&other.0)
+    }
+
+    pub fn difference(&self, other: &CpuSet) -> CpuSet {
+        CpuSet(self.0.difference(&other.0).copied().collect())
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut set = BTreeSet::new();
+        for part in s.trim().split(',').filter(|p| !p.is_empty()) {
+            let invalid = || format!("Invalid CPU list {:?}", s);
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    let start: u32 = start.parse().with_context(invalid)?;
+                    let end: u32 = end.parse().with_context(invalid)?;
+                    if start > end {
+                        anyhow::bail!("Invalid range {:?} in CPU list {:?}", part, s);
+                    }
+                    set.extend(start..=end);
+                }
+                None => {
+                    set.insert(part.parse().with_context(invalid)?);
+                }
+            }
+        }
+        if set.is_empty() {
+            anyhow::bail!("CPU list {:?} is empty", s);
+        }
+        Ok(Self(set))
+    }
+}
+
+/// Writes the set back in list format, collapsing runs into ranges.
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for &n in &self.0 {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == n => *end = n,
+                _ => ranges.push((n, n)),
+            }
+        }
+
+        let parts: Vec<String> = ranges
+            .into_iter()
+            .map(|(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{}-{}", start, end)
+                }
+            })
+            .collect();
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+/// Resource limits applied to the container's cgroup on top of the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resources {
+    pub device_read_bps: Vec<ThrottleDevice>,
+    pub device_write_bps: Vec<ThrottleDevice>,
+    pub device_read_iops: Vec<ThrottleDevice>,
+    pub device_write_iops: Vec<ThrottleDevice>,
+    /// Relative block I/O weight, 10 to 1000 as in Docker.
+    pub blkio_weight: Option<u16>,
+    /// CPUs the container may run on.
+    pub cpuset_cpus: Option<CpuSet>,
+    /// NUMA memory nodes the container may allocate from.
+    pub cpuset_mems: Option<CpuSet>,
+    /// Relative CPU weight in Docker's `--cpu-shares` units (2 to 262144).
+    pub cpu_shares: Option<u64>,
+    /// Relative CPU weight in cgroup v2 units (1 to 10000). Takes precedence
+    /// over `cpu_shares`.
+    pub cpu_weight: Option<u64>,
+    /// Hard memory limit, [`DEFAULT_MEMORY_LIMIT`] when unset.
+    pub memory: Option<u64>,
+    /// Usage above which the container is throttled and reclaimed from.
+    pub memory_high: Option<u64>,
+    /// Memory protected from reclaim while the system has other options.
+    pub memory_reservation: Option<u64>,
+    /// Memory never reclaimed from the container.
+    pub memory_min: Option<u64>,
+    /// Combined memory and swap limit.
+    pub memory_swap: Option<MemorySwap>,
+}
+
+impl Resources {
+    /// Whether no limits beyond the defaults were requested.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// cgroup interface files to write, with their values, in order. Device
+    /// paths are resolved to their major:minor numbers here.
+    pub fn cgroup_settings(&self) -> Result<Vec<(&'static str, String)>> {
+        let mut settings = Vec::new();
+
+        if let Some(cpus) = &self.cpuset_cpus {
+            settings.push(("cpuset.cpus", cpus.to_string()));
+        }
+        if let Some(mems) = &self.cpuset_mems {
+            settings.push(("cpuset.mems", mems.to_string()));
+        }
+        if let Some(weight) = self.cpu_weight.or(self.cpu_shares.map(cpu_weight)) {
+            settings.push(("cpu.weight", weight.to_string()));
+        }
+
+        settings.extend(self.memory_settings()?);
+
+        if let Some(weight) = self.blkio_weight {
+            settings.push(("io.weight", format!("default {}", io_weight(weight))));
+        }
+
+        let throttles = [
+            ("rbps", &self.device_read_bps),
+            ("wbps", &self.device_write_bps),
+            ("riops", &self.device_read_iops),
+            ("wiops", &self.device_write_iops),
+        ];
+        let mut limits = Vec::new();
+        for (key, devices) in throttles {
+            for device in devices {
+                limits.push((device_number(&device.path)?, key, device.rate));
+            }
+        }
+        for line in io_max_lines(&limits) {
+            settings.push(("io.max", line));
+        }
+
+        Ok(settings)
+    }
+
+    fn memory_settings(&self) -> Result<Vec<(&'static str, String)>> {
+        let memory = self.memory.unwrap_or(DEFAULT_MEMORY_LIMIT);
+        let mut settings = Vec::new();
+
+        if let Some(limit) = self.memory {
+            settings.push(("memory.max", limit.to_string()));
+        }
+        let protections = [
+            (
+                "memory.low",
+                "--memory-reservation",
+                self.memory_reservation,
+            ),
+            ("memory.min", "--memory-min", self.memory_min),
+        ];
+        for (file, option, value) in protections {
+            if let Some(value) = value {
+                if value > memory {
+                    anyhow::bail!("{} must not exceed the memory limit", option);
+                }
+                settings.push((file, value.to_string()));
+            }
+        }
+        if let Some(high) = self.memory_high {
+            settings.push(("memory.high", high.to_string()));
+        }
+        match self.memory_swap {
+            // memory.swap.max counts swap alone, not memory plus swap.
+            Some(MemorySwap::Limit(total)) => {
+                if total < memory {
+                    anyhow::bail!("--memory-swap must be at least the memory limit");
+                }
+                settings.push(("memory.swap.max", (total - memory).to_string()));
+            }
+            Some(MemorySwap::Unlimited) => settings.push(("memory.swap.max", "max".to_string())),
+            None => {}
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Maps CPU shares (2-262144) onto the `cpu.weight` range (1-10000) the
+/// same way runc does.
+pub fn cpu_weight(shares: u64) -> u64 {
+    let shares = shares.clamp(2, 262144);
+    1 + (shares - 2) * 9999 / 262142
+}
+
+/// Maps a blkio weight (10-1000) onto the `io.weight` range (1-10000) the
+/// same way runc does.
+pub fn io_weight(blkio_weight: u16) -> u64 {
+    let weight = u64::from(blkio_weight.clamp(10, 1000));
+    1 + (weight - 10) * 9999 / 990
+}
+
+/// One `io.max` line per device, e.g. `8:0 rbps=1048576 wiops=100`. The
+/// kernel only updates the keys present in each write.
+fn io_max_lines(limits: &[((u64, u64), &str, u64)]) -> Vec<String> {
+    let mut by_device: BTreeMap<(u64, u64), Vec<String>> = BTreeMap::new();
+    for ((major, minor), key, value) in limits {
+        by_device
+            .entry((*major, *minor))
+            .or_default()
+            .push(format!("{}={}", key, value));
+    }
+
+    by_device
+        .into_iter()
+        .map(|((major, minor), keys)| format!("{}:{} {}", major, minor, keys.join(" ")))
+        .collect()
+}
+
+/// The major and minor number of the block device at `path`.
+pub fn device_number(path: &Path) -> Result<(u64, u64)> {
+    let st = stat(path).with_context(|| format!("Failed to stat device {:?}", path))?;
+    if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT != SFlag::S_IFBLK {
+        anyhow::bail!("{:?} is not a block device", path);
+    }
+    Ok((major(st.st_rdev), minor(st.st_rdev)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64k").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10MB").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("1GiB").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_size("2t").unwrap(), 2 << 40);
+
+        assert_eq!(parse_size("512b").unwrap(), 512);
+        for bad in [
+            "",
+            "mb",
+            "10x",
+            "-1",
+            "1.5m",
+            "99999999999999t",
+            "1bb",
+            "64mm",
+            "1ib",
+            "2kibb",
+            "3gibib",
+        ] {
+            assert!(parse_size(bad).is_err(), "{:?} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_throttle_device() {
+        let device: ThrottleDevice = "/dev/sda:10mb".parse().unwrap();
+        assert_eq!(device.path, PathBuf::from("/dev/sda"));
+        assert_eq!(device.rate, 10 * 1024 * 1024);
+
+        let device = ThrottleDevice::parse_iops("/dev/sda:300").unwrap();
+        assert_eq!(device.rate, 300);
+
+        assert!(ThrottleDevice::parse_iops("/dev/sda:1k").is_err());
+        assert!("/dev/sda".parse::<ThrottleDevice>().is_err());
+        assert!("sda:1mb".parse::<ThrottleDevice>().is_err());
+    }
+
+    #[test]
+    fn test_io_weight_mapping() {
+        assert_eq!(io_weight(10), 1);
+        assert_eq!(io_weight(500), 4950);
+        assert_eq!(io_weight(1000), 10000);
+    }
+
+    #[test]
+    fn test_cpu_weight_mapping() {
+        assert_eq!(cpu_weight(2), 1);
+        assert_eq!(cpu_weight(1024), 39);
+        assert_eq!(cpu_weight(262144), 10000);
+    }
+
+    #[test]
+    fn test_parse_cpu_set() {
+        let set: CpuSet = "0-3,8,10-11".parse().unwrap();
+        assert_eq!(set.to_string(), "0-3,8,10-11");
+
+        let set: CpuSet = "3,1,2,7".parse().unwrap();
+        assert_eq!(set.to_string(), "1-3,7");
+
+        assert_eq!("1,,2".parse::<CpuSet>().unwrap().to_string(), "1-2");
+        for bad in ["", " ", ",", "a", "3-1", "1-", "-2", "1,,x"] {
+            assert!(bad.parse::<CpuSet>().is_err(), "{:?} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn test_cpu_set_subset() {
+        let available: CpuSet = "0-3".parse().unwrap();
+        let wanted: CpuSet = "2-5".parse().unwrap();
+
+        assert!("1,3".parse::<CpuSet>().unwrap().is_subset(&available));
+        assert!(!wanted.is_subset(&available));
+        assert_eq!(wanted.difference(&available).to_string(), "4-5");
+    }
+
+    #[test]
+    fn test_cpu_settings() {
+        let resources = Resources {
+            cpuset_cpus: Some("0-1".parse().unwrap()),
+            cpuset_mems: Some("0".parse().unwrap()),
+            cpu_shares: Some(2),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            resources.cgroup_settings().unwrap(),
+            vec![
+                ("cpuset.cpus", "0-1".to_string()),
+                ("cpuset.mems", "0".to_string()),
+                ("cpu.weight", "1".to_string()),
+            ]
+        );
+
+        let resources = Resources {
+            cpu_shares: Some(2),
+            cpu_weight: Some(500),
+            ..Default::default()
+        };
+        assert_eq!(
+            resources.cgroup_settings().unwrap(),
+            vec![("cpu.weight", "500".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_memory_swap() {
+        assert_eq!("-1".parse::<MemorySwap>().unwrap(), MemorySwap::Unlimited);
+        assert_eq!(
+            "1g".parse::<MemorySwap>().unwrap(),
+            MemorySwap::Limit(1024 * 1024 * 1024)
+        );
+        assert!("-2".parse::<MemorySwap>().is_err());
+    }
+
+    #[test]
+    fn test_memory_settings() {
+        let resources = Resources {
+            memory: Some(512 * 1024 * 1024),
+            memory_high: Some(400 * 1024 * 1024),
+            memory_reservation: Some(256 * 1024 * 1024),
+            memory_min: Some(64 * 1024 * 1024),
+            memory_swap: Some(MemorySwap::Limit(1024 * 1024 * 1024)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            resources.cgroup_settings().unwrap(),
+            vec![
+                ("memory.max", "536870912".to_string()),
+                ("memory.low", "268435456".to_string()),
+                ("memory.min", "67108864".to_string()),
+                ("memory.high", "419430400".to_string()),
+                ("memory.swap.max", "536870912".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_swap_uses_default_limit() {
+        let resources = Resources {
+            memory_swap: Some(MemorySwap::Limit(DEFAULT_MEMORY_LIMIT)),
+            ..Default::default()
+        };
+        assert_eq!(
+            resources.cgroup_settings().unwrap(),
+            vec![("memory.swap.max", "0".to_string())]
+        );
+
+        let resources = Resources {
+            memory_swap: Some(MemorySwap::Unlimited),
+            ..Default::default()
+        };
+        assert_eq!(
+            resources.cgroup_settings().unwrap(),
+            vec![("memory.swap.max", "max".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_memory_settings_validation() {
+        let too_little_swap = Resources {
+            memory: Some(512 * 1024 * 1024),
+            memory_swap: Some(MemorySwap::Limit(256 * 1024 * 1024)),
+            ..Default::default()
+        };
+        assert!(too_little_swap.cgroup_settings().is_err());
+
+        let reservation_above_limit = Resources {
+            memory_reservation: Some(DEFAULT_MEMORY_LIMIT + 1),
+            ..Default::default()
+        };
+        assert!(reservation_above_limit.cgroup_settings().is_err());
+    }
+
+    #[test]
+    fn test_io_max_lines_group_by_device() {
+        let limits = [
+            ((8, 0), "rbps", 1048576),
+            ((8, 16), "riops", 100),
+            ((8, 0), "wiops", 50),
+        ];
+
+        assert_eq!(
+            io_max_lines(&limits),
+            vec!["8:0 rbps=1048576 wiops=50", "8:16 riops=100"]
+        );
+    }
+
+    #[test]
+    fn test_device_number_rejects_non_block_devices() {
+        assert!(device_number(Path::new("/dev/null")).is_err());
+        assert!(device_number(Path::new("/nonexistent/device")).is_err());
+    }
+
+    #[test]
+    fn test_cgroup_settings() {
+        let resources = Resources {
+            blkio_weight: Some(500),
+            ..Default::default()
+        };
+        assert_eq!(
+            resources.cgroup_settings().unwrap(),
+            vec![("io.weight", "default 4950".to_string())]
+        );
+
+        assert!(Resources::default().cgroup_settings().unwrap().is_empty());
+
+        let resources = Resources {
+            device_read_bps: vec!["/dev/null:1mb".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(resources.cgroup_settings().is_err());
+    }
+}
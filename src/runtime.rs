@@ -1,25 +1,50 @@
-// use crate::cgroup::setup_cgroup;
+use crate::capabilities::{apply_capabilities, drop_bounding_set, last_cap};
+use crate::cgroup::{cleanup_cgroup, freeze_cgroup, oom_kill_count, setup_cgroup};
+use crate::config::ContainerConfig;
+use crate::devices::attach_device_filter;
+use crate::idmap::{attach_tree, open_idmapped_tree, owner_userns};
+use crate::landlock::apply_landlock;
+use crate::lsm::{check_apparmor, check_selinux, relabel, set_apparmor_exec, set_selinux_exec};
+use crate::masking::{apply_masked_paths, apply_readonly_paths};
+use crate::mounts::{Relabel, Volume};
 use crate::namespace::setup_user_namespace;
-use crate::pivot_root::setup_rootfs;
-use anyhow::Result;
+use crate::overlay::{OverlayDirs, mount_detached_overlay};
+use crate::pivot_root::{remount_readonly, setup_rootfs};
+use crate::process::{container_env, enter_working_dir, resolve_user, switch_user};
+use crate::psi::PressureMonitor;
+use crate::seccomp::{SeccompFilter, Target};
+use crate::security::set_no_new_privs;
+use crate::state::{ContainerState, StateStore, Status, generate_id};
+use crate::ulimit::apply_ulimits;
+use anyhow::{Context, Result};
 use nix::mount::{MsFlags, mount};
 use nix::sched::{CloneFlags, clone};
-use nix::sys::signal::Signal;
-use nix::sys::socket::{AddressFamily, SockFlag, SockType, socketpair};
-use nix::unistd::{Pid, execvp, sethostname};
-use nix::unistd::{read, write};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use nix::sys::socket::{
+    AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType, recvmsg,
+    sendmsg, socketpair,
+};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::{ForkResult, Pid, execvpe, fork, getgid, getuid, sethostname};
+use nix::{cmsg_space, errno::Errno};
 use std::env;
 use std::ffi::CString;
-use std::os::fd::AsRawFd;
-use std::path::Path;
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
 
 const STACK_SIZE: usize = 1024 * 1024; // 1MB stack
 
-pub fn run_container(
-    rootfs: &str,
-    command: &str,
-    args: Vec<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
+/// Most file descriptors one SCM_RIGHTS message may carry (SCM_MAX_FD).
+const MAX_PASSED_FDS: usize = 253;
+
+/// Container process and signal the stop handler forwards to, 0 when no
+/// container is running.
+static STOP_PID: AtomicI32 = AtomicI32::new(0);
+static STOP_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+pub fn run_container(config: &ContainerConfig) -> Result<(), Box<dyn std::error::Error>> {
     let mut stack = vec![0u8; STACK_SIZE];
     let flags = CloneFlags::CLONE_NEWPID
         | CloneFlags::CLONE_NEWNS
@@ -31,47 +56,232 @@ pub fn run_container(
         AddressFamily::Unix,
         SockType::SeqPacket,
         None,
-        SockFlag::empty(),
+        // Keeps the overlay helper and the container command from inheriting it.
+        SockFlag::SOCK_CLOEXEC,
     )?;
 
-    if !Path::new(rootfs).exists() {
+    if !Path::new(&config.rootfs).exists() {
         return Err("Invalid rootfs".into());
     }
-    if !Path::new(command).exists() {
+    // Image commands are looked up inside the image, on its PATH.
+    if config.image.is_none() && !Path::new(&config.command).exists() {
         return Err("Command does not exist".into());
     }
+    // The overlay's mount is passed along with the idmapped volumes.
+    let passed_overlay = usize::from(config.overlay);
+    if config.volumes.iter().filter(|v| v.idmap).count() + passed_overlay > MAX_PASSED_FDS {
+        return Err(format!(
+            "At most {} idmapped volumes are supported",
+            MAX_PASSED_FDS - passed_overlay
+        )
+        .into());
+    }
+
+    if let Some(profile) = &config.apparmor_profile {
+        check_apparmor(profile).map_err(|e| format!("{:#}", e))?;
+    }
+    if let Some(label) = &config.selinux_label {
+        check_selinux().map_err(|e| format!("{:#}", e))?;
+        for volume in &config.volumes {
+            let mount_label = match volume.relabel {
+                Some(Relabel::Shared) => label.shared_mount_label(),
+                Some(Relabel::Private) => label.mount_label(),
+                None => continue,
+            };
+            relabel(&volume.source, &mount_label).map_err(|e| format!("{:#}", e))?;
+        }
+    }
+
+    // Compiled up front so a bad profile fails before anything is created.
+    let seccomp = match &config.seccomp {
+        Some(profile) => Some(
+            Target::host(&config.capabilities)
+                .and_then(|target| profile.compile(&target))
+                .map_err(|e| format!("{:#}", e))?,
+        ),
+        None => None,
+    };
+
+    let mut device_rules = config.device_rules.clone();
+    for device in &config.devices {
+        device_rules.push(device.rule().map_err(|e| format!("{:#}", e))?);
+    }
+
+    let store = StateStore::new(&config.root);
+    let id = generate_id()?;
+    let config = &with_anonymous_volumes(config, &store.container_dir(&id))
+        .map_err(|e| format!("{:#}", e))?;
+    let rootfs = Path::new(&config.rootfs).canonicalize()?;
+    let mut state = ContainerState::new(&id, &rootfs, &config.command, &config.args);
+    state.image = config.image.clone();
+    state.exposed_ports = config.exposed_ports.clone();
+    state.selinux_level = config.selinux_label.as_ref().map(|l| l.level.clone());
+
+    let overlay = if config.overlay {
+        let mut lower = vec![rootfs];
+        for layer in &config.layers {
+            lower.push(
+                layer
+                    .canonicalize()
+                    .map_err(|e| format!("Invalid layer {:?}: {}", layer, e))?,
+            );
+        }
+        let dirs = OverlayDirs::new(&store.container_dir(&id), lower);
+        dirs.create()?;
+        state.overlay = true;
+        Some(dirs)
+    } else {
+        None
+    };
+    store.save(&state)?;
 
+    let child_overlay = overlay.clone();
     unsafe {
         child_pid = clone(
             Box::new(move || {
-                let mut buf = [0u8];
-                if read(child_sock.as_raw_fd(), &mut buf).is_err() {
-                    return 1;
-                }
-                child_process(rootfs.to_string(), command.to_string(), args.clone())
+                let mut idmapped = match receive_start(child_sock.as_raw_fd()) {
+                    Ok(fds) => fds,
+                    Err(_) => return 1,
+                };
+                // The overlay's mount comes ahead of the idmapped volumes.
+                let overlay = match &child_overlay {
+                    Some(dirs) if !idmapped.is_empty() => Some((dirs.clone(), idmapped.remove(0))),
+                    Some(_) => return 1,
+                    None => None,
+                };
+                child_process(config.clone(), overlay, idmapped, seccomp.as_ref())
             }),
             &mut stack,
             flags,
             Some(Signal::SIGCHLD as i32),
         )?;
     }
-    // if let Err(e) = setup_cgroup(child_pid.as_raw()) {
-    //     eprintln!("Failed to setup cgroups: {}", e);
-    //     return Err(e);
-    // }
+    let mut guard = StartGuard {
+        store: &store,
+        id: &id,
+        pid: child_pid,
+        cgroup: None,
+        started: false,
+    };
+    match setup_cgroup(&id, child_pid.as_raw(), &config.resources) {
+        Ok(cgroup) => {
+            guard.cgroup = Some(cgroup.clone());
+            state.cgroup = Some(cgroup);
+        }
+        // Limits the user asked for must not be silently dropped.
+        Err(e) if !config.resources.is_default() => {
+            eprintln!("Failed to setup cgroups: {}", e);
+            return Err(e);
+        }
+        Err(e) => eprintln!(
+            "Failed to setup cgroups: {}; running without resource limits or device restrictions",
+            e
+        ),
+    }
+
+    if let Some(cgroup) = &state.cgroup
+        && let Err(e) = attach_device_filter(cgroup, &device_rules)
+    {
+        // As with cgroups above, only fail when the user asked for devices.
+        if !config.devices.is_empty() {
+            eprintln!("Failed to restrict device access: {:#}", e);
+            return Err(e.into());
+        }
+        eprintln!(
+            "Failed to restrict device access: {:#}; running without a device filter",
+            e
+        );
+    }
+
+    let monitor = match (&state.cgroup, config.pressure_triggers.is_empty()) {
+        (_, true) => None,
+        (Some(cgroup), false) => {
+            match PressureMonitor::start(&id, cgroup, &config.pressure_triggers) {
+                Ok(monitor) => Some(monitor),
+                Err(e) => {
+                    eprintln!("Failed to set up pressure triggers: {:#}", e);
+                    return Err(e.into());
+                }
+            }
+        }
+        (None, false) => {
+            eprintln!("Pressure triggers need a cgroup; not monitoring pressure");
+            None
+        }
+    };
+
+    if let Some(score) = config.oom_score_adj
+        && let Err(e) = set_oom_score_adj(child_pid, score)
+    {
+        eprintln!("Failed to set OOM score adjustment: {:#}", e);
+        return Err(e.into());
+    }
+
+    // The child is blocked on the start message, so map its user namespace
+    // now: container root becomes the invoking user. Unmapped, it could not
+    // even create files.
+    if let Err(e) = setup_user_namespace(child_pid.as_raw()) {
+        eprintln!("Failed to setup user namespace: {}", e);
+        return Err(e);
+    }
+    let mut passed = Vec::new();
+    if let Some(dirs) = &overlay {
+        match prepare_overlay(dirs, child_pid) {
+            Ok(tree) => passed.push(tree),
+            Err(e) => {
+                eprintln!("Failed to mount overlay: {:#}", e);
+                return Err(e.into());
+            }
+        }
+    }
+    match prepare_idmapped_volumes(config) {
+        Ok(fds) => passed.extend(fds),
+        Err(e) => {
+            eprintln!("Failed to prepare idmapped volumes: {:#}", e);
+            return Err(e.into());
+        }
+    }
+
+    state.status = Status::Running;
+    state.pid = Some(child_pid.as_raw());
+    store.save(&state)?;
 
-    // if let Err(e) = setup_user_namespace(child_pid.as_raw()) {
-    //     eprintln!("Failed to setup user namespace: {}", e);
-    //     return Err(e);
-    // }
-    if let Err(e) = write(parent_sock.as_raw_fd(), &[1]) {
+    if let Err(e) = send_start(parent_sock.as_raw_fd(), &passed) {
         eprintln!("Failed to signal child process: {}", e);
         return Err(e.into());
     }
+    drop(passed);
+    guard.started = true;
 
-    println!("Container started with PID: {}", child_pid);
+    println!("Container {} started with PID: {}", id, child_pid);
+
+    let wait_result =
+        wait_forwarding_stop(child_pid, config.stop_signal.unwrap_or(Signal::SIGTERM));
+
+    state.status = Status::Stopped;
+    if let Ok(status) = wait_result {
+        state.exit_code = exit_code(status);
+    }
+    drop(monitor);
+    // The counter has to be read before the cgroup is removed.
+    if let Some(cgroup) = &state.cgroup
+        && let Ok(killed) = oom_kill_count(cgroup)
+        && killed > 0
+    {
+        state.oom_killed = true;
+        eprintln!(
+            "Container {} ran out of memory: the kernel OOM-killed {} process(es) after it hit its memory limit",
+            id, killed
+        );
+    }
+    if let Some(cgroup) = &state.cgroup
+        && let Err(e) = cleanup_cgroup(cgroup)
+    {
+        eprintln!("Failed to clean up cgroup: {}", e);
+    }
+    store.save(&state)?;
 
-    match nix::sys::wait::waitpid(child_pid, None) {
+    match wait_result {
         Ok(_) => Ok(()),
         Err(e) => {
             eprintln!("Failed to wait for child process: {}", e);
@@ -80,29 +290,297 @@ pub fn run_container(
     }
 }
 
-fn child_process(rootfs: String, command: String, args: Vec<String>) -> isize {
-    println!(
-        "rootfs : {}, command : {}, args : {:?}",
-        rootfs, command, args
+/// Undoes a start that failed after the child was cloned: kills and reaps
+/// the child, removes its cgroup and records the container as stopped.
+struct StartGuard<'a> {
+    store: &'a StateStore,
+    id: &'a str,
+    pid: Pid,
+    cgroup: Option<PathBuf>,
+    /// Set once the child has been told to start; the normal exit path
+    /// cleans up from there.
+    started: bool,
+}
+
+impl Drop for StartGuard<'_> {
+    fn drop(&mut self) {
+        if self.started {
+            return;
+        }
+        let _ = nix::sys::signal::kill(self.pid, Signal::SIGKILL);
+        let _ = nix::sys::wait::waitpid(self.pid, None);
+        if let Some(cgroup) = &self.cgroup
+            && let Err(e) = cleanup_cgroup(cgroup)
+        {
+            eprintln!("Failed to clean up cgroup: {}", e);
+        }
+        if let Ok(mut state) = self.store.load(self.id) {
+            state.status = Status::Stopped;
+            state.pid = None;
+            let _ = self.store.save(&state);
+        }
+    }
+}
+
+/// Freezes every process of a running container and records it as paused.
+pub fn pause_container(store: &StateStore, id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut state = store.load(id)?;
+    if !state.is_alive() {
+        return Err(format!("Container {} is not running", id).into());
+    }
+    if state.status == Status::Paused {
+        return Err(format!("Container {} is already paused", id).into());
+    }
+    let cgroup = state
+        .cgroup
+        .clone()
+        .ok_or_else(|| format!("Container {} has no cgroup to freeze", id))?;
+
+    if let Err(e) = freeze_cgroup(&cgroup, true) {
+        // Don't leave the container half frozen.
+        let _ = freeze_cgroup(&cgroup, false);
+        return Err(e);
+    }
+
+    state.status = Status::Paused;
+    store.save(&state)?;
+    Ok(())
+}
+
+/// Thaws a container frozen by [`pause_container`].
+pub fn unpause_container(store: &StateStore, id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut state = store.load(id)?;
+    if state.status != Status::Paused || !state.is_alive() {
+        return Err(format!("Container {} is not paused", id).into());
+    }
+    let cgroup = state
+        .cgroup
+        .clone()
+        .ok_or_else(|| format!("Container {} has no cgroup to thaw", id))?;
+
+    freeze_cgroup(&cgroup, false)?;
+
+    state.status = Status::Running;
+    store.save(&state)?;
+    Ok(())
+}
+
+/// Sets the OOM killer score adjustment of `pid`, which its exec'd command
+/// inherits. Lowering it below the current value needs CAP_SYS_RESOURCE.
+fn set_oom_score_adj(pid: Pid, score: i32) -> Result<()> {
+    let path = format!("/proc/{}/oom_score_adj", pid);
+    std::fs::write(&path, score.to_string())
+        .with_context(|| format!("Failed to write {} to {}", score, path))
+}
+
+/// Adds a volume in the container directory for each of the image's
+/// anonymous volumes, so they go away when the container is deleted.
+fn with_anonymous_volumes(
+    config: &ContainerConfig,
+    container_dir: &Path,
+) -> Result<ContainerConfig> {
+    let mut config = config.clone();
+    for (i, destination) in config.anonymous_volumes.iter().enumerate() {
+        let source = container_dir.join("volumes").join(i.to_string());
+        std::fs::create_dir_all(&source)
+            .with_context(|| format!("Failed to create volume for {:?}", destination))?;
+        config.volumes.push(Volume {
+            source,
+            destination: destination.clone(),
+            read_only: false,
+            idmap: false,
+            relabel: None,
+        });
+    }
+    Ok(config)
+}
+
+extern "C" fn forward_stop(_: libc::c_int) {
+    let pid = STOP_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        // kill(2) is async-signal-safe.
+        unsafe { libc::kill(pid, STOP_SIGNAL.load(Ordering::SeqCst)) };
+    }
+}
+
+/// Waits for the container, sending it `stop_signal` if the runtime gets
+/// SIGINT or SIGTERM in the meantime. The handler may run on any thread, so
+/// it signals the container itself rather than interrupting the wait.
+fn wait_forwarding_stop(child: Pid, stop_signal: Signal) -> nix::Result<WaitStatus> {
+    STOP_SIGNAL.store(stop_signal as i32, Ordering::SeqCst);
+    STOP_PID.store(child.as_raw(), Ordering::SeqCst);
+    let action = SigAction::new(
+        SigHandler::Handler(forward_stop),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
     );
+    let previous: Vec<_> = [Signal::SIGINT, Signal::SIGTERM]
+        .into_iter()
+        .filter_map(|signal| Some((signal, unsafe { sigaction(signal, &action) }.ok()?)))
+        .collect();
+
+    let result = loop {
+        match nix::sys::wait::waitpid(child, None) {
+            Err(Errno::EINTR) => continue,
+            result => break result,
+        }
+    };
 
-    if let Err(e) = mount(
-        None::<&str>,
-        "/",
-        None::<&str>,
-        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
-        None::<&str>,
-    ) {
-        eprintln!("Failed to make mount private: {}", e);
-        return 1;
+    STOP_PID.store(0, Ordering::SeqCst);
+    for (signal, action) in previous {
+        let _ = unsafe { sigaction(signal, &action) };
     }
+    result
+}
+
+/// Builds the detached idmapped mount for each `idmap` volume, with the
+/// volume's owner mapped to the container's root. This needs host
+/// privileges, so it is done here and the mounts are handed to the child to
+/// attach.
+fn prepare_idmapped_volumes(config: &ContainerConfig) -> Result<Vec<OwnedFd>> {
+    // Container root is the invoking user; see setup_user_namespace.
+    let (uid, gid) = (getuid(), getgid());
+    config
+        .volumes
+        .iter()
+        .filter(|v| v.idmap)
+        .map(|volume| {
+            let userns = owner_userns(&volume.source, uid, gid)?;
+            open_idmapped_tree(&volume.source, &userns, volume.read_only)
+        })
+        .collect()
+}
+
+/// Mounts the container's overlay from a helper process, outside the
+/// container but as its user namespace; see [`mount_detached_overlay`].
+/// Returns the mount for the child to attach.
+fn prepare_overlay(dirs: &OverlayDirs, child: Pid) -> Result<OwnedFd> {
+    let path = format!("/proc/{}/ns/user", child);
+    let userns = std::fs::File::open(&path).with_context(|| format!("Failed to open {}", path))?;
+    let (parent_sock, helper_sock) = socketpair(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )?;
+
+    match unsafe { fork() }.context("Failed to fork the overlay helper")? {
+        ForkResult::Child => {
+            drop(parent_sock);
+            let code = match mount_detached_overlay(dirs, &userns) {
+                Ok(tree) => match send_start(helper_sock.as_raw_fd(), &[tree]) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        eprintln!("Failed to pass on the overlay mount: {}", e);
+                        1
+                    }
+                },
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    1
+                }
+            };
+            unsafe { libc::_exit(code) };
+        }
+        ForkResult::Parent { child: helper } => {
+            drop(helper_sock);
+            let received = receive_start(parent_sock.as_raw_fd());
+            let _ = nix::sys::wait::waitpid(helper, None);
+            received
+                .context("The overlay helper failed")?
+                .pop()
+                .context("The overlay helper passed no mount")
+        }
+    }
+}
+
+/// Releases the child, passing along any file descriptors it needs.
+fn send_start(sock: RawFd, fds: &[OwnedFd]) -> nix::Result<()> {
+    let raw: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+    let iov = [IoSlice::new(&[1])];
+    let cmsgs = if raw.is_empty() {
+        vec![]
+    } else {
+        vec![ControlMessage::ScmRights(&raw)]
+    };
+
+    sendmsg::<()>(sock, &iov, &cmsgs, MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+/// Blocks until the parent releases the child. Fails if the parent went
+/// away without doing so.
+fn receive_start(sock: RawFd) -> nix::Result<Vec<OwnedFd>> {
+    let mut buf = [0u8];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg_buffer = cmsg_space!([RawFd; MAX_PASSED_FDS]);
+
+    let msg = recvmsg::<()>(
+        sock,
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    if msg.bytes == 0 {
+        return Err(Errno::EPIPE);
+    }
+
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(raw) = cmsg {
+            fds.extend(
+                raw.into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    Ok(fds)
+}
+
+/// Shell-style exit code: the process's own code, or 128 + signal number.
+fn exit_code(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Some(code),
+        WaitStatus::Signaled(_, signal, _) => Some(128 + signal as i32),
+        _ => None,
+    }
+}
+
+fn child_process(
+    config: ContainerConfig,
+    overlay: Option<(OverlayDirs, OwnedFd)>,
+    idmapped: Vec<OwnedFd>,
+    seccomp: Option<&SeccompFilter>,
+) -> isize {
+    println!(
+        "rootfs : {}, command : {}, args : {:?}",
+        config.rootfs, config.command, config.args
+    );
 
     if let Err(e) = sethostname("docker-clone") {
         eprintln!("Failed to set hostname: {}", e);
         return 1;
     }
-    let path: &Path = Path::new(&rootfs);
-    if let Err(e) = setup_rootfs(path) {
+    // Opened before pivot_root since the new root may not have a /dev/null.
+    let dev_null = match std::fs::File::open("/dev/null") {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Failed to open /dev/null: {}", e);
+            return 1;
+        }
+    };
+
+    let path: PathBuf = match &overlay {
+        Some((dirs, tree)) => {
+            if let Err(e) = attach_tree(tree, &dirs.merged) {
+                eprintln!("Failed to mount overlay: {:#}", e);
+                return 1;
+            }
+            dirs.merged.clone()
+        }
+        None => PathBuf::from(&config.rootfs),
+    };
+    if let Err(e) = setup_rootfs(&path, &config, idmapped) {
         eprintln!("Failed to setup root filesystem: {}", e);
         return 1;
     }
@@ -123,10 +601,97 @@ fn child_process(rootfs: String, command: String, args: Vec<String>) -> isize {
         return 1;
     }
 
-    exec_command(&command, args)
+    if let Err(e) = apply_masked_paths(&config.masked_paths, &dev_null) {
+        eprintln!("Failed to mask paths: {}", e);
+        return 1;
+    }
+
+    if let Err(e) = apply_readonly_paths(&config.readonly_paths) {
+        eprintln!("Failed to make paths read-only: {}", e);
+        return 1;
+    }
+
+    if let Some(dir) = &config.working_dir
+        && let Err(e) = enter_working_dir(dir)
+    {
+        eprintln!("{:#}", e);
+        return 1;
+    }
+
+    if config.read_only
+        && let Err(e) = remount_readonly("/")
+    {
+        eprintln!("Failed to make root filesystem read-only: {}", e);
+        return 1;
+    }
+
+    // Both take effect at exec. Set before Landlock, which may deny access
+    // to /proc.
+    if let Some(profile) = &config.apparmor_profile
+        && let Err(e) = set_apparmor_exec(profile)
+    {
+        eprintln!("{:#}", e);
+        return 1;
+    }
+    if let Some(label) = &config.selinux_label
+        && let Err(e) = set_selinux_exec(&label.process_label())
+    {
+        eprintln!("{:#}", e);
+        return 1;
+    }
+
+    if let Err(e) = restrict_process(&config, seccomp) {
+        eprintln!("{:#}", e);
+        return 1;
+    }
+
+    exec_command(&config.command, config.args, container_env(&config.env))
+}
+
+/// Applies Landlock, rlimits, seccomp, capabilities, the user switch and
+/// no_new_privs, in that order, leaving the process ready to exec.
+fn restrict_process(config: &ContainerConfig, seccomp: Option<&SeccompFilter>) -> Result<()> {
+    // The ruleset may deny /proc and /etc, so read everything the later
+    // steps need first.
+    let last = last_cap()?;
+    let user = config.user.as_ref().map(resolve_user).transpose()?;
+
+    // Applied while the process still has CAP_SYS_ADMIN, which Landlock
+    // needs when no_new_privs is off.
+    if !config.landlock.is_empty() {
+        println!("{}", apply_landlock(&config.landlock)?);
+    }
+
+    apply_ulimits(&config.ulimits)?;
+
+    // Without no_new_privs, installing the filter needs CAP_SYS_ADMIN, so it
+    // goes in before capabilities are dropped. Otherwise it goes in last so
+    // the profile does not have to allow capset and prctl.
+    let install_seccomp = || match seccomp {
+        Some(filter) => filter.install(),
+        None => Ok(()),
+    };
+    if !config.no_new_privileges {
+        install_seccomp()?;
+    }
+
+    // The same order as runc: the bounding set is dropped while CAP_SETPCAP
+    // is still effective, the user switch keeps the permitted set, and the
+    // remaining sets are applied as that user.
+    drop_bounding_set(&config.capabilities, last)?;
+    if let Some((uid, gid)) = user {
+        switch_user(uid, gid)?;
+    }
+    apply_capabilities(&config.capabilities, last)?;
+
+    if config.no_new_privileges {
+        set_no_new_privs()?;
+        install_seccomp()?;
+    }
+    Ok(())
 }
 
-fn exec_command(command: &str, args: Vec<String>) -> isize {
+fn exec_command(command: &str, args: Vec<String>, env: Vec<String>) -> isize {
     let cmd = match CString::new(command) {
         Ok(cmd) => cmd,
         Err(e) => {
@@ -146,13 +711,27 @@ fn exec_command(command: &str, args: Vec<String>) -> isize {
         }
     }
 
-    unsafe {
-        env::set_var("PATH", "/bin:/sbin:/usr/bin:/usr/sbin");
+    let mut full_env = Vec::new();
+    for var in &env {
+        match CString::new(var.as_str()) {
+            Ok(c_var) => full_env.push(c_var),
+            Err(e) => {
+                eprintln!("Failed to create environment string '{}': {}", var, e);
+                return 1;
+            }
+        }
+        // execvpe searches the caller's PATH, so it has to match the
+        // container's.
+        if let Some(path) = var.strip_prefix("PATH=") {
+            unsafe {
+                env::set_var("PATH", path);
+            }
+        }
     }
 
     println!("Executing {:?} with args {:?}", command, full_args);
 
-    match execvp(&cmd, &full_args) {
+    match execvpe(&cmd, &full_args, &full_env) {
         Ok(_) => 0,
         Err(e) => {
             eprintln!("exec failed: {}", e);
@@ -166,6 +745,146 @@ mod tests {
     use super::*;
     use std::ffi::CString;
 
+    #[test]
+    fn test_start_message_passes_fds() {
+        let (parent, child) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        let file: OwnedFd = std::fs::File::open("/dev/null").unwrap().into();
+
+        send_start(parent.as_raw_fd(), &[file]).unwrap();
+        let fds = receive_start(child.as_raw_fd()).unwrap();
+
+        assert_eq!(fds.len(), 1);
+    }
+
+    #[test]
+    fn test_start_message_fails_when_parent_is_gone() {
+        let (parent, child) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        drop(parent);
+
+        assert!(receive_start(child.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn test_restrict_process_under_narrow_landlock_ruleset() {
+        // Dropping from the bounding set needs CAP_SETPCAP.
+        if !getuid().is_root() {
+            eprintln!("skipping: restricting the process needs root");
+            return;
+        }
+        if crate::landlock::abi_version().is_none() {
+            eprintln!("skipping: Landlock is not available");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let config = ContainerConfig {
+            landlock: crate::landlock::LandlockRules {
+                read_only: vec![dir.path().to_path_buf()],
+                read_write: Vec::new(),
+            },
+            user: Some("0:0".parse().unwrap()),
+            capabilities: crate::capabilities::Capability::defaults(),
+            no_new_privileges: true,
+            ..ContainerConfig::default()
+        };
+
+        match unsafe { nix::unistd::fork() }.unwrap() {
+            nix::unistd::ForkResult::Child => {
+                let code = if restrict_process(&config, None).is_err() {
+                    2
+                } else if std::fs::read_to_string("/etc/passwd").is_ok() {
+                    // The ruleset was not enforced.
+                    1
+                } else {
+                    0
+                };
+                unsafe { libc::_exit(code) };
+            }
+            nix::unistd::ForkResult::Parent { child } => {
+                assert_eq!(
+                    nix::sys::wait::waitpid(child, None).unwrap(),
+                    WaitStatus::Exited(child, 0)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pause_requires_running_container() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = StateStore::new(temp_dir.path());
+        let mut state =
+            ContainerState::new("abc123def456", Path::new("/tmp/rootfs"), "/bin/sh", &[]);
+        state.status = Status::Stopped;
+        store.save(&state).unwrap();
+
+        assert!(pause_container(&store, "abc123def456").is_err());
+        assert!(unpause_container(&store, "abc123def456").is_err());
+        assert!(pause_container(&store, "missing").is_err());
+    }
+
+    #[test]
+    fn test_pause_and_unpause_update_status() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = StateStore::new(temp_dir.path());
+        let cgroup = temp_dir.path().join("cgroup");
+        std::fs::create_dir(&cgroup).unwrap();
+        std::fs::write(cgroup.join("cgroup.freeze"), "0").unwrap();
+        std::fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 1\n").unwrap();
+
+        let mut state =
+            ContainerState::new("abc123def456", Path::new("/tmp/rootfs"), "/bin/sh", &[]);
+        state.status = Status::Running;
+        state.pid = Some(std::process::id() as i32);
+        state.cgroup = Some(cgroup.clone());
+        store.save(&state).unwrap();
+
+        pause_container(&store, "abc123def456").unwrap();
+        assert_eq!(store.load("abc123def456").unwrap().status, Status::Paused);
+        assert!(pause_container(&store, "abc123def456").is_err());
+
+        std::fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 0\n").unwrap();
+        unpause_container(&store, "abc123def456").unwrap();
+        assert_eq!(store.load("abc123def456").unwrap().status, Status::Running);
+    }
+
+    #[test]
+    fn test_set_oom_score_adj() {
+        let pid = Pid::this();
+        let current: i32 = std::fs::read_to_string(format!("/proc/{}/oom_score_adj", pid))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+
+        set_oom_score_adj(pid, current).unwrap();
+        assert!(set_oom_score_adj(Pid::from_raw(i32::MAX), 0).is_err());
+    }
+
+    #[test]
+    fn test_exit_code_from_wait_status() {
+        let pid = Pid::from_raw(42);
+
+        assert_eq!(exit_code(WaitStatus::Exited(pid, 0)), Some(0));
+        assert_eq!(exit_code(WaitStatus::Exited(pid, 3)), Some(3));
+        assert_eq!(
+            exit_code(WaitStatus::Signaled(pid, Signal::SIGKILL, false)),
+            Some(137)
+        );
+        assert_eq!(exit_code(WaitStatus::StillAlive), None);
+    }
+
     #[test]
     fn test_exec_command_builds_correct_args() {
         let command = "/bin/ls";
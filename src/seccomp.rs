@@ -0,0 +1,864 @@
+mod syscalls;
+
+use crate::capabilities::Capability;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use syscalls::SYSCALLS;
+
+/// Profile used when `--security-opt seccomp=...` is not given.
+const DEFAULT_PROFILE: &str = include_str!("seccomp/default.json");
+
+/// Longest program the kernel accepts (BPF_MAXINSNS).
+const MAX_INSNS: usize = 4096;
+
+const EPERM: u32 = libc::EPERM as u32;
+
+// Classic BPF opcodes, narrowed to the width of `sock_filter::code`.
+const LD_ABS: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+const ALU_AND: u16 = (libc::BPF_ALU | libc::BPF_AND | libc::BPF_K) as u16;
+const JMP_JA: u16 = (libc::BPF_JMP | libc::BPF_JA) as u16;
+const JMP_JEQ: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+const JMP_JGT: u16 = (libc::BPF_JMP | libc::BPF_JGT | libc::BPF_K) as u16;
+const JMP_JGE: u16 = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+const RET: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+
+// Offsets into `struct seccomp_data`.
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+
+/// x32 syscalls share the x86_64 audit arch but have this bit set.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+#[cfg(target_arch = "x86_64")]
+const ARCH: Arch = Arch {
+    name: "amd64",
+    scmp_name: "SCMP_ARCH_X86_64",
+    audit: 0xC000_003E,
+};
+#[cfg(target_arch = "aarch64")]
+const ARCH: Arch = Arch {
+    name: "arm64",
+    scmp_name: "SCMP_ARCH_AARCH64",
+    audit: 0xC000_00B7,
+};
+
+/// The architecture filters are compiled for, under both names Docker
+/// profiles use for it.
+#[derive(Debug, Clone, Copy)]
+struct Arch {
+    name: &'static str,
+    scmp_name: &'static str,
+    audit: u32,
+}
+
+/// What the kernel does when a filter matches a syscall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Action {
+    #[serde(rename = "SCMP_ACT_KILL", alias = "SCMP_ACT_KILL_THREAD")]
+    KillThread,
+    #[serde(rename = "SCMP_ACT_KILL_PROCESS")]
+    KillProcess,
+    #[serde(rename = "SCMP_ACT_TRAP")]
+    Trap,
+    #[serde(rename = "SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename = "SCMP_ACT_TRACE")]
+    Trace,
+    #[serde(rename = "SCMP_ACT_LOG")]
+    Log,
+    #[serde(rename = "SCMP_ACT_ALLOW")]
+    Allow,
+    #[serde(rename = "SCMP_ACT_NOTIFY")]
+    Notify,
+}
+
+impl Action {
+    /// The filter return value for this action. `data` is the errno for
+    /// `Errno` and the tracer message for `Trace`.
+    fn ret(self, data: Option<u32>) -> Result<u32> {
+        let data = |default: u32| data.unwrap_or(default) & libc::SECCOMP_RET_DATA;
+        Ok(match self {
+            Action::KillThread => libc::SECCOMP_RET_KILL_THREAD,
+            Action::KillProcess => libc::SECCOMP_RET_KILL_PROCESS,
+            Action::Trap => libc::SECCOMP_RET_TRAP,
+            Action::Errno => libc::SECCOMP_RET_ERRNO | data(EPERM),
+            Action::Trace => libc::SECCOMP_RET_TRACE | data(0),
+            Action::Log => libc::SECCOMP_RET_LOG,
+            Action::Allow => libc::SECCOMP_RET_ALLOW,
+            Action::Notify => bail!("SCMP_ACT_NOTIFY is not supported"),
+        })
+    }
+}
+
+/// Comparison applied to a syscall argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Op {
+    #[serde(rename = "SCMP_CMP_NE")]
+    Ne,
+    #[serde(rename = "SCMP_CMP_LT")]
+    Lt,
+    #[serde(rename = "SCMP_CMP_LE")]
+    Le,
+    #[serde(rename = "SCMP_CMP_EQ")]
+    Eq,
+    #[serde(rename = "SCMP_CMP_GE")]
+    Ge,
+    #[serde(rename = "SCMP_CMP_GT")]
+    Gt,
+    /// `(arg & value) == valueTwo`
+    #[serde(rename = "SCMP_CMP_MASKED_EQ")]
+    MaskedEq,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArgRule {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default)]
+    pub value_two: u64,
+    pub op: Op,
+}
+
+/// Conditions under which a rule applies. Docker's profile uses these to
+/// vary rules by architecture, capability and kernel version.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Filter {
+    #[serde(default)]
+    pub arches: Vec<String>,
+    #[serde(default)]
+    pub caps: Vec<String>,
+    pub min_kernel: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRule {
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Single-name form used by older profiles.
+    pub name: Option<String>,
+    pub action: Action,
+    #[serde(default)]
+    pub args: Vec<ArgRule>,
+    pub errno_ret: Option<u32>,
+    #[serde(default)]
+    pub includes: Filter,
+    #[serde(default)]
+    pub excludes: Filter,
+}
+
+/// A seccomp profile in Docker's JSON format. Fields this runtime has no
+/// use for, such as `architectures`, are accepted and ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub default_action: Action,
+    pub default_errno_ret: Option<u32>,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>,
+}
+
+/// What a profile is compiled against: rules can be limited to certain
+/// capabilities or kernel versions.
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    /// Capabilities the container keeps, e.g. `CAP_SYS_ADMIN`.
+    pub caps: Vec<String>,
+    /// Running kernel as (major, minor).
+    pub kernel: (u32, u32),
+}
+
+impl Target {
+    /// The running kernel, for a container keeping `caps`.
+    pub fn host(caps: &[Capability]) -> Result<Self> {
+        let release = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .context("Failed to read kernel version")?;
+        Ok(Target {
+            caps: caps.iter().map(Capability::to_string).collect(),
+            kernel: parse_kernel_version(&release)?,
+        })
+    }
+}
+
+/// A compiled BPF program, ready to be installed.
+#[derive(Clone)]
+pub struct SeccompFilter(Vec<libc::sock_filter>);
+
+impl std::fmt::Debug for SeccompFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SeccompFilter({} instructions)", self.0.len())
+    }
+}
+
+impl Profile {
+    pub fn default_profile() -> Self {
+        serde_json::from_str(DEFAULT_PROFILE).expect("built-in seccomp profile is valid")
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read seccomp profile {:?}", path))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid seccomp profile {:?}", path))
+    }
+
+    pub fn compile(&self, target: &Target) -> Result<SeccompFilter> {
+        let default_ret = self.default_action.ret(self.default_errno_ret)?;
+
+        // Rules for the same syscall are checked in profile order and the
+        // first whose arguments match wins.
+        let mut by_nr: BTreeMap<u32, Vec<Insn>> = BTreeMap::new();
+        for rule in &self.syscalls {
+            if !rule.applies_to(target) {
+                continue;
+            }
+            let ret = rule.action.ret(rule.errno_ret)?;
+            let code = rule_code(&rule.args, ret)?;
+            for name in rule.names.iter().chain(&rule.name) {
+                // Profiles list syscalls for every architecture at once.
+                if let Some(nr) = syscall_number(name) {
+                    by_nr.entry(nr).or_default().extend_from_slice(&code);
+                }
+            }
+        }
+
+        let mut program = vec![
+            stmt(LD_ABS, ARCH_OFFSET),
+            jump(JMP_JEQ, ARCH.audit, 1, 0),
+            stmt(RET, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(LD_ABS, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(JMP_JGE, X32_SYSCALL_BIT, 0, 1),
+            stmt(RET, libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+        for (nr, mut body) in by_nr {
+            body.push(stmt(RET, default_ret));
+            match u8::try_from(body.len()) {
+                Ok(len) => program.push(jump(JMP_JEQ, nr, 0, len)),
+                Err(_) => {
+                    program.extend([jump(JMP_JEQ, nr, 1, 0), stmt(JMP_JA, body.len() as u32)])
+                }
+            }
+            program.extend(body);
+        }
+        program.push(stmt(RET, default_ret));
+
+        if program.len() > MAX_INSNS {
+            bail!(
+                "Seccomp profile compiles to {} instructions, more than the kernel's limit of {}",
+                program.len(),
+                MAX_INSNS
+            );
+        }
+        Ok(SeccompFilter(program))
+    }
+}
+
+impl SyscallRule {
+    fn applies_to(&self, target: &Target) -> bool {
+        let arch_matches =
+            |arches: &[String]| arches.iter().any(|a| a == ARCH.name || a == ARCH.scmp_name);
+        let kernel_at_least =
+            |min: &str| parse_kernel_version(min).is_ok_and(|min| target.kernel >= min);
+
+        let inc = &self.includes;
+        if !inc.arches.is_empty() && !arch_matches(&inc.arches) {
+            return false;
+        }
+        if !inc.caps.iter().all(|c| target.caps.contains(c)) {
+            return false;
+        }
+        if let Some(min) = &inc.min_kernel
+            && !kernel_at_least(min)
+        {
+            return false;
+        }
+
+        let exc = &self.excludes;
+        if arch_matches(&exc.arches) || exc.caps.iter().any(|c| target.caps.contains(c)) {
+            return false;
+        }
+        if let Some(min) = &exc.min_kernel
+            && kernel_at_least(min)
+        {
+            return false;
+        }
+        true
+    }
+}
+
+impl SeccompFilter {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Installs the filter on the calling thread. The caller needs
+    /// CAP_SYS_ADMIN in its user namespace or `no_new_privs` set.
+    pub fn install(&self) -> Result<()> {
+        let prog = libc::sock_fprog {
+            len: self.0.len() as u16,
+            filter: self.0.as_ptr() as *mut libc::sock_filter,
+        };
+        let ret = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to install seccomp filter");
+        }
+        Ok(())
+    }
+}
+
+/// Looks up a syscall number on the architecture being compiled for.
+fn syscall_number(name: &str) -> Option<u32> {
+    SYSCALLS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, nr)| *nr as u32)
+}
+
+/// Parses the `major.minor` prefix of a kernel release such as
+/// `6.8.0-45-generic`.
+fn parse_kernel_version(s: &str) -> Result<(u32, u32)> {
+    let invalid = || format!("Invalid kernel version {:?}", s.trim());
+    let mut parts = s.trim().split(['.', '-']);
+    let major = parts.next().unwrap_or("").parse().with_context(invalid)?;
+    let minor = parts.next().unwrap_or("0").parse().with_context(invalid)?;
+    Ok((major, minor))
+}
+
+/// Where a jump inside one rule lands.
+#[derive(Debug, Clone, Copy)]
+enum Dest {
+    /// The next instruction.
+    Next,
+    /// The instruction after the current argument check.
+    Pass,
+    /// The first instruction after the rule.
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op32 {
+    Stmt(u16, u32),
+    Jump(u16, u32, Dest, Dest),
+}
+
+type Insn = libc::sock_filter;
+
+fn stmt(code: u16, k: u32) -> Insn {
+    Insn {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Insn {
+    Insn { code, jt, jf, k }
+}
+
+/// Compiles one rule: every argument check falls through on success and
+/// jumps past the rule on failure, so a rule without arguments is a bare
+/// return.
+fn rule_code(args: &[ArgRule], ret: u32) -> Result<Vec<Insn>> {
+    let mut checks = Vec::new();
+    for arg in args {
+        if arg.index > 5 {
+            bail!("Invalid seccomp argument index {}", arg.index);
+        }
+        checks.push(arg_check(arg));
+    }
+
+    let len: usize = checks.iter().map(Vec::len).sum::<usize>() + 1;
+    let mut code = Vec::with_capacity(len);
+    for check in checks {
+        let end = code.len() + check.len();
+        for op in check {
+            let pos = code.len();
+            let offset = |dest| match dest {
+                Dest::Next => 0,
+                Dest::Pass => (end - pos - 1) as u8,
+                Dest::Fail => (len - pos - 1) as u8,
+            };
+            code.push(match op {
+                Op32::Stmt(c, k) => stmt(c, k),
+                Op32::Jump(c, k, jt, jf) => jump(c, k, offset(jt), offset(jf)),
+            });
+        }
+    }
+    code.push(stmt(RET, ret));
+    Ok(code)
+}
+
+/// Compares a 64-bit argument one 32-bit half at a time, high half first.
+/// Both supported architectures are little-endian.
+fn arg_check(arg: &ArgRule) -> Vec<Op32> {
+    use Dest::*;
+    let lo_offset = ARGS_OFFSET + 8 * arg.index;
+    let hi_offset = lo_offset + 4;
+    let (hi, lo) = ((arg.value >> 32) as u32, arg.value as u32);
+    let ld_hi = Op32::Stmt(LD_ABS, hi_offset);
+    let ld_lo = Op32::Stmt(LD_ABS, lo_offset);
+    let j = |code, k, jt, jf| Op32::Jump(code, k, jt, jf);
+
+    match arg.op {
+        Op::Eq => vec![
+            ld_hi,
+            j(JMP_JEQ, hi, Next, Fail),
+            ld_lo,
+            j(JMP_JEQ, lo, Next, Fail),
+        ],
+        Op::Ne => vec![
+            ld_hi,
+            j(JMP_JEQ, hi, Next, Pass),
+            ld_lo,
+            j(JMP_JEQ, lo, Fail, Next),
+        ],
+        Op::MaskedEq => {
+            let (want_hi, want_lo) = ((arg.value_two >> 32) as u32, arg.value_two as u32);
+            vec![
+                ld_hi,
+                Op32::Stmt(ALU_AND, hi),
+                j(JMP_JEQ, want_hi, Next, Fail),
+                ld_lo,
+                Op32::Stmt(ALU_AND, lo),
+                j(JMP_JEQ, want_lo, Next, Fail),
+            ]
+        }
+        Op::Gt | Op::Ge => {
+            let low = if arg.op == Op::Gt { JMP_JGT } else { JMP_JGE };
+            vec![
+                ld_hi,
+                j(JMP_JGT, hi, Pass, Next),
+                j(JMP_JEQ, hi, Next, Fail),
+                ld_lo,
+                j(low, lo, Next, Fail),
+            ]
+        }
+        Op::Lt | Op::Le => {
+            // arg < v is !(arg >= v); arg <= v is !(arg > v).
+            let low = if arg.op == Op::Lt { JMP_JGE } else { JMP_JGT };
+            vec![
+                ld_hi,
+                j(JMP_JGT, hi, Fail, Next),
+                j(JMP_JEQ, hi, Next, Pass),
+                ld_lo,
+                j(low, lo, Fail, Next),
+            ]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork};
+
+    /// Runs a compiled program the way the kernel would for one syscall.
+    fn evaluate(filter: &SeccompFilter, arch: u32, nr: u32, args: [u64; 6]) -> u32 {
+        let load = |offset: u32| -> u32 {
+            match offset {
+                NR_OFFSET => nr,
+                ARCH_OFFSET => arch,
+                _ => {
+                    let arg = args[((offset - ARGS_OFFSET) / 8) as usize];
+                    if (offset - ARGS_OFFSET).is_multiple_of(8) {
+                        arg as u32
+                    } else {
+                        (arg >> 32) as u32
+                    }
+                }
+            }
+        };
+        let (mut pc, mut acc) = (0, 0);
+        loop {
+            let insn = filter.0[pc];
+            pc += 1;
+            let taken = match insn.code {
+                LD_ABS => {
+                    acc = load(insn.k);
+                    continue;
+                }
+                ALU_AND => {
+                    acc &= insn.k;
+                    continue;
+                }
+                RET => return insn.k,
+                JMP_JA => {
+                    pc += insn.k as usize;
+                    continue;
+                }
+                JMP_JEQ => acc == insn.k,
+                JMP_JGT => acc > insn.k,
+                JMP_JGE => acc >= insn.k,
+                code => panic!("unexpected opcode {:#x}", code),
+            };
+            pc += if taken { insn.jt } else { insn.jf } as usize;
+        }
+    }
+
+    fn nr(name: &str) -> u32 {
+        syscall_number(name).unwrap()
+    }
+
+    fn target() -> Target {
+        Target {
+            caps: Vec::new(),
+            kernel: (6, 1),
+        }
+    }
+
+    fn profile(json: &str) -> Profile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_default_profile_denies_dangerous_syscalls() {
+        let filter = Profile::default_profile().compile(&target()).unwrap();
+        let errno = |e: i32| libc::SECCOMP_RET_ERRNO | e as u32;
+
+        for name in [
+            "keyctl",
+            "add_key",
+            "bpf",
+            "userfaultfd",
+            "mount",
+            "unshare",
+        ] {
+            assert_eq!(
+                evaluate(&filter, ARCH.audit, nr(name), [0; 6]),
+                errno(libc::EPERM)
+            );
+        }
+        assert_eq!(
+            evaluate(&filter, ARCH.audit, nr("clone3"), [0; 6]),
+            errno(libc::ENOSYS)
+        );
+        for name in ["read", "write", "execve", "clone", "ptrace"] {
+            assert_eq!(
+                evaluate(&filter, ARCH.audit, nr(name), [0; 6]),
+                libc::SECCOMP_RET_ALLOW
+            );
+        }
+
+        // Anything not on the allowlist is denied.
+        assert_eq!(
+            evaluate(&filter, ARCH.audit, nr("kexec_load"), [0; 6]),
+            errno(libc::EPERM)
+        );
+        let thread = (libc::CLONE_VM | libc::CLONE_THREAD | libc::CLONE_SIGHAND) as u64;
+        let new_user = libc::CLONE_NEWUSER as u64;
+        assert_eq!(
+            evaluate(&filter, ARCH.audit, nr("clone"), [thread, 0, 0, 0, 0, 0]),
+            libc::SECCOMP_RET_ALLOW
+        );
+        assert_eq!(
+            evaluate(&filter, ARCH.audit, nr("clone"), [new_user, 0, 0, 0, 0, 0]),
+            errno(libc::EPERM)
+        );
+        assert_eq!(
+            evaluate(
+                &filter,
+                ARCH.audit,
+                nr("socket"),
+                [libc::AF_VSOCK as u64, 0, 0, 0, 0, 0]
+            ),
+            errno(libc::EPERM)
+        );
+    }
+
+    #[test]
+    fn test_default_profile_follows_capabilities() {
+        let target = Target {
+            caps: vec!["CAP_SYS_ADMIN".to_string()],
+            kernel: (6, 1),
+        };
+        let filter = Profile::default_profile().compile(&target).unwrap();
+        let new_user = libc::CLONE_NEWUSER as u64;
+
+        for (name, args) in [
+            ("clone", [new_user, 0, 0, 0, 0, 0]),
+            ("clone3", [0; 6]),
+            ("mount", [0; 6]),
+            ("unshare", [0; 6]),
+        ] {
+            assert_eq!(
+                evaluate(&filter, ARCH.audit, nr(name), args),
+                libc::SECCOMP_RET_ALLOW
+            );
+        }
+        assert_eq!(
+            evaluate(&filter, ARCH.audit, nr("reboot"), [0; 6]),
+            libc::SECCOMP_RET_ERRNO | EPERM
+        );
+    }
+
+    #[test]
+    fn test_foreign_arch_is_killed() {
+        let filter = Profile::default_profile().compile(&target()).unwrap();
+        assert_eq!(
+            evaluate(&filter, 0x4000_0003, nr("read"), [0; 6]),
+            libc::SECCOMP_RET_KILL_PROCESS
+        );
+    }
+
+    #[test]
+    fn test_parse_profile_fields() {
+        let p = profile(
+            r#"{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "defaultErrnoRet": 38,
+                "architectures": ["SCMP_ARCH_X86_64"],
+                "syscalls": [
+                    {"name": "read", "action": "SCMP_ACT_ALLOW"},
+                    {"names": ["personality"], "action": "SCMP_ACT_ALLOW",
+                     "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}],
+                     "includes": {"minKernel": "4.8"}}
+                ]
+            }"#,
+        );
+        assert_eq!(p.default_action, Action::Errno);
+        assert_eq!(p.default_errno_ret, Some(38));
+        assert_eq!(p.syscalls[0].name.as_deref(), Some("read"));
+        assert_eq!(p.syscalls[1].args[0].op, Op::Eq);
+        assert_eq!(p.syscalls[1].includes.min_kernel.as_deref(), Some("4.8"));
+
+        assert!(serde_json::from_str::<Profile>(r#"{"defaultAction": "SCMP_ACT_NOPE"}"#).is_err());
+    }
+
+    #[test]
+    fn test_action_return_values() {
+        assert_eq!(Action::Allow.ret(None).unwrap(), 0x7fff_0000);
+        assert_eq!(Action::Errno.ret(None).unwrap(), 0x0005_0001);
+        assert_eq!(Action::Errno.ret(Some(38)).unwrap(), 0x0005_0026);
+        assert_eq!(Action::Trace.ret(Some(7)).unwrap(), 0x7ff0_0007);
+        assert_eq!(Action::KillThread.ret(None).unwrap(), 0);
+        assert_eq!(Action::KillProcess.ret(None).unwrap(), 0x8000_0000);
+        assert!(Action::Notify.ret(None).is_err());
+    }
+
+    #[test]
+    fn test_argument_comparisons() {
+        const BIG: u64 = 0x1_0000_0005;
+        // (op, value, [(argument, denied)])
+        type Case<'a> = (&'a str, u64, &'a [(u64, bool)]);
+        let cases: &[Case] = &[
+            (
+                "SCMP_CMP_EQ",
+                BIG,
+                &[(BIG, true), (5, false), (BIG + 1, false)],
+            ),
+            (
+                "SCMP_CMP_NE",
+                BIG,
+                &[(BIG, false), (5, true), (0x2_0000_0005, true)],
+            ),
+            (
+                "SCMP_CMP_GT",
+                BIG,
+                &[
+                    (BIG + 1, true),
+                    (BIG, false),
+                    (0x2_0000_0000, true),
+                    (9, false),
+                ],
+            ),
+            (
+                "SCMP_CMP_GE",
+                BIG,
+                &[(BIG, true), (BIG - 1, false), (0x2_0000_0000, true)],
+            ),
+            (
+                "SCMP_CMP_LT",
+                BIG,
+                &[
+                    (BIG - 1, true),
+                    (BIG, false),
+                    (9, true),
+                    (0x2_0000_0000, false),
+                ],
+            ),
+            (
+                "SCMP_CMP_LE",
+                BIG,
+                &[(BIG, true), (BIG + 1, false), (9, true)],
+            ),
+        ];
+        for (op, value, checks) in cases {
+            let p = profile(&format!(
+                r#"{{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [{{"names": ["personality"],
+                    "action": "SCMP_ACT_ERRNO", "args": [{{"index": 2, "value": {}, "op": "{}"}}]}}]}}"#,
+                value, op
+            ));
+            let filter = p.compile(&target()).unwrap();
+            for (arg, denied) in *checks {
+                let ret = evaluate(
+                    &filter,
+                    ARCH.audit,
+                    nr("personality"),
+                    [0, 0, *arg, 0, 0, 0],
+                );
+                assert_eq!(ret != libc::SECCOMP_RET_ALLOW, *denied, "{} {:#x}", op, arg);
+            }
+        }
+    }
+
+    #[test]
+    fn test_masked_eq_and_multiple_args() {
+        // Deny clone only when CLONE_NEWUSER is set and the second arg is 0.
+        let p = profile(&format!(
+            r#"{{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [{{"names": ["clone"],
+                "action": "SCMP_ACT_ERRNO", "args": [
+                    {{"index": 0, "value": {flag}, "valueTwo": {flag}, "op": "SCMP_CMP_MASKED_EQ"}},
+                    {{"index": 1, "value": 0, "op": "SCMP_CMP_EQ"}}]}}]}}"#,
+            flag = libc::CLONE_NEWUSER
+        ));
+        let filter = p.compile(&target()).unwrap();
+        let newuser = libc::CLONE_NEWUSER as u64 | libc::SIGCHLD as u64;
+        let check = |args| evaluate(&filter, ARCH.audit, nr("clone"), args);
+
+        assert_ne!(check([newuser, 0, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(check([newuser, 1, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(
+            check([libc::SIGCHLD as u64, 0, 0, 0, 0, 0]),
+            libc::SECCOMP_RET_ALLOW
+        );
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let p = profile(
+            r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [
+                {"names": ["personality"], "action": "SCMP_ACT_ALLOW",
+                 "args": [{"index": 0, "value": 0, "op": "SCMP_CMP_EQ"}]},
+                {"names": ["personality"], "action": "SCMP_ACT_LOG"}]}"#,
+        );
+        let filter = p.compile(&target()).unwrap();
+        let check = |arg| evaluate(&filter, ARCH.audit, nr("personality"), [arg, 0, 0, 0, 0, 0]);
+        assert_eq!(check(0), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(check(8), libc::SECCOMP_RET_LOG);
+        assert_eq!(
+            evaluate(&filter, ARCH.audit, nr("read"), [0; 6]),
+            libc::SECCOMP_RET_ERRNO | libc::EPERM as u32
+        );
+    }
+
+    #[test]
+    fn test_includes_and_excludes() {
+        let rule = |filters: &str| -> SyscallRule {
+            serde_json::from_str(&format!(
+                r#"{{"names": ["read"], "action": "SCMP_ACT_ALLOW", {}}}"#,
+                filters
+            ))
+            .unwrap()
+        };
+        let mut t = target();
+        assert!(rule(r#""includes": {"arches": ["amd64", "arm64"]}"#).applies_to(&t));
+        assert!(!rule(r#""includes": {"arches": ["s390x"]}"#).applies_to(&t));
+        assert!(!rule(r#""excludes": {"arches": ["amd64", "arm64"]}"#).applies_to(&t));
+        assert!(rule(r#""includes": {"minKernel": "4.8"}"#).applies_to(&t));
+        assert!(!rule(r#""includes": {"minKernel": "6.2"}"#).applies_to(&t));
+        assert!(!rule(r#""excludes": {"minKernel": "5.0"}"#).applies_to(&t));
+        assert!(!rule(r#""includes": {"caps": ["CAP_SYS_ADMIN"]}"#).applies_to(&t));
+        assert!(rule(r#""excludes": {"caps": ["CAP_SYS_ADMIN"]}"#).applies_to(&t));
+
+        t.caps.push("CAP_SYS_ADMIN".to_string());
+        assert!(rule(r#""includes": {"caps": ["CAP_SYS_ADMIN"]}"#).applies_to(&t));
+        assert!(!rule(r#""excludes": {"caps": ["CAP_SYS_ADMIN"]}"#).applies_to(&t));
+    }
+
+    #[test]
+    fn test_unknown_syscalls_are_skipped() {
+        let p = profile(
+            r#"{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [
+                {"names": ["not_a_syscall", "bpf"], "action": "SCMP_ACT_ERRNO"}]}"#,
+        );
+        let filter = p.compile(&target()).unwrap();
+        assert_ne!(
+            evaluate(&filter, ARCH.audit, nr("bpf"), [0; 6]),
+            libc::SECCOMP_RET_ALLOW
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_argument_index() {
+        let p = profile(
+            r#"{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [{"names": ["read"],
+                "action": "SCMP_ACT_ERRNO", "args": [{"index": 6, "value": 0, "op": "SCMP_CMP_EQ"}]}]}"#,
+        );
+        assert!(p.compile(&target()).is_err());
+    }
+
+    #[test]
+    fn test_parse_kernel_version() {
+        assert_eq!(parse_kernel_version("6.8.0-45-generic\n").unwrap(), (6, 8));
+        assert_eq!(parse_kernel_version("4.8").unwrap(), (4, 8));
+        assert_eq!(parse_kernel_version("5").unwrap(), (5, 0));
+        assert!(parse_kernel_version("linux").is_err());
+    }
+
+    #[test]
+    fn test_load_profile_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profile.json");
+        fs::write(&path, DEFAULT_PROFILE).unwrap();
+        assert_eq!(Profile::load(&path).unwrap(), Profile::default_profile());
+
+        fs::write(&path, "{").unwrap();
+        assert!(Profile::load(&path).is_err());
+        assert!(Profile::load(&dir.path().join("missing.json")).is_err());
+    }
+
+    #[test]
+    fn test_installed_filter_denies_syscall() {
+        let p = profile(
+            r#"{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [
+                {"names": ["getppid"], "action": "SCMP_ACT_ERRNO", "errnoRet": 13}]}"#,
+        );
+        let target = Target::host(&[]).unwrap();
+        let default = Profile::default_profile().compile(&target).unwrap();
+        let filter = p.compile(&target).unwrap();
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+                let code = if default.install().is_err() || filter.install().is_err() {
+                    2
+                } else {
+                    let ret = unsafe { libc::syscall(libc::SYS_getppid) };
+                    let errno = io::Error::last_os_error().raw_os_error();
+                    if ret == -1 && errno == Some(libc::EACCES) {
+                        0
+                    } else {
+                        1
+                    }
+                };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
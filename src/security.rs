@@ -0,0 +1,165 @@
+use anyhow::{Context, Result, bail};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// One `--security-opt` value, in Docker's `KEY=VALUE` syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecurityOpt {
+    /// `seccomp=unconfined`: run without a syscall filter.
+    SeccompUnconfined,
+    /// `seccomp=<file>`: use this profile instead of the built-in one.
+    SeccompProfile(PathBuf),
+    /// `no-new-privileges[=true|false]`: whether exec may gain privileges
+    /// through setuid binaries or file capabilities.
+    NoNewPrivileges(bool),
+    /// `apparmor=<profile>`: run the container under a loaded profile.
+    AppArmor(String),
+    /// `apparmor=unconfined`: run without an AppArmor profile.
+    AppArmorUnconfined,
+    /// `label=...`: one part of the container's SELinux label.
+    Label(LabelOpt),
+}
+
+/// The `label=` options Docker accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelOpt {
+    User(String),
+    Role(String),
+    Type(String),
+    Level(String),
+    /// `label=disable`: run without an SELinux label.
+    Disable,
+}
+
+impl FromStr for LabelOpt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "disable" {
+            return Ok(LabelOpt::Disable);
+        }
+        let (part, value) = match s.split_once(':') {
+            Some((part, value)) if !value.is_empty() => (part, value.to_string()),
+            _ => bail!(
+                "Invalid label option {:?}, expected user:, role:, type:, level: or disable",
+                s
+            ),
+        };
+        match part {
+            "user" => Ok(LabelOpt::User(value)),
+            "role" => Ok(LabelOpt::Role(value)),
+            "type" => Ok(LabelOpt::Type(value)),
+            "level" => Ok(LabelOpt::Level(value)),
+            _ => bail!("Unknown label option {:?}", part),
+        }
+    }
+}
+
+impl FromStr for SecurityOpt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "no-new-privileges" {
+            return Ok(SecurityOpt::NoNewPrivileges(true));
+        }
+        let Some((key, value)) = s.split_once('=') else {
+            bail!("Invalid security option {:?}: expected KEY=VALUE", s);
+        };
+        match key {
+            "no-new-privileges" => match value {
+                "true" => Ok(SecurityOpt::NoNewPrivileges(true)),
+                "false" => Ok(SecurityOpt::NoNewPrivileges(false)),
+                _ => bail!("no-new-privileges must be true or false, not {:?}", value),
+            },
+            "seccomp" if value == "unconfined" => Ok(SecurityOpt::SeccompUnconfined),
+            "seccomp" if !value.is_empty() => Ok(SecurityOpt::SeccompProfile(value.into())),
+            "seccomp" => bail!("seccomp needs a profile path or \"unconfined\""),
+            "apparmor" if value == "unconfined" => Ok(SecurityOpt::AppArmorUnconfined),
+            "apparmor" if !value.is_empty() => Ok(SecurityOpt::AppArmor(value.to_string())),
+            "apparmor" => bail!("apparmor needs a profile name or \"unconfined\""),
+            "label" => value.parse().map(SecurityOpt::Label),
+            _ => bail!("Unknown security option {:?}", key),
+        }
+    }
+}
+
+/// Stops the calling process and its children from gaining privileges on
+/// exec. It also lets a seccomp filter be installed without CAP_SYS_ADMIN.
+pub fn set_no_new_privs() -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to set no_new_privs");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_seccomp_options() {
+        assert_eq!(
+            "seccomp=unconfined".parse::<SecurityOpt>().unwrap(),
+            SecurityOpt::SeccompUnconfined
+        );
+        assert_eq!(
+            "seccomp=/etc/profile.json".parse::<SecurityOpt>().unwrap(),
+            SecurityOpt::SeccompProfile("/etc/profile.json".into())
+        );
+    }
+
+    #[test]
+    fn test_parse_no_new_privileges() {
+        for (opt, enabled) in [
+            ("no-new-privileges", true),
+            ("no-new-privileges=true", true),
+            ("no-new-privileges=false", false),
+        ] {
+            assert_eq!(
+                opt.parse::<SecurityOpt>().unwrap(),
+                SecurityOpt::NoNewPrivileges(enabled)
+            );
+        }
+        assert!("no-new-privileges=maybe".parse::<SecurityOpt>().is_err());
+    }
+
+    #[test]
+    fn test_parse_lsm_options() {
+        assert_eq!(
+            "apparmor=docker-default".parse::<SecurityOpt>().unwrap(),
+            SecurityOpt::AppArmor("docker-default".to_string())
+        );
+        assert_eq!(
+            "apparmor=unconfined".parse::<SecurityOpt>().unwrap(),
+            SecurityOpt::AppArmorUnconfined
+        );
+        assert_eq!(
+            "label=type:spc_t".parse::<SecurityOpt>().unwrap(),
+            SecurityOpt::Label(LabelOpt::Type("spc_t".to_string()))
+        );
+        assert_eq!(
+            "label=level:s0:c100,c200".parse::<SecurityOpt>().unwrap(),
+            SecurityOpt::Label(LabelOpt::Level("s0:c100,c200".to_string()))
+        );
+        assert_eq!(
+            "label=disable".parse::<SecurityOpt>().unwrap(),
+            SecurityOpt::Label(LabelOpt::Disable)
+        );
+        for bad in [
+            "apparmor=",
+            "label=type:",
+            "label=color:red",
+            "label=enable",
+        ] {
+            assert!(bad.parse::<SecurityOpt>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_options() {
+        assert!("seccomp".parse::<SecurityOpt>().is_err());
+        assert!("seccomp=".parse::<SecurityOpt>().is_err());
+        assert!("selinux=foo".parse::<SecurityOpt>().is_err());
+    }
+}
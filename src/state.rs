@@ -0,0 +1,376 @@
+use crate::image::oci::Digest;
+use crate::namespace::in_user_namespace;
+use anyhow::{Context, Result};
+use nix::sys::signal::kill;
+use nix::unistd::{Pid, getuid};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STATE_FILE: &str = "state.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Running,
+    /// Frozen through the cgroup freezer.
+    Paused,
+    Stopped,
+}
+
+/// Persistent record of a container, stored as
+/// `<root>/containers/<id>/state.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    pub status: Status,
+    pub pid: Option<i32>,
+    pub rootfs: PathBuf,
+    pub command: String,
+    pub args: Vec<String>,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub overlay: bool,
+    pub exit_code: Option<i32>,
+    /// cgroup directory created for the container, removed when it exits.
+    #[serde(default)]
+    pub cgroup: Option<PathBuf>,
+    /// Whether the kernel OOM-killed a process in the container.
+    #[serde(rename = "OOMKilled", default)]
+    pub oom_killed: bool,
+    /// Manifest digest of the image the container was created from.
+    #[serde(default)]
+    pub image: Option<Digest>,
+    /// Ports the image declares, such as `80/tcp`.
+    #[serde(default)]
+    pub exposed_ports: Vec<String>,
+    /// SELinux level, whose MCS categories no other live container shares.
+    #[serde(default)]
+    pub selinux_level: Option<String>,
+}
+
+impl ContainerState {
+    pub fn new(id: &str, rootfs: &Path, command: &str, args: &[String]) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            id: id.to_string(),
+            status: Status::Created,
+            pid: None,
+            rootfs: rootfs.to_path_buf(),
+            command: command.to_string(),
+            args: args.to_vec(),
+            created,
+            overlay: false,
+            exit_code: None,
+            cgroup: None,
+            oom_killed: false,
+            image: None,
+            exposed_ports: Vec::new(),
+            selinux_level: None,
+        }
+    }
+
+    /// Whether the recorded process still exists. A record left `running` by
+    /// a crashed runtime reports `false` here.
+    pub fn is_alive(&self) -> bool {
+        match (self.status, self.pid) {
+            (Status::Running | Status::Paused, Some(pid)) => kill(Pid::from_raw(pid), None).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// Data directory used when `--root` is not given.
+pub fn default_root() -> PathBuf {
+    if getuid().is_root() {
+        return PathBuf::from("/var/lib/docker-clone");
+    }
+
+    if let Ok(data_home) = std::env::var("XDG_DATA_HOME") {
+        return PathBuf::from(data_home).join("docker-clone");
+    }
+
+    match std::env::var("HOME") {
+        Ok(home) => PathBuf::from(home).join(".local/share/docker-clone"),
+        Err(_) => std::env::temp_dir().join("docker-clone"),
+    }
+}
+
+/// Returns a random 12 character hex identifier.
+pub fn generate_id() -> Result<String> {
+    let mut bytes = [0u8; 6];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .context("Failed to read /dev/urandom")?;
+
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Checks that `id` has the form [`generate_id`] produces, so an id from the
+/// command line cannot name a path outside the container's directory.
+pub fn validate_id(id: &str) -> Result<()> {
+    if id.len() != 12 || !id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        anyhow::bail!("Invalid container ID {:?}: expected 12 hex digits", id);
+    }
+    Ok(())
+}
+
+pub struct StateStore {
+    root: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn container_dir(&self, id: &str) -> PathBuf {
+        self.root.join("containers").join(id)
+    }
+
+    pub fn save(&self, state: &ContainerState) -> Result<()> {
+        let dir = self.container_dir(&state.id);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create state directory {:?}", dir))?;
+
+        let json = serde_json::to_string_pretty(state)?;
+
+        // Write then rename so readers never observe a half-written file.
+        let tmp = dir.join(format!("{}.tmp", STATE_FILE));
+        std::fs::write(&tmp, json).with_context(|| format!("Failed to write {:?}", tmp))?;
+        std::fs::rename(&tmp, dir.join(STATE_FILE))
+            .with_context(|| format!("Failed to save state for container {}", state.id))?;
+
+        Ok(())
+    }
+
+    pub fn load(&self, id: &str) -> Result<ContainerState> {
+        validate_id(id)?;
+        self.read(id)
+    }
+
+    fn read(&self, id: &str) -> Result<ContainerState> {
+        let path = self.container_dir(id).join(STATE_FILE);
+        let json =
+            std::fs::read_to_string(&path).with_context(|| format!("No such container: {}", id))?;
+
+        serde_json::from_str(&json).with_context(|| format!("Corrupt state file {:?}", path))
+    }
+
+    /// All containers with a state record, oldest first.
+    pub fn list(&self) -> Result<Vec<ContainerState>> {
+        let dir = self.root.join("containers");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut states = Vec::new();
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}", dir))? {
+            let id = entry?.file_name().to_string_lossy().to_string();
+            if self.container_dir(&id).join(STATE_FILE).exists() {
+                states.push(self.read(&id)?);
+            }
+        }
+
+        states.sort_by_key(|s| s.created);
+        Ok(states)
+    }
+
+    /// Deletes a stopped container. With `keep`, the overlay upper directory
+    /// is left in place so its changes can still be inspected, and its path
+    /// is returned: nothing else refers to it once the record is gone.
+    pub fn remove(&self, id: &str, keep: bool) -> Result<Option<PathBuf>> {
+        let state = self.load(id)?;
+        if state.is_alive() {
+            anyhow::bail!("Container {} is still running", id);
+        }
+
+        let dir = self.container_dir(id);
+        if !keep {
+            remove_path(&dir)?;
+            return Ok(None);
+        }
+
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}", dir))? {
+            let path = entry?.path();
+            if path.file_name().is_some_and(|name| name == "upper") {
+                continue;
+            }
+            remove_path(&path)?;
+        }
+
+        let upper = dir.join("upper");
+        Ok(upper.exists().then_some(upper))
+    }
+}
+
+/// Removes a file or directory tree from a container's state directory.
+/// Rootless, files the container's non-root users created belong to
+/// subordinate ids, so that is retried as root of a user namespace.
+fn remove_path(path: &Path) -> Result<()> {
+    let remove = |path: &Path| {
+        if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    };
+    match remove(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && !getuid().is_root() => {
+            in_user_namespace(|| remove(path))
+                .map_err(|e| anyhow::anyhow!("{}", e))
+                .with_context(|| format!("Failed to remove {:?}", path))
+        }
+        result => result.with_context(|| format!("Failed to remove {:?}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample_state(id: &str) -> ContainerState {
+        ContainerState::new(id, Path::new("/tmp/rootfs"), "/bin/sh", &[])
+    }
+
+    #[test]
+    fn test_generate_id_format() {
+        let id = generate_id().unwrap();
+
+        assert_eq!(id.len(), 12);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(id, generate_id().unwrap());
+        assert!(validate_id(&id).is_ok());
+    }
+
+    #[test]
+    fn test_validate_id() {
+        assert!(validate_id("0123456789ab").is_ok());
+        for bad in [
+            "",
+            "abc",
+            "0123456789AB",
+            "0123456789abc",
+            "../../../etc",
+            "0123456789/b",
+        ] {
+            assert!(validate_id(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = StateStore::new(temp_dir.path());
+        let mut state = sample_state("abc123def456");
+        state.status = Status::Stopped;
+        state.exit_code = Some(3);
+
+        store.save(&state).unwrap();
+
+        assert_eq!(store.load("abc123def456").unwrap(), state);
+        assert!(store.load("missing").is_err());
+    }
+
+    #[test]
+    fn test_status_serialization() {
+        let json = serde_json::to_string(&Status::Running).unwrap();
+        assert_eq!(json, "\"running\"");
+    }
+
+    #[test]
+    fn test_oom_killed_serialization() {
+        let mut state = sample_state("oom");
+        state.oom_killed = true;
+
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["OOMKilled"], true);
+
+        // Records written before the field existed still load.
+        let mut json = json.as_object().unwrap().clone();
+        json.remove("OOMKilled");
+        let old: ContainerState = serde_json::from_value(json.into()).unwrap();
+        assert!(!old.oom_killed);
+    }
+
+    #[test]
+    fn test_list_containers() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = StateStore::new(temp_dir.path());
+
+        assert!(store.list().unwrap().is_empty());
+
+        store.save(&sample_state("one")).unwrap();
+        store.save(&sample_state("two")).unwrap();
+        std::fs::create_dir_all(store.container_dir("leftover")).unwrap();
+
+        let ids: Vec<String> = store.list().unwrap().into_iter().map(|s| s.id).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&"one".to_string()));
+        assert!(ids.contains(&"two".to_string()));
+    }
+
+    #[test]
+    fn test_remove_container() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = StateStore::new(temp_dir.path());
+        store.save(&sample_state("0a0000000001")).unwrap();
+        std::fs::create_dir_all(store.container_dir("0a0000000001").join("upper")).unwrap();
+
+        store.remove("0a0000000001", false).unwrap();
+
+        assert!(!store.container_dir("0a0000000001").exists());
+    }
+
+    #[test]
+    fn test_remove_container_keeps_upper() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = StateStore::new(temp_dir.path());
+        let dir = store.container_dir("0b0000000002");
+        store.save(&sample_state("0b0000000002")).unwrap();
+        std::fs::create_dir_all(dir.join("upper")).unwrap();
+        std::fs::create_dir_all(dir.join("work")).unwrap();
+
+        assert_eq!(
+            store.remove("0b0000000002", true).unwrap(),
+            Some(dir.join("upper"))
+        );
+
+        assert!(dir.join("upper").exists());
+        assert!(!dir.join("work").exists());
+        assert!(!dir.join(STATE_FILE).exists());
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remove_refuses_running_container() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = StateStore::new(temp_dir.path());
+        let mut state = sample_state("0c0000000003");
+        state.status = Status::Running;
+        state.pid = Some(std::process::id() as i32);
+        store.save(&state).unwrap();
+
+        assert!(store.remove("0c0000000003", false).is_err());
+        assert!(store.container_dir("0c0000000003").exists());
+    }
+
+    #[test]
+    fn test_paused_container_is_alive() {
+        let mut state = sample_state("frozen");
+        state.status = Status::Paused;
+        state.pid = Some(std::process::id() as i32);
+
+        assert!(state.is_alive());
+        assert_eq!(serde_json::to_string(&state.status).unwrap(), "\"paused\"");
+    }
+}
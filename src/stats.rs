@@ -0,0 +1,519 @@
+use crate::psi::{Pressure, Resource, read_pressure};
+use crate::state::StateStore;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Time between samples, both for the live table and the single sample
+/// taken by `--no-stream`.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Output format of the `stats` command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StatsFormat {
+    #[default]
+    Table,
+    /// One JSON object per container and line.
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("Unknown stats format {:?} (expected table or json)", s),
+        }
+    }
+}
+
+/// Raw counters read from a container's cgroup. Files of controllers that
+/// aren't enabled read as zero.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CgroupStats {
+    pub memory_current: u64,
+    /// `memory.max`, or `None` when unlimited.
+    pub memory_max: Option<u64>,
+    /// Page cache that can be reclaimed without swapping, from `memory.stat`.
+    pub inactive_file: u64,
+    pub cpu_usage_usec: u64,
+    pub pids_current: u64,
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+    /// Pressure stall information, `None` where the kernel has PSI disabled.
+    pub cpu_pressure: Option<Pressure>,
+    pub memory_pressure: Option<Pressure>,
+    pub io_pressure: Option<Pressure>,
+}
+
+impl CgroupStats {
+    /// Memory in use, leaving out reclaimable page cache as `docker stats`
+    /// does.
+    pub fn memory_usage(&self) -> u64 {
+        self.memory_current.saturating_sub(self.inactive_file)
+    }
+}
+
+/// One row of `stats` output.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsEntry {
+    pub id: String,
+    pub cpu_percent: f64,
+    pub memory_usage: u64,
+    pub memory_limit: Option<u64>,
+    pub memory_percent: Option<f64>,
+    pub pids: u64,
+    pub block_read: u64,
+    pub block_write: u64,
+    pub cpu_pressure: Option<Pressure>,
+    pub memory_pressure: Option<Pressure>,
+    pub io_pressure: Option<Pressure>,
+}
+
+impl StatsEntry {
+    /// Builds a row from two samples taken `elapsed` apart.
+    pub fn new(id: &str, prev: &CgroupStats, cur: &CgroupStats, elapsed: Duration) -> Self {
+        let memory_usage = cur.memory_usage();
+        Self {
+            id: id.to_string(),
+            cpu_percent: cpu_percent(prev.cpu_usage_usec, cur.cpu_usage_usec, elapsed),
+            memory_usage,
+            memory_limit: cur.memory_max,
+            memory_percent: cur
+                .memory_max
+                .filter(|&max| max > 0)
+                .map(|max| memory_usage as f64 * 100.0 / max as f64),
+            pids: cur.pids_current,
+            block_read: cur.io_read_bytes,
+            block_write: cur.io_write_bytes,
+            cpu_pressure: cur.cpu_pressure,
+            memory_pressure: cur.memory_pressure,
+            io_pressure: cur.io_pressure,
+        }
+    }
+}
+
+/// Reads the counters of the cgroup at `cgroup`.
+pub fn read_stats(cgroup: &Path) -> Result<CgroupStats> {
+    // memory.current only exists below the root; treat it as the marker that
+    // the group is still there.
+    let memory_current = std::fs::read_to_string(cgroup.join("memory.current"))
+        .with_context(|| format!("Failed to read stats from {:?}", cgroup))?;
+
+    let memory_stat = parse_flat_keyed(&read_optional(cgroup, "memory.stat"));
+    let cpu_stat = parse_flat_keyed(&read_optional(cgroup, "cpu.stat"));
+    let (io_read_bytes, io_write_bytes) = parse_io_stat(&read_optional(cgroup, "io.stat"));
+
+    Ok(CgroupStats {
+        memory_current: memory_current.trim().parse().unwrap_or(0),
+        memory_max: parse_limit(&read_optional(cgroup, "memory.max")),
+        inactive_file: memory_stat.get("inactive_file").copied().unwrap_or(0),
+        cpu_usage_usec: cpu_stat.get("usage_usec").copied().unwrap_or(0),
+        pids_current: read_optional(cgroup, "pids.current")
+            .trim()
+            .parse()
+            .unwrap_or(0),
+        io_read_bytes,
+        io_write_bytes,
+        cpu_pressure: read_pressure(cgroup, Resource::Cpu).ok(),
+        memory_pressure: read_pressure(cgroup, Resource::Memory).ok(),
+        io_pressure: read_pressure(cgroup, Resource::Io).ok(),
+    })
+}
+
+fn read_optional(cgroup: &Path, file: &str) -> String {
+    std::fs::read_to_string(cgroup.join(file)).unwrap_or_default()
+}
+
+/// Parses "key value" lines as found in `memory.stat` and `cpu.stat`.
+fn parse_flat_keyed(content: &str) -> HashMap<String, u64> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key.to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Sums `rbytes` and `wbytes` over all devices in `io.stat`, whose lines
+/// look like `8:0 rbytes=4096 wbytes=0 rios=1 wios=0 dbytes=0 dios=0`.
+fn parse_io_stat(content: &str) -> (u64, u64) {
+    let mut read = 0;
+    let mut write = 0;
+    for field in content
+        .lines()
+        .flat_map(|line| line.split_whitespace().skip(1))
+    {
+        match field.split_once('=') {
+            Some(("rbytes", value)) => read += value.parse().unwrap_or(0),
+            Some(("wbytes", value)) => write += value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    (read, write)
+}
+
+fn parse_limit(content: &str) -> Option<u64> {
+    content.trim().parse().ok()
+}
+
+/// CPU time used between two `usage_usec` readings as a percentage of one
+/// CPU, so a container busy on two cores reports 200%.
+pub fn cpu_percent(prev_usec: u64, cur_usec: u64, elapsed: Duration) -> f64 {
+    let elapsed_usec = elapsed.as_micros();
+    if elapsed_usec == 0 {
+        return 0.0;
+    }
+    cur_usec.saturating_sub(prev_usec) as f64 * 100.0 / elapsed_usec as f64
+}
+
+/// Formats a byte count with binary units, e.g. `1.5MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+pub fn format_table(entries: &[StatsEntry]) -> String {
+    let mut table = format!(
+        "{:<14}{:<10}{:<24}{:<10}{:<22}{:<6}{}\n",
+        "CONTAINER ID",
+        "CPU %",
+        "MEM USAGE / LIMIT",
+        "MEM %",
+        "BLOCK I/O",
+        "PIDS",
+        "PRESSURE CPU / MEM / IO"
+    );
+
+    for entry in entries {
+        let limit = entry
+            .memory_limit
+            .map(format_bytes)
+            .unwrap_or_else(|| "max".to_string());
+        let memory_percent = entry
+            .memory_percent
+            .map(|p| format!("{:.2}%", p))
+            .unwrap_or_else(|| "--".to_string());
+
+        // Share of the last 10s in which some task was stalled.
+        let pressure = [entry.cpu_pressure, entry.memory_pressure, entry.io_pressure]
+            .map(|p| {
+                p.map(|p| format!("{:.2}%", p.some.avg10))
+                    .unwrap_or_else(|| "--".to_string())
+            })
+            .join(" / ");
+
+        table.push_str(&format!(
+            "{:<14}{:<10}{:<24}{:<10}{:<22}{:<6}{}\n",
+            entry.id,
+            format!("{:.2}%", entry.cpu_percent),
+            format!("{} / {}", format_bytes(entry.memory_usage), limit),
+            memory_percent,
+            format!(
+                "{} / {}",
+                format_bytes(entry.block_read),
+                format_bytes(entry.block_write)
+            ),
+            entry.pids,
+            pressure
+        ));
+    }
+
+    table
+}
+
+/// Cgroups of the running containers named by `ids`, or of every running
+/// container when `ids` is empty.
+fn resolve_targets(store: &StateStore, ids: &[String]) -> Result<Vec<(String, PathBuf)>> {
+    if ids.is_empty() {
+        return Ok(store
+            .list()?
+            .into_iter()
+            .filter(|state| state.is_alive())
+            .filter_map(|state| Some((state.id, state.cgroup?)))
+            .collect());
+    }
+
+    let mut targets = Vec::new();
+    for id in ids {
+        let state = store.load(id)?;
+        if !state.is_alive() {
+            anyhow::bail!("Container {} is not running", id);
+        }
+        let cgroup = state
+            .cgroup
+            .with_context(|| format!("Container {} has no cgroup", id))?;
+        targets.push((state.id, cgroup));
+    }
+    Ok(targets)
+}
+
+fn sample(targets: &[(String, PathBuf)]) -> HashMap<String, CgroupStats> {
+    targets
+        .iter()
+        .filter_map(|(id, cgroup)| Some((id.clone(), read_stats(cgroup).ok()?)))
+        .collect()
+}
+
+fn print_entries(entries: &[StatsEntry], format: StatsFormat, stream: bool) -> Result<()> {
+    match format {
+        StatsFormat::Table => {
+            if stream {
+                // Clear the screen and move the cursor home.
+                print!("\x1b[2J\x1b[H");
+            }
+            print!("{}", format_table(entries));
+        }
+        StatsFormat::Json => {
+            for entry in entries {
+                println!("{}", serde_json::to_string(entry)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints resource usage for the containers named by `ids` (all running
+/// containers when empty). Refreshes every second until interrupted unless
+/// `no_stream` is set, in which case a single sample is printed.
+pub fn show_stats(
+    store: &StateStore,
+    ids: &[String],
+    no_stream: bool,
+    format: StatsFormat,
+) -> Result<()> {
+    let mut targets = resolve_targets(store, ids)?;
+    let mut prev = sample(&targets);
+    let mut prev_time = Instant::now();
+
+    loop {
+        sleep(SAMPLE_INTERVAL);
+
+        // Without explicit ids, pick up containers started since the last
+        // refresh. Containers that exited drop out once their cgroup is gone.
+        if ids.is_empty() {
+            targets = resolve_targets(store, ids)?;
+        }
+        let cur = sample(&targets);
+        let now = Instant::now();
+        let elapsed = now - prev_time;
+
+        let entries: Vec<StatsEntry> = targets
+            .iter()
+            .filter_map(|(id, _)| {
+                let cur = cur.get(id)?;
+                // A container first seen this round has no earlier sample.
+                let prev = prev.get(id).unwrap_or(cur);
+                Some(StatsEntry::new(id, prev, cur, elapsed))
+            })
+            .collect();
+
+        print_entries(&entries, format, !no_stream)?;
+
+        if no_stream {
+            return Ok(());
+        }
+        prev = cur;
+        prev_time = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::PressureLine;
+    use tempfile::TempDir;
+
+    fn fake_cgroup(files: &[(&str, &str)]) -> TempDir {
+        let temp_dir = TempDir::new().unwrap();
+        for (name, content) in files {
+            std::fs::write(temp_dir.path().join(name), content).unwrap();
+        }
+        temp_dir
+    }
+
+    #[test]
+    fn test_read_stats() {
+        let cgroup = fake_cgroup(&[
+            ("memory.current", "10485760\n"),
+            ("memory.max", "104857600\n"),
+            (
+                "memory.stat",
+                "anon 8388608\nfile 2097152\ninactive_file 1048576\n",
+            ),
+            (
+                "cpu.stat",
+                "usage_usec 250000\nuser_usec 200000\nsystem_usec 50000\n",
+            ),
+            ("pids.current", "3\n"),
+            (
+                "io.stat",
+                "8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n\
+                 8:16 rbytes=1024 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n",
+            ),
+            (
+                "memory.pressure",
+                "some avg10=12.50 avg60=4.00 avg300=1.00 total=900\n\
+                 full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+            ),
+        ]);
+
+        let stats = read_stats(cgroup.path()).unwrap();
+
+        assert_eq!(
+            stats,
+            CgroupStats {
+                memory_current: 10485760,
+                memory_max: Some(104857600),
+                inactive_file: 1048576,
+                cpu_usage_usec: 250000,
+                pids_current: 3,
+                io_read_bytes: 5120,
+                io_write_bytes: 8192,
+                cpu_pressure: None,
+                memory_pressure: Some(Pressure {
+                    some: PressureLine {
+                        avg10: 12.5,
+                        avg60: 4.0,
+                        avg300: 1.0,
+                        total: 900,
+                    },
+                    full: Some(PressureLine::default()),
+                }),
+                io_pressure: None,
+            }
+        );
+        assert_eq!(stats.memory_usage(), 9437184);
+    }
+
+    #[test]
+    fn test_read_stats_missing_controllers() {
+        let cgroup = fake_cgroup(&[("memory.current", "4096\n"), ("memory.max", "max\n")]);
+
+        let stats = read_stats(cgroup.path()).unwrap();
+
+        assert_eq!(stats.memory_current, 4096);
+        assert_eq!(stats.memory_max, None);
+        assert_eq!(stats.cpu_usage_usec, 0);
+        assert_eq!(stats.pids_current, 0);
+    }
+
+    #[test]
+    fn test_read_stats_missing_cgroup() {
+        assert!(read_stats(Path::new("/nonexistent/docker-clone/abc")).is_err());
+    }
+
+    #[test]
+    fn test_cpu_percent() {
+        let second = Duration::from_secs(1);
+
+        assert_eq!(cpu_percent(0, 500_000, second), 50.0);
+        assert_eq!(cpu_percent(1_000_000, 3_000_000, second), 200.0);
+        assert_eq!(cpu_percent(500, 100, second), 0.0);
+        assert_eq!(cpu_percent(0, 100, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn test_stats_entry() {
+        let prev = CgroupStats {
+            cpu_usage_usec: 100_000,
+            ..Default::default()
+        };
+        let cur = CgroupStats {
+            memory_current: 50 * 1024 * 1024,
+            memory_max: Some(100 * 1024 * 1024),
+            cpu_usage_usec: 350_000,
+            pids_current: 2,
+            ..Default::default()
+        };
+
+        let entry = StatsEntry::new("abc123", &prev, &cur, Duration::from_millis(500));
+
+        assert_eq!(entry.cpu_percent, 50.0);
+        assert_eq!(entry.memory_percent, Some(50.0));
+        assert_eq!(entry.pids, 2);
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512B");
+        assert_eq!(format_bytes(1536), "1.5KiB");
+        assert_eq!(format_bytes(100 * 1024 * 1024), "100.0MiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0GiB");
+    }
+
+    #[test]
+    fn test_format_table() {
+        let entry = StatsEntry {
+            id: "abc123".to_string(),
+            cpu_percent: 12.5,
+            memory_usage: 1024 * 1024,
+            memory_limit: None,
+            memory_percent: None,
+            pids: 4,
+            block_read: 0,
+            block_write: 2048,
+            cpu_pressure: None,
+            memory_pressure: Some(Pressure {
+                some: PressureLine {
+                    avg10: 7.25,
+                    ..Default::default()
+                },
+                full: None,
+            }),
+            io_pressure: None,
+        };
+
+        let table = format_table(&[entry]);
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("CONTAINER ID"));
+        assert!(lines[1].starts_with("abc123"));
+        assert!(lines[1].contains("12.50%"));
+        assert!(lines[1].contains("1.0MiB / max"));
+        assert!(lines[1].contains("0B / 2.0KiB"));
+        assert!(lines[1].ends_with("-- / 7.25% / --"));
+    }
+
+    #[test]
+    fn test_stats_entry_json() {
+        let entry = StatsEntry::new(
+            "abc123",
+            &CgroupStats::default(),
+            &CgroupStats::default(),
+            Duration::from_secs(1),
+        );
+
+        let json: serde_json::Value = serde_json::to_value(&entry).unwrap();
+
+        assert_eq!(json["id"], "abc123");
+        assert_eq!(json["memory_limit"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_parse_stats_format() {
+        assert_eq!("table".parse::<StatsFormat>().unwrap(), StatsFormat::Table);
+        assert_eq!("json".parse::<StatsFormat>().unwrap(), StatsFormat::Json);
+        assert!("yaml".parse::<StatsFormat>().is_err());
+    }
+}
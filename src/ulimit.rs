@@ -0,0 +1,174 @@
+use anyhow::{Context, Result, bail};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Resource names accepted by `--ulimit`, as Docker spells them.
+const RESOURCES: &[(&str, libc::__rlimit_resource_t)] = &[
+    ("core", libc::RLIMIT_CORE),
+    ("cpu", libc::RLIMIT_CPU),
+    ("data", libc::RLIMIT_DATA),
+    ("fsize", libc::RLIMIT_FSIZE),
+    ("locks", libc::RLIMIT_LOCKS),
+    ("memlock", libc::RLIMIT_MEMLOCK),
+    ("msgqueue", libc::RLIMIT_MSGQUEUE),
+    ("nice", libc::RLIMIT_NICE),
+    ("nofile", libc::RLIMIT_NOFILE),
+    ("nproc", libc::RLIMIT_NPROC),
+    ("rss", libc::RLIMIT_RSS),
+    ("rtprio", libc::RLIMIT_RTPRIO),
+    ("rttime", libc::RLIMIT_RTTIME),
+    ("sigpending", libc::RLIMIT_SIGPENDING),
+    ("stack", libc::RLIMIT_STACK),
+];
+
+/// One `--ulimit NAME=SOFT[:HARD]` value. A missing hard limit equals the
+/// soft one; `-1` or `unlimited` means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ulimit {
+    pub name: &'static str,
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl Ulimit {
+    fn resource(&self) -> libc::__rlimit_resource_t {
+        RESOURCES
+            .iter()
+            .find(|(name, _)| *name == self.name)
+            .map(|(_, resource)| *resource)
+            .expect("ulimit names are validated when parsed")
+    }
+}
+
+fn parse_limit(s: &str) -> Result<u64> {
+    match s {
+        "-1" | "unlimited" => Ok(libc::RLIM_INFINITY),
+        _ => s
+            .parse()
+            .with_context(|| format!("Invalid ulimit value {:?}", s)),
+    }
+}
+
+impl FromStr for Ulimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, limits) = s
+            .split_once('=')
+            .with_context(|| format!("Invalid ulimit {:?}: expected NAME=SOFT[:HARD]", s))?;
+        let Some(&(name, _)) = RESOURCES.iter().find(|(n, _)| *n == name) else {
+            bail!("Unknown ulimit {:?}", name);
+        };
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (parse_limit(soft)?, parse_limit(hard)?),
+            None => {
+                let limit = parse_limit(limits)?;
+                (limit, limit)
+            }
+        };
+        if soft > hard {
+            bail!(
+                "Ulimit {}: soft limit {} is above hard limit {}",
+                name,
+                soft,
+                hard
+            );
+        }
+        Ok(Ulimit { name, soft, hard })
+    }
+}
+
+impl fmt::Display for Ulimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = |v: u64| {
+            if v == libc::RLIM_INFINITY {
+                "unlimited".to_string()
+            } else {
+                v.to_string()
+            }
+        };
+        write!(f, "{}={}:{}", self.name, limit(self.soft), limit(self.hard))
+    }
+}
+
+/// Sets each limit on the calling process; they are inherited across exec.
+/// Raising a hard limit needs CAP_SYS_RESOURCE in the initial user
+/// namespace, so inside the container limits can only be lowered.
+pub fn apply_ulimits(ulimits: &[Ulimit]) -> Result<()> {
+    for ulimit in ulimits {
+        let limit = libc::rlimit {
+            rlim_cur: ulimit.soft,
+            rlim_max: ulimit.hard,
+        };
+        if unsafe { libc::setrlimit(ulimit.resource(), &limit) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to set ulimit {}", ulimit));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork};
+
+    #[test]
+    fn test_parse_ulimits() {
+        assert_eq!(
+            "nofile=1024:4096".parse::<Ulimit>().unwrap(),
+            Ulimit {
+                name: "nofile",
+                soft: 1024,
+                hard: 4096
+            }
+        );
+        let nproc: Ulimit = "nproc=512".parse().unwrap();
+        assert_eq!((nproc.soft, nproc.hard), (512, 512));
+        let core: Ulimit = "core=0:-1".parse().unwrap();
+        assert_eq!((core.soft, core.hard), (0, libc::RLIM_INFINITY));
+        assert_eq!(core.to_string(), "core=0:unlimited");
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_ulimits() {
+        for bad in [
+            "nofile",
+            "files=10",
+            "nofile=ten",
+            "nofile=4096:1024",
+            "nofile=1:2:3",
+        ] {
+            assert!(bad.parse::<Ulimit>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_apply_ulimits_in_child() {
+        let ulimits = ["nofile=64:128".parse().unwrap(), "core=0".parse().unwrap()];
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let mut nofile = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                let code = if apply_ulimits(&ulimits).is_err()
+                    || unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut nofile) } != 0
+                {
+                    2
+                } else if (nofile.rlim_cur, nofile.rlim_max) == (64, 128) {
+                    0
+                } else {
+                    1
+                };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}